@@ -0,0 +1,447 @@
+//! ROMs compressed in a .zip or a .gz, with a small inflate so nothing else is needed
+//! (the wasm build gets the archive bytes from the page as they are)
+
+use std::fmt;
+
+use crate::header::MAX_ROM_SIZE;
+use crate::image::crc32;
+
+#[derive(Debug, PartialEq)]
+pub enum ArchiveError {
+    /// The compressed data or the structure of the archive is invalid, or the ROM is too large
+    Corrupt,
+    /// The zip has no .gb or .gbc file
+    NoRom,
+    /// Compression method other than stored or deflate, or an encrypted file
+    Unsupported(u16),
+    BadChecksum { expected: u32, computed: u32 },
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::Corrupt => write!(f, "the archive is corrupt"),
+            ArchiveError::NoRom => write!(f, "no .gb or .gbc file in the archive"),
+            ArchiveError::Unsupported(method) => write!(f, "unsupported compression method {}", method),
+            ArchiveError::BadChecksum { expected, computed } => {
+                write!(f, "CRC32 of the ROM is {:08x}, expected {:08x}", computed, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+/// The ROM in `data`: the first .gb/.gbc of a zip, the content of a gzip,
+/// or `data` itself when it isn't an archive (detected from the magic number)
+pub fn extract_rom(data: Vec<u8>) -> Result<Vec<u8>, ArchiveError> {
+    if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+        unzip_rom(&data)
+    } else if data.starts_with(&[0x1F, 0x8B, 0x08]) {
+        gunzip(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+fn le16(data: &[u8], offset: usize) -> Result<usize, ArchiveError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ArchiveError::Corrupt)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+}
+
+fn le32(data: &[u8], offset: usize) -> Result<u32, ArchiveError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ArchiveError::Corrupt)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn check_crc(data: &[u8], expected: u32) -> Result<(), ArchiveError> {
+    match crc32(data) {
+        computed if computed == expected => Ok(()),
+        computed => Err(ArchiveError::BadChecksum { expected, computed }),
+    }
+}
+
+pub fn gunzip(data: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    if !data.starts_with(&[0x1F, 0x8B]) || data.len() < 18 {
+        return Err(ArchiveError::Corrupt);
+    }
+    if data[2] != 8 {
+        return Err(ArchiveError::Unsupported(data[2] as u16));
+    }
+    let flags = data[3];
+    let mut offset = 10;
+    if flags & FEXTRA != 0 {
+        offset += 2 + le16(data, offset)?;
+    }
+    // Zero terminated file name and comment
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data.get(offset..).and_then(|rest| rest.iter().position(|&byte| byte == 0));
+            offset += end.ok_or(ArchiveError::Corrupt)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        offset += 2;
+    }
+
+    let footer = data.len() - 8;
+    // ISIZE, the size modulo 2^32
+    let size = le32(data, footer + 4)? as usize;
+    if size > MAX_ROM_SIZE {
+        return Err(ArchiveError::Corrupt);
+    }
+    let output = inflate(data.get(offset..footer).ok_or(ArchiveError::Corrupt)?, size)?;
+    check_crc(&output, le32(data, footer)?)?;
+    if output.len() != size {
+        return Err(ArchiveError::Corrupt);
+    }
+    Ok(output)
+}
+
+/// Extract the first .gb or .gbc file of the zip, in the order of the central directory
+pub fn unzip_rom(data: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    // The end of central directory record is at the end, before a comment of up to 64 KiB
+    let last = data.len().checked_sub(22).ok_or(ArchiveError::Corrupt)?;
+    let end = (last.saturating_sub(0xFFFF)..=last)
+        .rev()
+        .find(|&offset| data[offset..].starts_with(b"PK\x05\x06"))
+        .ok_or(ArchiveError::Corrupt)?;
+
+    let count = le16(data, end + 10)?;
+    let mut offset = le32(data, end + 16)? as usize;
+    for _ in 0..count {
+        if !data.get(offset..).is_some_and(|entry| entry.starts_with(b"PK\x01\x02")) {
+            return Err(ArchiveError::Corrupt);
+        }
+        let name_length = le16(data, offset + 28)?;
+        let name = data.get(offset + 46..offset + 46 + name_length).ok_or(ArchiveError::Corrupt)?;
+        let name = String::from_utf8_lossy(name).to_lowercase();
+        if name.ends_with(".gb") || name.ends_with(".gbc") {
+            return extract(data, offset);
+        }
+        offset += 46 + name_length + le16(data, offset + 30)? + le16(data, offset + 32)?;
+    }
+    Err(ArchiveError::NoRom)
+}
+
+/// Content of the file of the central directory entry at `entry`
+fn extract(data: &[u8], entry: usize) -> Result<Vec<u8>, ArchiveError> {
+    let flags = le16(data, entry + 8)?;
+    let method = le16(data, entry + 10)? as u16;
+    if flags & 1 != 0 {
+        return Err(ArchiveError::Unsupported(method));
+    }
+    let crc = le32(data, entry + 16)?;
+    let compressed_size = le32(data, entry + 20)? as usize;
+    let size = le32(data, entry + 24)? as usize;
+    if size > MAX_ROM_SIZE {
+        return Err(ArchiveError::Corrupt);
+    }
+
+    let header = le32(data, entry + 42)? as usize;
+    if !data.get(header..).is_some_and(|header| header.starts_with(b"PK\x03\x04")) {
+        return Err(ArchiveError::Corrupt);
+    }
+    let start = header + 30 + le16(data, header + 26)? + le16(data, header + 28)?;
+    let compressed = data.get(start..start + compressed_size).ok_or(ArchiveError::Corrupt)?;
+
+    let output = match method {
+        0 => compressed.to_vec(),
+        8 => inflate(compressed, size)?,
+        _ => return Err(ArchiveError::Unsupported(method)),
+    };
+    if output.len() != size {
+        return Err(ArchiveError::Corrupt);
+    }
+    check_crc(&output, crc)?;
+    Ok(output)
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+    6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// Order of the code lengths of the code length alphabet in a dynamic block
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Bits of the deflate stream, least significant bit first
+struct Bits<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn bits(&mut self, count: u32) -> Result<u32, ArchiveError> {
+        while self.count < count {
+            let byte = *self.data.get(self.position).ok_or(ArchiveError::Corrupt)?;
+            self.position += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u32 << count) - 1);
+        self.buffer >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Drop the bits left in the current byte
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+/// Canonical Huffman code, from the length of the code of each symbol
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, ArchiveError> {
+        // First code and index of the symbols of each length, walking down the lengths
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return self.symbols.get((index + code - first) as usize).copied().ok_or(ArchiveError::Corrupt);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(ArchiveError::Corrupt)
+    }
+}
+
+/// Decompress raw deflate data (RFC 1951), the data is `Corrupt` if it inflates to more than `limit` bytes
+pub fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>, ArchiveError> {
+    let mut bits = Bits { data, position: 0, buffer: 0, count: 0 };
+    let mut output = Vec::with_capacity(limit.min(data.len() * 4));
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let length = le16(data, bits.position)?;
+                if length != !le16(data, bits.position + 2)? & 0xFFFF {
+                    return Err(ArchiveError::Corrupt);
+                }
+                let start = bits.position + 4;
+                if output.len() + length > limit {
+                    return Err(ArchiveError::Corrupt);
+                }
+                output.extend_from_slice(data.get(start..start + length).ok_or(ArchiveError::Corrupt)?);
+                bits.position = start + length;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                inflate_block(&mut bits, &mut output, limit, &Huffman::new(&lengths), &Huffman::new(&[5; 30]))?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut output, limit, &literals, &distances)?;
+            }
+            _ => return Err(ArchiveError::Corrupt),
+        }
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+/// Read the literal/length and distance codes at the start of a dynamic block
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman), ArchiveError> {
+    let literal_count = bits.bits(5)? as usize + 257;
+    let distance_count = bits.bits(5)? as usize + 1;
+    let code_length_count = bits.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = bits.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_lengths.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or(ArchiveError::Corrupt)?, 3 + bits.bits(2)?),
+            17 => (0, 3 + bits.bits(3)?),
+            _ => (0, 11 + bits.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count || lengths[256] == 0 {
+        return Err(ArchiveError::Corrupt);
+    }
+    Ok((Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..])))
+}
+
+fn inflate_block(
+    bits: &mut Bits,
+    output: &mut Vec<u8>,
+    limit: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), ArchiveError> {
+    loop {
+        match literals.decode(bits)? as usize {
+            symbol @ 0..=255 if output.len() < limit => output.push(symbol as u8),
+            0..=255 => return Err(ArchiveError::Corrupt),
+            256 => return Ok(()),
+            symbol => {
+                let index = symbol - 257;
+                let length = *LENGTH_BASE.get(index).ok_or(ArchiveError::Corrupt)? as usize
+                    + bits.bits(LENGTH_EXTRA[index] as u32)? as usize;
+                let index = distances.decode(bits)? as usize;
+                let distance = *DISTANCE_BASE.get(index).ok_or(ArchiveError::Corrupt)? as usize
+                    + bits.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+                let start = output.len().checked_sub(distance).ok_or(ArchiveError::Corrupt)?;
+                if output.len() + length > limit {
+                    return Err(ArchiveError::Corrupt);
+                }
+                // The copy can overlap what it writes
+                for i in start..start + length {
+                    output.push(output[i]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: &[u8] = b"Hello, Game Boy!";
+    /// HELLO with fixed Huffman codes
+    const HELLO_DEFLATE: &str = "f348cdc9c9d751704fcc4d5570caaf540400";
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// Zip of `(name, method, stored data, content)` files
+    fn zip(files: &[(&str, u16, &[u8], &[u8])]) -> Vec<u8> {
+        let (mut zip, mut directory) = (Vec::new(), Vec::new());
+        for &(name, method, data, content) in files {
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&[20, 0, 0, 0]);
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]);
+            fields.extend_from_slice(&crc32(content).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&[0, 0]);
+
+            directory.extend_from_slice(b"PK\x01\x02\x14\x00");
+            directory.extend_from_slice(&fields);
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&(zip.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+
+            zip.extend_from_slice(b"PK\x03\x04");
+            zip.extend_from_slice(&fields);
+            zip.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(data);
+        }
+        let offset = zip.len() as u32;
+        zip.extend_from_slice(&directory);
+        zip.extend_from_slice(b"PK\x05\x06\x00\x00\x00\x00");
+        zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&offset.to_le_bytes());
+        zip.extend_from_slice(&[0, 0]);
+        zip
+    }
+
+    #[test]
+    fn test_inflate() {
+        assert_eq!(inflate(&unhex(HELLO_DEFLATE), HELLO.len()).unwrap(), HELLO);
+        assert_eq!(inflate(&unhex(HELLO_DEFLATE), HELLO.len() - 1), Err(ArchiveError::Corrupt));
+
+        // Dynamic Huffman codes
+        let text = [b"abcdefghijklmnopqrstuvwxyz0123456789".repeat(3), b"the quick brown fox jumps over the lazy dog".to_vec()].concat();
+        let data = unhex(concat!(
+            "adca591680101400d0adbc25340fcb410ac53344587da73df47d2fa16ce3fb21a43a2f6dd03a1fee",
+            "989e5c6ad376fd304ef3b2929fce2d38b828d909d4e36360c70c2a6a1b0013f7f0f1456a810d8f17"
+        ));
+        assert_eq!(inflate(&data, text.len()).unwrap(), text);
+        assert_eq!(inflate(&data, 50), Err(ArchiveError::Corrupt));
+
+        // Stored blocks, as in our PNGs
+        let png = crate::image::Image::new(1, 1, vec![1, 2, 3]).to_png();
+        let idat = png.windows(4).position(|chunk| chunk == b"IDAT").unwrap() + 4;
+        assert_eq!(inflate(&png[idat + 2..], 4).unwrap(), [0, 1, 2, 3]);
+        assert_eq!(inflate(&png[idat + 2..], 3), Err(ArchiveError::Corrupt));
+
+        assert_eq!(inflate(&unhex(&HELLO_DEFLATE[..20]), MAX_ROM_SIZE), Err(ArchiveError::Corrupt));
+    }
+
+    #[test]
+    fn test_gzip() {
+        let gz = unhex("1f8b08080000000002ff68656c6c6f2e676200f348cdc9c9d751704fcc4d5570caaf540400f9d53a7e10000000");
+        assert_eq!(extract_rom(gz.clone()).unwrap(), HELLO);
+
+        let mut bad = gz;
+        let footer = bad.len() - 8;
+        bad[footer] ^= 1;
+        assert!(matches!(gunzip(&bad), Err(ArchiveError::BadChecksum { .. })));
+        bad[footer + 7] = 0xFF; // ISIZE past the size of a ROM
+        assert_eq!(gunzip(&bad), Err(ArchiveError::Corrupt));
+    }
+
+    #[test]
+    fn test_zip() {
+        let deflated = unhex(HELLO_DEFLATE);
+        let archive = zip(&[("README.txt", 0, b"readme", b"readme"), ("Game.GBC", 8, &deflated, HELLO)]);
+        assert_eq!(extract_rom(archive).unwrap(), HELLO);
+
+        let archive = zip(&[("rom.gb", 0, HELLO, HELLO)]);
+        assert_eq!(unzip_rom(&archive).unwrap(), HELLO);
+        assert_eq!(unzip_rom(&zip(&[("README.txt", 0, b"readme", b"readme")])), Err(ArchiveError::NoRom));
+        assert_eq!(unzip_rom(&zip(&[("rom.gb", 14, HELLO, HELLO)])), Err(ArchiveError::Unsupported(14)));
+
+        assert_eq!(extract_rom(HELLO.to_vec()).unwrap(), HELLO);
+    }
+}
@@ -0,0 +1,272 @@
+//! Cheat codes: Game Genie codes patch the ROM as the CPU reads it, GameShark codes write the RAM
+//! at the start of every VBlank
+//!
+//! A cheat file has one code per line, followed by an optional description. Blank lines and
+//! lines starting with `#` are skipped:
+//!
+//! ```text
+//! # Super Mario Land
+//! 00A-17B-C49 Infinite lives
+//! 010138CD    Start with 1 coin
+//! ```
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug)]
+pub enum CheatError {
+    Io(io::Error),
+    BadCode(String),
+    BadLine { line: usize, code: String },
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatError::Io(e) => write!(f, "Can't read the cheats: {}", e),
+            CheatError::BadCode(code) => write!(f, "Invalid cheat code '{}'", code),
+            CheatError::BadLine { line, code } => write!(f, "Invalid cheat code '{}' on line {}", code, line),
+        }
+    }
+}
+
+impl std::error::Error for CheatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CheatError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CheatError {
+    fn from(e: io::Error) -> Self {
+        CheatError::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Code {
+    /// `VVA-AAA-CxC`: reads of `address` return `value`, only when the ROM has `compare` there
+    /// if it is given (the address can be in any bank)
+    GameGenie { address: u16, value: u8, compare: Option<u8> },
+    /// `BBVVAAAA`: `value` is written at `address` when every VBlank starts, the address must be in RAM.
+    /// `bank` is 0x01 in DMG codes, it selects a WRAM bank in CGB codes, which isn't emulated:
+    /// the mapped bank is written.
+    GameShark { bank: u8, address: u16, value: u8 },
+}
+
+impl Code {
+    pub fn parse(code: &str) -> Result<Code, CheatError> {
+        let bad_code = || CheatError::BadCode(code.to_string());
+        let digits: Vec<u8> = code
+            .chars()
+            .filter(|&c| c != '-')
+            .map(|c| c.to_digit(16).map(|digit| digit as u8))
+            .collect::<Option<_>>()
+            .ok_or_else(bad_code)?;
+        let byte = |i: usize| digits[i] << 4 | digits[i + 1];
+
+        match digits.len() {
+            6 | 9 => {
+                let address = ((digits[5] ^ 0xF) as u16) << 12
+                    | (digits[2] as u16) << 8
+                    | (digits[3] as u16) << 4
+                    | digits[4] as u16;
+                if address >= 0x8000 {
+                    return Err(bad_code());
+                }
+                // The 8th digit is only a check, the compare byte is scrambled in the other two
+                let compare = (digits.len() == 9).then(|| (digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA);
+                Ok(Code::GameGenie { address, value: byte(0), compare })
+            }
+            8 if !code.contains('-') => {
+                let address = u16::from_le_bytes([byte(4), byte(6)]);
+                if !is_ram(address) {
+                    return Err(bad_code());
+                }
+                Ok(Code::GameShark { bank: byte(0), value: byte(2), address })
+            }
+            _ => Err(bad_code()),
+        }
+    }
+}
+
+/// Cartridge RAM, WRAM or HRAM: the addresses GameShark codes and freezes can write
+pub(crate) fn is_ram(address: u16) -> bool {
+    matches!(address, 0xA000..=0xDFFF | 0xFF80..=0xFFFE)
+}
+
+impl FromStr for Code {
+    type Err = CheatError;
+
+    fn from_str(code: &str) -> Result<Code, CheatError> {
+        Code::parse(code)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheat {
+    pub code: Code,
+    /// The code as it was given
+    pub text: String,
+    pub description: String,
+    pub enabled: bool,
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)?;
+        if !self.description.is_empty() {
+            write!(f, " {}", self.description)?;
+        }
+        if !self.enabled {
+            write!(f, " (disabled)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Cheat codes of a game, they are enabled when added
+#[derive(Default)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Cheats {
+        Cheats::default()
+    }
+
+    /// Returns the index of the cheat
+    pub fn add(&mut self, code: &str, description: &str) -> Result<usize, CheatError> {
+        self.cheats.push(Cheat {
+            code: code.parse()?,
+            text: code.to_uppercase(),
+            description: description.to_string(),
+            enabled: true,
+        });
+        Ok(self.cheats.len() - 1)
+    }
+
+    /// Add the codes of a cheat file, returns how many were added. Nothing is added when a line is invalid.
+    pub fn parse(&mut self, text: &str) -> Result<usize, CheatError> {
+        let mut cheats = Cheats::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (code, description) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            cheats.add(code, description.trim()).map_err(|_| CheatError::BadLine { line: number + 1, code: code.to_string() })?;
+        }
+        let count = cheats.len();
+        self.cheats.append(&mut cheats.cheats);
+        Ok(count)
+    }
+
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, CheatError> {
+        self.parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Cheat> {
+        (index < self.cheats.len()).then(|| self.cheats.remove(index))
+    }
+
+    /// Returns false when there is no cheat at `index`
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.cheats.get_mut(index) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.cheats.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.cheats.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cheat> {
+        self.cheats.iter()
+    }
+
+    fn enabled(&self) -> impl Iterator<Item = Code> + '_ {
+        self.cheats.iter().filter(|cheat| cheat.enabled).map(|cheat| cheat.code)
+    }
+
+    /// Value seen by the CPU for `rom_value` read at `address`
+    pub(crate) fn patch_rom(&self, address: u16, rom_value: u8) -> u8 {
+        self.enabled()
+            .find_map(|code| match code {
+                Code::GameGenie { address: a, value, compare } if a == address && compare.is_none_or(|c| c == rom_value) => Some(value),
+                _ => None,
+            })
+            .unwrap_or(rom_value)
+    }
+
+    /// Writes of the enabled GameShark codes, as (address, value)
+    pub(crate) fn ram_writes(&self) -> impl Iterator<Item = (u16, u8)> + '_ {
+        self.enabled().filter_map(|code| match code {
+            Code::GameShark { address, value, .. } => Some((address, value)),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_codes() {
+        assert_eq!(
+            Code::parse("00A-17B-C49").unwrap(),
+            Code::GameGenie { address: 0x4A17, value: 0x00, compare: Some(0xC8) }
+        );
+        assert_eq!(Code::parse("3ea-21f").unwrap(), Code::GameGenie { address: 0x0A21, value: 0x3E, compare: None });
+        assert_eq!(Code::parse("010138CD").unwrap(), Code::GameShark { bank: 0x01, address: 0xCD38, value: 0x01 });
+
+        assert!(Code::parse("00A-172-C49").is_err()); // Address out of the ROM
+        assert!(Code::parse("0101-38CD").is_err());
+        assert!(Code::parse("01010040").is_err()); // ROM
+        assert!(Code::parse("010100FF").is_err()); // I/O registers
+        assert!(Code::parse("010180FF").is_ok());
+        assert!(Code::parse("01G138CD").is_err());
+        assert!(Code::parse("").is_err());
+    }
+
+    #[test]
+    fn test_cheats() {
+        let mut cheats = Cheats::new();
+        let text = "# Comment\n\n00A-17B-C49  Infinite lives\n010138CD\n";
+        assert_eq!(cheats.parse(text).unwrap(), 2);
+        assert_eq!(cheats.iter().next().unwrap().description, "Infinite lives");
+
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC8), 0x00);
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC9), 0xC9);
+        assert_eq!(cheats.patch_rom(0x4A18, 0xC8), 0xC8);
+        assert_eq!(cheats.ram_writes().collect::<Vec<_>>(), [(0xCD38, 0x01)]);
+
+        assert!(cheats.set_enabled(0, false));
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC8), 0xC8);
+        assert!(!cheats.set_enabled(2, false));
+
+        match cheats.parse("010138CD\nnope") {
+            Err(CheatError::BadLine { line: 2, code }) => assert_eq!(code, "nope"),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(cheats.len(), 2);
+    }
+}
@@ -1,10 +1,20 @@
-use crate::{mbc::MBC, memory::Memory, registers::{Flag, Registers}};
+use crate::{disasm::{self, Instruction}, mbc::MBC, memory::Memory, registers::{Flag, Registers}, trace::Trace};
 
 pub struct CPU {
     pub registers: Registers,
     pub memory: Memory,
     ime: bool,
+    ei_delay: bool,     // EI enables IME only after the next instruction
     halt: bool,
+    halt_bug: bool,     // HALT with IME=0 and a pending interrupt: the next opcode fetch doesn't increment PC
+    stop: bool,
+
+    /// Advance the rest of the system by one M-cycle on each memory access
+    /// instead of once per instruction
+    pub cycle_accurate: bool,
+    ticked: u8,         // Cycles already sent to the memory during the current step
+
+    pub trace: Option<Trace>,
 }
 
 impl CPU {
@@ -14,80 +24,181 @@ impl CPU {
             registers: Registers::new(),
             memory: Memory::new(mbc),
             ime: false,
+            ei_delay: false,
             halt: false,
+            halt_bug: false,
+            stop: false,
+
+            cycle_accurate: false,
+            ticked: 0,
+
+            trace: None,
+        }
+    }
+
+    /// Spend one M-cycle, the other components only see it in cycle accurate mode
+    #[inline(always)]
+    fn tick(&mut self) {
+        if self.cycle_accurate {
+            self.memory.step(4);
+            self.ticked += 4;
         }
     }
 
     pub fn read_byte(&mut self, address: u16) -> u8 {
+        self.tick();
         self.memory.read(address)
     }
 
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        self.tick();
+        self.memory.write(address, value);
+    }
+
     fn fetch_byte(&mut self) -> u8 {
-        let value = self.memory.read(self.registers.pc);
-        self.registers.pc = self.registers.pc.wrapping_add(1);
+        let value = self.read_byte(self.registers.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.registers.pc = self.registers.pc.wrapping_add(1);
+        }
         value
     }
 
-    fn read_word(&mut self) -> u16 {
-        self.memory.read_word(self.registers.pc)
+    fn fetch_word(&mut self) -> u16 {
+        let low = self.fetch_byte() as u16;
+        let high = self.fetch_byte() as u16;
+        high << 8 | low
     }
 
-    fn fetch_word(&mut self) -> u16 {
-        let value = self.read_word();
-        self.registers.pc = self.registers.pc.wrapping_add(2);
-        value
+    /// Log the state before the instruction at PC
+    fn log_trace(&mut self) {
+        let pc = self.registers.pc;
+        let Some(trace) = self.trace.as_mut().filter(|trace| trace.wants(pc)) else { return };
+        let pcmem = [0, 1, 2, 3].map(|i| self.memory.peek(pc.wrapping_add(i)));
+        let bank = if pc < 0x8000 { self.memory.mbc.rom_bank(pc) } else { 0 };
+        trace.log(&self.registers, pcmem, bank);
     }
 
-    pub fn step_debug(&mut self) -> u8 {
-        let opcode = self.read_byte(self.registers.pc);
-        println!("PC: {:#06x}, Opcode: {:#04x}", self.registers.pc, opcode);
-        self.step()
+    /// Decode the instruction at `address` in the current memory map
+    pub fn disassemble(&self, address: u16) -> Instruction {
+        let bytes: Vec<u8> = (0..3).map(|i| self.memory.peek(address.wrapping_add(i))).collect();
+        let bank = if address < 0x8000 { self.memory.mbc.rom_bank(address) } else { 0 };
+        disasm::decode(&bytes, address, bank)
     }
 
     pub fn step(&mut self) -> u8 {
+        self.ticked = 0;
 
-        match self.handle_interrupts() {
-            0 => {},
-            n => return n,
-        }
-
-        let cycles = if self.halt {
-            4
-        } else {
-            self.call()
+        let cycles = match self.handle_interrupts() {
+            0 if self.stop => { self.handle_stop(); 4 },
+            0 if self.halt => self.halt_cycles(),
+            0 => {
+                // IME is only set once the instruction following EI is executed
+                let enable_ime = self.ei_delay;
+                if self.trace.is_some() {
+                    self.log_trace();
+                }
+                let cycles = self.call();
+                if enable_ime && self.ei_delay {
+                    self.ime = true;
+                    self.ei_delay = false;
+                }
+                cycles
+            },
+            n => n,
         };
 
-        self.memory.step(cycles);
+        // Internal cycles that weren't spent on a memory access
+        self.memory.step(cycles.saturating_sub(self.ticked));
         cycles
     }
 
-    fn handle_interrupts(&mut self) -> u8 {
-
-        // If both IME and HALT are false, we are in a normal state
-        if self.ime == false && self.halt == false { return 0; }
+    /// Pending interrupts, only the 5 lower bits of IE and IF are wired
+    #[inline(always)]
+    fn pending_interrupts(&self) -> u8 {
+        self.memory.interrupt_flags & self.memory.interrupt_enable & 0x1F
+    }
 
-        // If HALT is true, we are in a HALT state
-        let interrupt = self.memory.interrupt_flags & self.memory.interrupt_enable;
-        if interrupt == 0 { return 0; }
+    fn handle_interrupts(&mut self) -> u8 {
+        if self.pending_interrupts() == 0 { return 0; }
 
+        // Any pending interrupt wakes the CPU up, even when IME is disabled
+        let was_halted = self.halt;
         self.halt = false;
-        if self.ime == false { return 0; }
+        self.stop = false;
+        if !self.ime { return 0; }
         self.ime = false;
 
-        let n = interrupt.trailing_zeros() as u8;
-        if n == 5 { panic!() }
-        
-        self.memory.interrupt_flags &= !(1 << n);
-        let interrupt_vector = 0x0040 | (n as u16) << 3;
+        self.dispatch_interrupt();
 
-        self.call_interrupt(interrupt_vector);
-        16
+        // 5 M-cycles, plus one to exit HALT
+        if was_halted {
+            self.tick();
+            24
+        } else {
+            20
+        }
     }
 
-    fn call_interrupt(&mut self, address: u16) {
-        let pc = self.registers.pc;
-        self.push_stack(pc);
-        self.registers.pc = address;
+    /// Push PC and jump to the vector of the highest priority interrupt (lowest bit)
+    /// The vector is chosen after the high byte of PC is pushed: if that push overwrites IE
+    /// and cancels every pending interrupt, the CPU jumps to 0x0000 and IF is left untouched.
+    fn dispatch_interrupt(&mut self) {
+        self.tick();
+        self.tick();
+
+        // HALT bug with EI just before: IME is set when the interrupt is taken, it returns to the HALT
+        let pc = if self.halt_bug {
+            self.halt_bug = false;
+            self.registers.pc.wrapping_sub(1)
+        } else {
+            self.registers.pc
+        };
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write_byte(self.registers.sp, (pc >> 8) as u8);
+
+        let interrupt = self.pending_interrupts();
+
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write_byte(self.registers.sp, pc as u8);
+        self.tick();
+
+        self.registers.pc = if interrupt == 0 {
+            0x0000
+        } else {
+            let n = interrupt.trailing_zeros() as u8;
+            self.memory.interrupt_flags &= !(1 << n);
+            0x0040 | (n as u16) << 3
+        };
+    }
+
+    /// While halted, the CPU sleeps until the next scheduled event in one go
+    fn halt_cycles(&self) -> u8 {
+        let cycles = self.memory.cycles_until_next_event().clamp(4, 252);
+        (cycles as u8 + 3) & !3
+    }
+
+    /// HALT is skipped when IME is disabled and an interrupt is already pending (HALT bug)
+    fn halt(&mut self) {
+        if !self.ime && self.pending_interrupts() != 0 {
+            self.halt_bug = true;
+        } else {
+            self.halt = true;
+        }
+    }
+
+    /// STOP resets DIV and sleeps until a button of a selected row is pressed
+    fn stop(&mut self) {
+        self.fetch_byte();
+        self.memory.write(0xFF04, 0);
+        self.stop = true;
+    }
+
+    fn handle_stop(&mut self) {
+        if self.memory.keypad.read() & 0x0F != 0x0F {
+            self.stop = false;
+        }
     }
 
     fn call(&mut self) -> u8 {
@@ -95,23 +206,24 @@ impl CPU {
         match opcode {
             0x00 => 4, // NOP
             0x01 => { let v = self.fetch_word(); self.registers.set_bc(v); 12 }, // LD BC, d16
-            0x02 => { self.memory.write(self.registers.bc(), self.registers.a) ; 8 }, // LD (BC), A
+            0x02 => { self.write_byte(self.registers.bc(), self.registers.a) ; 8 }, // LD (BC), A
             0x03 => { self.registers.set_bc(self.registers.bc().wrapping_add(1)); 8 }, // INC BC
             0x04 => { self.registers.b = self.reg_inc(self.registers.b); 4 }, // INC B
             0x05 => { self.registers.b = self.reg_dec(self.registers.b); 4 }, // DEC B
             0x06 => { self.registers.b = self.fetch_byte(); 8 }, // LD B, d8
             0x07 => { self.registers.a = self.rlc(self.registers.a); self.registers.set_flag(Flag::Zero, false); 4 }, // RLCA
-            0x08 => { let addr = self.fetch_word(); self.memory.write_word(addr, self.registers.sp); 20 }, // LD (a16), SP
+            0x08 => { let addr = self.fetch_word(); self.write_byte(addr, self.registers.sp as u8); self.write_byte(addr.wrapping_add(1), (self.registers.sp >> 8) as u8); 20 }, // LD (a16), SP
             0x09 => { self.add_hl(self.registers.bc()); 8 }, // ADD HL, BC
-            0x0a => { self.registers.a = self.memory.read(self.registers.bc()); 8 }, // LD A, (BC)
+            0x0a => { self.registers.a = self.read_byte(self.registers.bc()); 8 }, // LD A, (BC)
             0x0b => { self.registers.set_bc(self.registers.bc().wrapping_sub(1)); 8 }, // DEC BC
             0x0c => { self.registers.c = self.reg_inc(self.registers.c); 4 }, // INC C
             0x0d => { self.registers.c = self.reg_dec(self.registers.c); 4 }, // DEC C
             0x0e => { self.registers.c = self.fetch_byte(); 8 }, // LD C, d8
             0x0f => { self.registers.a = self.rrc(self.registers.a); self.registers.set_flag(Flag::Zero, false); 4 }, // RRCA
+            0x10 => { self.stop(); 4 }, // STOP
 
             0x11 => { let v = self.fetch_word(); self.registers.set_de(v); 12 }, // LD DE, d16
-            0x12 => { self.memory.write(self.registers.de(), self.registers.a); 8 }, // LD (DE), A
+            0x12 => { self.write_byte(self.registers.de(), self.registers.a); 8 }, // LD (DE), A
             0x13 => { self.registers.set_de(self.registers.de().wrapping_add(1)); 8 }, // INC DE
             0x14 => { self.registers.d = self.reg_inc(self.registers.d); 4 }, // INC D
             0x15 => { self.registers.d = self.reg_dec(self.registers.d); 4 }, // DEC D
@@ -119,40 +231,40 @@ impl CPU {
             0x17 => { self.registers.a = self.rl(self.registers.a); self.registers.set_flag(Flag::Zero, false); 4 }, // RLA
             0x18 => { self.jr(); 12 }, // JR r8
             0x19 => { self.add_hl(self.registers.de()); 8 }, // ADD HL, DE
-            0x1a => { self.registers.a = self.memory.read(self.registers.de()); 8 }, // LD A, (DE)
+            0x1a => { self.registers.a = self.read_byte(self.registers.de()); 8 }, // LD A, (DE)
             0x1b => { self.registers.set_de(self.registers.de().wrapping_sub(1)); 8 }, // DEC DE
             0x1c => { self.registers.e = self.reg_inc(self.registers.e); 4 }, // INC E
             0x1d => { self.registers.e = self.reg_dec(self.registers.e); 4 }, // DEC E
             0x1e => { self.registers.e = self.fetch_byte(); 8 }, // LD E, d8
             0x1f => { self.registers.a = self.rr(self.registers.a); self.registers.set_flag(Flag::Zero, false); 4 }, // RRA
             
-            0x20 => { if !self.registers.get_flag(Flag::Zero) { self.jr(); 12 } else { self.registers.pc += 1; 8 } }, // JR NZ, r8
+            0x20 => { if !self.registers.get_flag(Flag::Zero) { self.jr(); 12 } else { self.fetch_byte(); 8 } }, // JR NZ, r8
             0x21 => { let v = self.fetch_word(); self.registers.set_hl(v); 12 }, // LD HL, d16
-            0x22 => { self.memory.write(self.registers.hli(), self.registers.a); 8 }, // LD (HL+), A
+            0x22 => { let addr = self.registers.hli(); self.write_byte(addr, self.registers.a); 8 }, // LD (HL+), A
             0x23 => { self.registers.set_hl(self.registers.hl().wrapping_add(1)); 8 }, // INC HL
             0x24 => { self.registers.h = self.reg_inc(self.registers.h); 4 }, // INC H
             0x25 => { self.registers.h = self.reg_dec(self.registers.h); 4 }, // DEC H
             0x26 => { self.registers.h = self.fetch_byte(); 8 }, // LD H, d8
             0x27 => { self.daa(); 4 }, // DAA
-            0x28 => { if self.registers.get_flag(Flag::Zero) { self.jr(); 12 } else { self.registers.pc += 1; 8 } }, // JR Z, r8
+            0x28 => { if self.registers.get_flag(Flag::Zero) { self.jr(); 12 } else { self.fetch_byte(); 8 } }, // JR Z, r8
             0x29 => { self.add_hl(self.registers.hl()); 8 }, // ADD HL, HL
-            0x2A => { self.registers.a = self.memory.read(self.registers.hli()); 8 }, // LD A, (HL+)
+            0x2A => { let addr = self.registers.hli(); self.registers.a = self.read_byte(addr); 8 }, // LD A, (HL+)
             0x2B => { self.registers.set_hl(self.registers.hl().wrapping_sub(1)); 8 }, // DEC HL
             0x2C => { self.registers.l = self.reg_inc(self.registers.l); 4 }, // INC L
             0x2D => { self.registers.l = self.reg_dec(self.registers.l); 4 }, // DEC L
             0x2E => { self.registers.l = self.fetch_byte(); 8 }, // LD L, d8
             0x2f => { self.registers.a = !self.registers.a; self.registers.set_flag(Flag::Sub, true); self.registers.set_flag(Flag::HalfCarry, true); 4 }, // CPL
-            0x30 => { if !self.registers.get_flag(Flag::Carry) { self.jr(); 12 } else { self.registers.pc += 1; 8 } }, // JR NC, r8
+            0x30 => { if !self.registers.get_flag(Flag::Carry) { self.jr(); 12 } else { self.fetch_byte(); 8 } }, // JR NC, r8
             0x31 => { self.registers.sp = self.fetch_word(); 12 }, // LD SP, d16
-            0x32 => { self.memory.write(self.registers.hld(), self.registers.a); 8 }, // LD (HL-), A
+            0x32 => { let addr = self.registers.hld(); self.write_byte(addr, self.registers.a); 8 }, // LD (HL-), A
             0x33 => { self.registers.sp = self.registers.sp.wrapping_add(1); 8 }, // INC SP
-            0x34 => { let v = self.memory.read(self.registers.hl()); let v2 = self.reg_inc(v); self.memory.write(self.registers.hl(), v2); 12 }, // INC (HL)
-            0x35 => { let v = self.memory.read(self.registers.hl()); let v2 = self.reg_dec(v); self.memory.write(self.registers.hl(), v2); 12 }, // DEC (HL)
-            0x36 => { let v = self.fetch_byte(); self.memory.write(self.registers.hl(), v); 12 }, // LD (HL), d8
+            0x34 => { let v = self.read_byte(self.registers.hl()); let v2 = self.reg_inc(v); self.write_byte(self.registers.hl(), v2); 12 }, // INC (HL)
+            0x35 => { let v = self.read_byte(self.registers.hl()); let v2 = self.reg_dec(v); self.write_byte(self.registers.hl(), v2); 12 }, // DEC (HL)
+            0x36 => { let v = self.fetch_byte(); self.write_byte(self.registers.hl(), v); 12 }, // LD (HL), d8
             0x37 => { self.registers.set_flag(Flag::Sub, false); self.registers.set_flag(Flag::HalfCarry, false); self.registers.set_flag(Flag::Carry, true); 4 }, // SCF
-            0x38 => { if self.registers.get_flag(Flag::Carry) { self.jr(); 12 } else { self.registers.pc += 1; 8 } }, // JR C, r8
+            0x38 => { if self.registers.get_flag(Flag::Carry) { self.jr(); 12 } else { self.fetch_byte(); 8 } }, // JR C, r8
             0x39 => { self.add_hl(self.registers.sp); 8 }, // ADD HL, SP
-            0x3A => { self.registers.a = self.memory.read(self.registers.hld()); 8 }, // LD A, (HL-)
+            0x3A => { let addr = self.registers.hld(); self.registers.a = self.read_byte(addr); 8 }, // LD A, (HL-)
             0x3B => { self.registers.sp = self.registers.sp.wrapping_sub(1); 8 }, // DEC SP
             0x3C => { self.registers.a = self.reg_inc(self.registers.a); 4 }, // INC A
             0x3D => { self.registers.a = self.reg_dec(self.registers.a); 4 }, // DEC A
@@ -169,7 +281,7 @@ impl CPU {
             0x43 => { self.registers.b = self.registers.e; 4 }, // LD B, E
             0x44 => { self.registers.b = self.registers.h; 4 }, // LD B, H
             0x45 => { self.registers.b = self.registers.l; 4 }, // LD B, L
-            0x46 => { self.registers.b = self.memory.read(self.registers.hl()); 8 }, // LD B, (HL)
+            0x46 => { self.registers.b = self.read_byte(self.registers.hl()); 8 }, // LD B, (HL)
             0x47 => { self.registers.b = self.registers.a; 4 }, // LD B, A
             0x48 => { self.registers.c = self.registers.b; 4 }, // LD C, B
             0x49 => { 4 }, // LD C, C
@@ -177,7 +289,7 @@ impl CPU {
             0x4B => { self.registers.c = self.registers.e; 4 }, // LD C, E
             0x4C => { self.registers.c = self.registers.h; 4 }, // LD C, H
             0x4D => { self.registers.c = self.registers.l; 4 }, // LD C, L
-            0x4E => { self.registers.c = self.memory.read(self.registers.hl()); 8 }, // LD C, (HL)
+            0x4E => { self.registers.c = self.read_byte(self.registers.hl()); 8 }, // LD C, (HL)
             0x4F => { self.registers.c = self.registers.a; 4 }, // LD C, A
             0x50 => { self.registers.d = self.registers.b; 4 }, // LD D, B
             0x51 => { self.registers.d = self.registers.c; 4 }, // LD D, C
@@ -185,7 +297,7 @@ impl CPU {
             0x53 => { self.registers.d = self.registers.e; 4 }, // LD D, E
             0x54 => { self.registers.d = self.registers.h; 4 }, // LD D, H
             0x55 => { self.registers.d = self.registers.l; 4 }, // LD D, L
-            0x56 => { self.registers.d = self.memory.read(self.registers.hl()); 8 }, // LD D, (HL)
+            0x56 => { self.registers.d = self.read_byte(self.registers.hl()); 8 }, // LD D, (HL)
             0x57 => { self.registers.d = self.registers.a; 4 }, // LD D, A
             0x58 => { self.registers.e = self.registers.b; 4 }, // LD E, B
             0x59 => { self.registers.e = self.registers.c; 4 }, // LD E, C
@@ -193,7 +305,7 @@ impl CPU {
             0x5B => { 4 }, // LD E, E
             0x5C => { self.registers.e = self.registers.h; 4 }, // LD E, H
             0x5D => { self.registers.e = self.registers.l; 4 }, // LD E, L
-            0x5E => { self.registers.e = self.memory.read(self.registers.hl()); 8 }, // LD E, (HL)
+            0x5E => { self.registers.e = self.read_byte(self.registers.hl()); 8 }, // LD E, (HL)
             0x5F => { self.registers.e = self.registers.a; 4 }, // LD E, A
             0x60 => { self.registers.h = self.registers.b; 4 }, // LD H, B
             0x61 => { self.registers.h = self.registers.c; 4 }, // LD H, C
@@ -201,7 +313,7 @@ impl CPU {
             0x63 => { self.registers.h = self.registers.e; 4 }, // LD H, E
             0x64 => { 4 }, // LD H, H
             0x65 => { self.registers.h = self.registers.l; 4 }, // LD H, L
-            0x66 => { self.registers.h = self.memory.read(self.registers.hl()); 8 }, // LD H, (HL)
+            0x66 => { self.registers.h = self.read_byte(self.registers.hl()); 8 }, // LD H, (HL)
             0x67 => { self.registers.h = self.registers.a; 4 }, // LD H, A
             0x68 => { self.registers.l = self.registers.b; 4 }, // LD L, B
             0x69 => { self.registers.l = self.registers.c; 4 }, // LD L, C
@@ -209,23 +321,23 @@ impl CPU {
             0x6B => { self.registers.l = self.registers.e; 4 }, // LD L, E
             0x6C => { self.registers.l = self.registers.h; 4 }, // LD L, H
             0x6D => { 4 }, // LD L, L
-            0x6E => { self.registers.l = self.memory.read(self.registers.hl()); 8 }, // LD L, (HL)
+            0x6E => { self.registers.l = self.read_byte(self.registers.hl()); 8 }, // LD L, (HL)
             0x6F => { self.registers.l = self.registers.a; 4 }, // LD L, A
-            0x70 => { self.memory.write(self.registers.hl(), self.registers.b); 8 }, // LD (HL), B
-            0x71 => { self.memory.write(self.registers.hl(), self.registers.c); 8 }, // LD (HL), C
-            0x72 => { self.memory.write(self.registers.hl(), self.registers.d); 8 }, // LD (HL), D
-            0x73 => { self.memory.write(self.registers.hl(), self.registers.e); 8 }, // LD (HL), E
-            0x74 => { self.memory.write(self.registers.hl(), self.registers.h); 8 }, // LD (HL), H
-            0x75 => { self.memory.write(self.registers.hl(), self.registers.l); 8 }, // LD (HL), L
-            0x76 => { self.halt = true; 4 }, // HALT
-            0x77 => { self.memory.write(self.registers.hl(), self.registers.a); 8 }, // LD (HL), A
+            0x70 => { self.write_byte(self.registers.hl(), self.registers.b); 8 }, // LD (HL), B
+            0x71 => { self.write_byte(self.registers.hl(), self.registers.c); 8 }, // LD (HL), C
+            0x72 => { self.write_byte(self.registers.hl(), self.registers.d); 8 }, // LD (HL), D
+            0x73 => { self.write_byte(self.registers.hl(), self.registers.e); 8 }, // LD (HL), E
+            0x74 => { self.write_byte(self.registers.hl(), self.registers.h); 8 }, // LD (HL), H
+            0x75 => { self.write_byte(self.registers.hl(), self.registers.l); 8 }, // LD (HL), L
+            0x76 => { self.halt(); 4 }, // HALT
+            0x77 => { self.write_byte(self.registers.hl(), self.registers.a); 8 }, // LD (HL), A
             0x78 => { self.registers.a = self.registers.b; 4 }, // LD A, B
             0x79 => { self.registers.a = self.registers.c; 4 }, // LD A, C
             0x7A => { self.registers.a = self.registers.d; 4 }, // LD A, D
             0x7B => { self.registers.a = self.registers.e; 4 }, // LD A, E
             0x7C => { self.registers.a = self.registers.h; 4 }, // LD A, H
             0x7D => { self.registers.a = self.registers.l; 4 }, // LD A, L
-            0x7E => { self.registers.a = self.memory.read(self.registers.hl()); 8 }, // LD A, (HL)
+            0x7E => { self.registers.a = self.read_byte(self.registers.hl()); 8 }, // LD A, (HL)
             0x7F => { 4 }, // LD A, A
             0x80 => { self.add(self.registers.b, false); 4 }, // ADD A, B
             0x81 => { self.add(self.registers.c, false); 4 }, // ADD A, C
@@ -233,7 +345,7 @@ impl CPU {
             0x83 => { self.add(self.registers.e, false); 4 }, // ADD A, E
             0x84 => { self.add(self.registers.h, false); 4 }, // ADD A, H
             0x85 => { self.add(self.registers.l, false); 4 }, // ADD A, L
-            0x86 => { self.add(self.memory.read(self.registers.hl()), false); 8 }, // ADD A, (HL)
+            0x86 => { let v = self.read_byte(self.registers.hl()); self.add(v, false); 8 }, // ADD A, (HL)
             0x87 => { self.add(self.registers.a, false); 4 }, // ADD A, A
             0x88 => { self.add(self.registers.b, true); 4 }, // ADC A, B
             0x89 => { self.add(self.registers.c, true); 4 }, // ADC A, C
@@ -241,7 +353,7 @@ impl CPU {
             0x8B => { self.add(self.registers.e, true); 4 }, // ADC A, E
             0x8C => { self.add(self.registers.h, true); 4 }, // ADC A, H
             0x8D => { self.add(self.registers.l, true); 4 }, // ADC A, L
-            0x8E => { self.add(self.memory.read(self.registers.hl()), true); 8 }, // ADC A, (HL)
+            0x8E => { let v = self.read_byte(self.registers.hl()); self.add(v, true); 8 }, // ADC A, (HL)
             0x8F => { self.add(self.registers.a, true); 4 }, // ADC A, A
             0x90 => { self.sub(self.registers.b, false); 4 }, // SUB B
             0x91 => { self.sub(self.registers.c, false); 4 }, // SUB C
@@ -249,7 +361,7 @@ impl CPU {
             0x93 => { self.sub(self.registers.e, false); 4 }, // SUB E
             0x94 => { self.sub(self.registers.h, false); 4 }, // SUB H
             0x95 => { self.sub(self.registers.l, false); 4 }, // SUB L
-            0x96 => { self.sub(self.memory.read(self.registers.hl()), false); 8 }, // SUB (HL)
+            0x96 => { let v = self.read_byte(self.registers.hl()); self.sub(v, false); 8 }, // SUB (HL)
             0x97 => { self.sub(self.registers.a, false); 4 }, // SUB A
             0x98 => { self.sub(self.registers.b, true); 4 }, // SBC A, B
             0x99 => { self.sub(self.registers.c, true); 4 }, // SBC A, C
@@ -257,7 +369,7 @@ impl CPU {
             0x9B => { self.sub(self.registers.e, true); 4 }, // SBC A, E
             0x9C => { self.sub(self.registers.h, true); 4 }, // SBC A, H
             0x9D => { self.sub(self.registers.l, true); 4 }, // SBC A, L
-            0x9E => { self.sub(self.memory.read(self.registers.hl()), true); 8 }, // SBC A, (HL)
+            0x9E => { let v = self.read_byte(self.registers.hl()); self.sub(v, true); 8 }, // SBC A, (HL)
             0x9F => { self.sub(self.registers.a, true); 4 }, // SBC A, A
             0xA0 => { self.and(self.registers.b); 4 }, // AND B
             0xA1 => { self.and(self.registers.c); 4 }, // AND C
@@ -265,7 +377,7 @@ impl CPU {
             0xA3 => { self.and(self.registers.e); 4 }, // AND E
             0xA4 => { self.and(self.registers.h); 4 }, // AND H
             0xA5 => { self.and(self.registers.l); 4 }, // AND L
-            0xA6 => { self.and(self.memory.read(self.registers.hl())); 8 }, // AND (HL)
+            0xA6 => { let v = self.read_byte(self.registers.hl()); self.and(v); 8 }, // AND (HL)
             0xA7 => { self.and(self.registers.a); 4 }, // AND A
             0xA8 => { self.xor(self.registers.b); 4 }, // XOR B
             0xA9 => { self.xor(self.registers.c); 4 }, // XOR C
@@ -273,7 +385,7 @@ impl CPU {
             0xAB => { self.xor(self.registers.e); 4 }, // XOR E
             0xAC => { self.xor(self.registers.h); 4 }, // XOR H
             0xAD => { self.xor(self.registers.l); 4 }, // XOR L
-            0xAE => { self.xor(self.memory.read(self.registers.hl())); 8 }, // XOR (HL)
+            0xAE => { let v = self.read_byte(self.registers.hl()); self.xor(v); 8 }, // XOR (HL)
             0xAF => { self.xor(self.registers.a); 4 }, // XOR A
             0xB0 => { self.or(self.registers.b); 4 }, // OR B
             0xB1 => { self.or(self.registers.c); 4 }, // OR C
@@ -281,7 +393,7 @@ impl CPU {
             0xB3 => { self.or(self.registers.e); 4 }, // OR E
             0xB4 => { self.or(self.registers.h); 4 }, // OR H
             0xB5 => { self.or(self.registers.l); 4 }, // OR L
-            0xB6 => { self.or(self.memory.read(self.registers.hl())); 8 }, // OR (HL)
+            0xB6 => { let v = self.read_byte(self.registers.hl()); self.or(v); 8 }, // OR (HL)
             0xB7 => { self.or(self.registers.a); 4 }, // OR A
             0xB8 => { self.cp(self.registers.b); 4 }, // CP B
             0xB9 => { self.cp(self.registers.c); 4 }, // CP C
@@ -289,55 +401,64 @@ impl CPU {
             0xBB => { self.cp(self.registers.e); 4 }, // CP E
             0xBC => { self.cp(self.registers.h); 4 }, // CP H
             0xBD => { self.cp(self.registers.l); 4 }, // CP L
-            0xBE => { self.cp(self.memory.read(self.registers.hl())); 8 }, // CP (HL)
+            0xBE => { let v = self.read_byte(self.registers.hl()); self.cp(v); 8 }, // CP (HL)
             0xBF => { self.cp(self.registers.a); 4 }, // CP A
             0xC0 => { if !self.registers.get_flag(Flag::Zero) { self.registers.pc = self.pop_stack(); 20 } else { 8 } }, // RET NZ
             0xC1 => { let v = self.pop_stack(); self.registers.set_bc(v); 12 }, // POP BC
-            0xC2 => { if !self.registers.get_flag(Flag::Zero) { self.registers.pc = self.fetch_word(); 16 } else { self.registers.pc += 2; 12 } }, // JP NZ, a16
+            0xC2 => { if !self.registers.get_flag(Flag::Zero) { self.registers.pc = self.fetch_word(); 16 } else { self.fetch_word(); 12 } }, // JP NZ, a16
             0xC3 => { self.registers.pc = self.fetch_word(); 16 }, // JP a16
-            0xC4 => { if !self.registers.get_flag(Flag::Zero) { self.push_stack(self.registers.pc + 2); self.registers.pc = self.fetch_word(); 24 } else { self.registers.pc += 2; 12 } }, // CALL NZ, a16
+            0xC4 => { if !self.registers.get_flag(Flag::Zero) { self.call_a16(); 24 } else { self.fetch_word(); 12 } }, // CALL NZ, a16
             0xC5 => { self.push_stack(self.registers.bc()); 16 }, // PUSH BC
             0xC6 => { let v = self.fetch_byte(); self.add(v, false); 8 }, // ADD A, d8
             0xC7 => { self.push_stack(self.registers.pc); self.registers.pc = 0x00; 16 }, // RST 00H
             0xC8 => { if self.registers.get_flag(Flag::Zero) { self.registers.pc = self.pop_stack(); 20 } else { 8 } }, // RET Z
             0xC9 => { self.registers.pc = self.pop_stack(); 16 }, // RET
-            0xCA => { if self.registers.get_flag(Flag::Zero) { self.registers.pc = self.fetch_word(); 16 } else { self.registers.pc += 2; 12 } }, // JP Z, a16
+            0xCA => { if self.registers.get_flag(Flag::Zero) { self.registers.pc = self.fetch_word(); 16 } else { self.fetch_word(); 12 } }, // JP Z, a16
             0xcb => { self.cb_call() }, // CB
-            0xcd => { self.push_stack(self.registers.pc + 2); self.registers.pc = self.fetch_word(); 24 }, // CALL a16
+            0xCC => { if self.registers.get_flag(Flag::Zero) { self.call_a16(); 24 } else { self.fetch_word(); 12 } }, // CALL Z, a16
+            0xcd => { self.call_a16(); 24 }, // CALL a16
             0xCE => { let v = self.fetch_byte(); self.add(v, true); 8 }, // ADC A, d8
             0xcf => { self.push_stack(self.registers.pc); self.registers.pc = 0x08; 16 }, // RST 08H
             0xD0 => { if !self.registers.get_flag(Flag::Carry) { self.registers.pc = self.pop_stack(); 20 } else { 8 } }, // RET NC
             0xD1 => { let v = self.pop_stack(); self.registers.set_de(v); 12 }, // POP DE
-            0xd2 => { if !self.registers.get_flag(Flag::Carry) { self.registers.pc = self.fetch_word(); 12 } else { self.registers.pc += 2; 12 } }, // JP NC, a16
+            0xd2 => { if !self.registers.get_flag(Flag::Carry) { self.registers.pc = self.fetch_word(); 16 } else { self.fetch_word(); 12 } }, // JP NC, a16
+            0xD4 => { if !self.registers.get_flag(Flag::Carry) { self.call_a16(); 24 } else { self.fetch_word(); 12 } }, // CALL NC, a16
             0xD5 => { self.push_stack(self.registers.de()); 16 }, // PUSH DE
             0xD6 => { let v = self.fetch_byte(); self.sub(v, false); 8 }, // SUB d8
+            0xd7 => { self.push_stack(self.registers.pc); self.registers.pc = 0x10; 16 }, // RST 10H
             0xD8 => { if self.registers.get_flag(Flag::Carry) { self.registers.pc = self.pop_stack(); 20 } else { 8 } }, // RET C
             0xD9 => { self.registers.pc = self.pop_stack(); self.ime = true; 16 }, // RETI
-            0xda => { if self.registers.get_flag(Flag::Carry) { self.registers.pc = self.fetch_word(); 16 } else { self.registers.pc += 2; 12 } }, // JP C, a16
+            0xda => { if self.registers.get_flag(Flag::Carry) { self.registers.pc = self.fetch_word(); 16 } else { self.fetch_word(); 12 } }, // JP C, a16
+            0xDC => { if self.registers.get_flag(Flag::Carry) { self.call_a16(); 24 } else { self.fetch_word(); 12 } }, // CALL C, a16
             0xde => { let v = self.fetch_byte(); self.sub(v, true); 8 }, // SBC A, d8
             0xdf => { self.push_stack(self.registers.pc); self.registers.pc = 0x18; 16 }, // RST 18H
-            0xE0 => { let v = 0xFF00 | self.fetch_byte() as u16; self.memory.write(v, self.registers.a); 12 }, // LDH (a8), A
+            0xE0 => { let v = 0xFF00 | self.fetch_byte() as u16; self.write_byte(v, self.registers.a); 12 }, // LDH (a8), A
             0xe1 => { let v = self.pop_stack(); self.registers.set_hl(v); 12 }, // POP HL
-            0xe2 => { let v = 0xFF00 | self.registers.c as u16; self.memory.write(v, self.registers.a); 8 }, // LD (C), A
+            0xe2 => { let v = 0xFF00 | self.registers.c as u16; self.write_byte(v, self.registers.a); 8 }, // LD (C), A
             0xe5 => { self.push_stack(self.registers.hl()); 16 }, // PUSH HL
             0xe6 => { let v = self.fetch_byte(); self.and(v); 8 }, // AND d8
+            0xe7 => { self.push_stack(self.registers.pc); self.registers.pc = 0x20; 16 }, // RST 20H
+            0xe8 => { self.registers.sp = self.add_sp_r8(); 16 }, // ADD SP, r8
             0xe9 => { self.registers.pc = self.registers.hl(); 4 }, // JP (HL)
-            0xea => { let v = self.fetch_word(); self.memory.write(v, self.registers.a); 16 }, // LD (a16), A
+            0xea => { let v = self.fetch_word(); self.write_byte(v, self.registers.a); 16 }, // LD (a16), A
             0xee => { let v = self.fetch_byte(); self.xor(v); 8 }, // XOR d8
             0xef => { self.push_stack(self.registers.pc); self.registers.pc = 0x28; 16 }, // RST 28H
-            0xF0 => { let v = 0xFF00 | self.fetch_byte() as u16; self.registers.a = self.memory.read(v); 12 }, // LDH A, (a8)
+            0xF0 => { let v = 0xFF00 | self.fetch_byte() as u16; self.registers.a = self.read_byte(v); 12 }, // LDH A, (a8)
             0xf1 => { let v = self.pop_stack(); self.registers.set_af(v); 12 }, // POP AF
-            0xF3 => { self.ime = false; 4 }, // DI
+            0xf2 => { let v = 0xFF00 | self.registers.c as u16; self.registers.a = self.read_byte(v); 8 }, // LD A, (C)
+            0xF3 => { self.ime = false; self.ei_delay = false; 4 }, // DI
             0xf5 => { self.push_stack(self.registers.af()); 16 }, // PUSH AF
             0xf6 => { let v = self.fetch_byte(); self.or(v); 8 }, // OR d8
-            0xfa => { let v = self.fetch_word(); self.registers.a = self.memory.read(v); 16 }, // LD A, (a16)
-            0xfb => { self.ime = true; 4 }
+            0xf7 => { self.push_stack(self.registers.pc); self.registers.pc = 0x30; 16 }, // RST 30H
+            0xf8 => { let v = self.add_sp_r8(); self.registers.set_hl(v); 12 }, // LD HL, SP+r8
+            0xf9 => { self.registers.sp = self.registers.hl(); 8 }, // LD SP, HL
+            0xfa => { let v = self.fetch_word(); self.registers.a = self.read_byte(v); 16 }, // LD A, (a16)
+            0xfb => { self.ei_delay = true; 4 }, // EI
             0xFE => { let v = self.fetch_byte(); self.cp(v); 8 }, // CP d8
             0xff => { self.push_stack(self.registers.pc); self.registers.pc = 0x38; 16 }, // RST 38H
             0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
-                unreachable!("Unreachable opcode: {:#04x} PC: {:#06x}", opcode, self.registers.pc); 0
+                unreachable!("Unreachable opcode: {:#04x} PC: {:#06x}", opcode, self.registers.pc)
             }, // Unreachable opcodes
-            _ => { unimplemented!("Unimplemented opcode: {:#04x} PC: {:#06x}", opcode, self.registers.pc); 0 },
         }
     }
 
@@ -350,7 +471,7 @@ impl CPU {
             0x03 => { self.registers.e = self.rlc(self.registers.e); 8 }, // RLC E
             0x04 => { self.registers.h = self.rlc(self.registers.h); 8 }, // RLC H
             0x05 => { self.registers.l = self.rlc(self.registers.l); 8 }, // RLC L
-            0x06 => { let v = self.memory.read(self.registers.hl()); let v2 = self.rlc(v); self.memory.write(self.registers.hl(), v2); 16 }, // RLC (HL)
+            0x06 => { let v = self.read_byte(self.registers.hl()); let v2 = self.rlc(v); self.write_byte(self.registers.hl(), v2); 16 }, // RLC (HL)
             0x07 => { self.registers.a = self.rlc(self.registers.a); 8 }, // RLC A
             0x08 => { self.registers.b = self.rrc(self.registers.b); 8 }, // RRC B
             0x09 => { self.registers.c = self.rrc(self.registers.c); 8 }, // RRC C
@@ -358,7 +479,7 @@ impl CPU {
             0x0B => { self.registers.e = self.rrc(self.registers.e); 8 }, // RRC E
             0x0C => { self.registers.h = self.rrc(self.registers.h); 8 }, // RRC H
             0x0D => { self.registers.l = self.rrc(self.registers.l); 8 }, // RRC L
-            0x0E => { let v = self.memory.read(self.registers.hl()); let v2 = self.rrc(v); self.memory.write(self.registers.hl(), v2); 16 }, // RRC (HL)
+            0x0E => { let v = self.read_byte(self.registers.hl()); let v2 = self.rrc(v); self.write_byte(self.registers.hl(), v2); 16 }, // RRC (HL)
             0x0F => { self.registers.a = self.rrc(self.registers.a); 8 }, // RRC A
             0x10 => { self.registers.b = self.rl(self.registers.b); 8 }, // RL B
             0x11 => { self.registers.c = self.rl(self.registers.c); 8 }, // RL C
@@ -366,7 +487,7 @@ impl CPU {
             0x13 => { self.registers.e = self.rl(self.registers.e); 8 }, // RL E
             0x14 => { self.registers.h = self.rl(self.registers.h); 8 }, // RL H
             0x15 => { self.registers.l = self.rl(self.registers.l); 8 }, // RL L
-            0x16 => { let v = self.memory.read(self.registers.hl()); let v2 = self.rl(v); self.memory.write(self.registers.hl(), v2); 16 }, // RL (HL)
+            0x16 => { let v = self.read_byte(self.registers.hl()); let v2 = self.rl(v); self.write_byte(self.registers.hl(), v2); 16 }, // RL (HL)
             0x17 => { self.registers.a = self.rl(self.registers.a); 8 }, // RL A
             0x18 => { self.registers.b = self.rr(self.registers.b); 8 }, // RR B
             0x19 => { self.registers.c = self.rr(self.registers.c); 8 }, // RR C
@@ -374,7 +495,7 @@ impl CPU {
             0x1B => { self.registers.e = self.rr(self.registers.e); 8 }, // RR E
             0x1C => { self.registers.h = self.rr(self.registers.h); 8 }, // RR H
             0x1D => { self.registers.l = self.rr(self.registers.l); 8 }, // RR L
-            0x1E => { let v = self.memory.read(self.registers.hl()); let v2 = self.rr(v); self.memory.write(self.registers.hl(), v2); 16 }, // RR (HL)
+            0x1E => { let v = self.read_byte(self.registers.hl()); let v2 = self.rr(v); self.write_byte(self.registers.hl(), v2); 16 }, // RR (HL)
             0x1F => { self.registers.a = self.rr(self.registers.a); 8 }, // RR A
             0x20 => { self.registers.b = self.sla(self.registers.b); 8 }, // SLA B
             0x21 => { self.registers.c = self.sla(self.registers.c); 8 }, // SLA C
@@ -382,7 +503,7 @@ impl CPU {
             0x23 => { self.registers.e = self.sla(self.registers.e); 8 }, // SLA E
             0x24 => { self.registers.h = self.sla(self.registers.h); 8 }, // SLA H
             0x25 => { self.registers.l = self.sla(self.registers.l); 8 }, // SLA L
-            0x26 => { let v = self.memory.read(self.registers.hl()); let v2 = self.sla(v); self.memory.write(self.registers.hl(), v2); 16 }, // SLA (HL)
+            0x26 => { let v = self.read_byte(self.registers.hl()); let v2 = self.sla(v); self.write_byte(self.registers.hl(), v2); 16 }, // SLA (HL)
             0x27 => { self.registers.a = self.sla(self.registers.a); 8 }, // SLA A
             0x28 => { self.registers.b = self.sra(self.registers.b); 8 }, // SRA B
             0x29 => { self.registers.c = self.sra(self.registers.c); 8 }, // SRA C
@@ -390,7 +511,7 @@ impl CPU {
             0x2B => { self.registers.e = self.sra(self.registers.e); 8 }, // SRA E
             0x2C => { self.registers.h = self.sra(self.registers.h); 8 }, // SRA H
             0x2D => { self.registers.l = self.sra(self.registers.l); 8 }, // SRA L
-            0x2E => { let v = self.memory.read(self.registers.hl()); let v2 = self.sra(v); self.memory.write(self.registers.hl(), v2); 16 }, // SRA (HL)
+            0x2E => { let v = self.read_byte(self.registers.hl()); let v2 = self.sra(v); self.write_byte(self.registers.hl(), v2); 16 }, // SRA (HL)
             0x2F => { self.registers.a = self.sra(self.registers.a); 8 }, // SRA A
             0x30 => { self.registers.b = self.swap(self.registers.b); 8 }, // SWAP B
             0x31 => { self.registers.c = self.swap(self.registers.c); 8 }, // SWAP C
@@ -398,7 +519,7 @@ impl CPU {
             0x33 => { self.registers.e = self.swap(self.registers.e); 8 }, // SWAP E
             0x34 => { self.registers.h = self.swap(self.registers.h); 8 }, // SWAP H
             0x35 => { self.registers.l = self.swap(self.registers.l); 8 }, // SWAP L
-            0x36 => { let v = self.memory.read(self.registers.hl()); let v2 = self.swap(v); self.memory.write(self.registers.hl(), v2); 16 }, // SWAP (HL)
+            0x36 => { let v = self.read_byte(self.registers.hl()); let v2 = self.swap(v); self.write_byte(self.registers.hl(), v2); 16 }, // SWAP (HL)
             0x37 => { self.registers.a = self.swap(self.registers.a); 8 }, // SWAP A
             0x38 => { self.registers.b = self.srl(self.registers.b); 8 }, // SRL B
             0x39 => { self.registers.c = self.srl(self.registers.c); 8 }, // SRL C
@@ -406,7 +527,7 @@ impl CPU {
             0x3B => { self.registers.e = self.srl(self.registers.e); 8 }, // SRL E
             0x3C => { self.registers.h = self.srl(self.registers.h); 8 }, // SRL H
             0x3D => { self.registers.l = self.srl(self.registers.l); 8 }, // SRL L
-            0x3E => { let v = self.memory.read(self.registers.hl()); let v2 = self.srl(v); self.memory.write(self.registers.hl(), v2); 16 }, // SRL (HL)
+            0x3E => { let v = self.read_byte(self.registers.hl()); let v2 = self.srl(v); self.write_byte(self.registers.hl(), v2); 16 }, // SRL (HL)
             0x3F => { self.registers.a = self.srl(self.registers.a); 8 }, // SRL A
             0x40 => { self.bit(self.registers.b, 0); 8 }, // BIT 0, B
             0x41 => { self.bit(self.registers.c, 0); 8 }, // BIT 0, C
@@ -414,7 +535,7 @@ impl CPU {
             0x43 => { self.bit(self.registers.e, 0); 8 }, // BIT 0, E
             0x44 => { self.bit(self.registers.h, 0); 8 }, // BIT 0, H
             0x45 => { self.bit(self.registers.l, 0); 8 }, // BIT 0, L
-            0x46 => { let v = self.memory.read(self.registers.hl()); self.bit(v, 0); 16 }, // BIT 0, (HL)
+            0x46 => { let v = self.read_byte(self.registers.hl()); self.bit(v, 0); 12 }, // BIT 0, (HL)
             0x47 => { self.bit(self.registers.a, 0); 8 }, // BIT 0, A
             0x48 => { self.bit(self.registers.b, 1); 8 }, // BIT 1, B
             0x49 => { self.bit(self.registers.c, 1); 8 }, // BIT 1, C
@@ -422,7 +543,7 @@ impl CPU {
             0x4B => { self.bit(self.registers.e, 1); 8 }, // BIT 1, E
             0x4C => { self.bit(self.registers.h, 1); 8 }, // BIT 1, H
             0x4D => { self.bit(self.registers.l, 1); 8 }, // BIT 1, L
-            0x4E => { let v = self.memory.read(self.registers.hl()); self.bit(v, 1); 16 }, // BIT 1, (HL)
+            0x4E => { let v = self.read_byte(self.registers.hl()); self.bit(v, 1); 12 }, // BIT 1, (HL)
             0x4F => { self.bit(self.registers.a, 1); 8 }, // BIT 1, A
             0x50 => { self.bit(self.registers.b, 2); 8 }, // BIT 2, B
             0x51 => { self.bit(self.registers.c, 2); 8 }, // BIT 2, C
@@ -430,7 +551,7 @@ impl CPU {
             0x53 => { self.bit(self.registers.e, 2); 8 }, // BIT 2, E
             0x54 => { self.bit(self.registers.h, 2); 8 }, // BIT 2, H
             0x55 => { self.bit(self.registers.l, 2); 8 }, // BIT 2, L
-            0x56 => { let v = self.memory.read(self.registers.hl()); self.bit(v, 2); 16 }, // BIT 2, (HL)
+            0x56 => { let v = self.read_byte(self.registers.hl()); self.bit(v, 2); 12 }, // BIT 2, (HL)
             0x57 => { self.bit(self.registers.a, 2); 8 }, // BIT 2, A
             0x58 => { self.bit(self.registers.b, 3); 8 }, // BIT 3, B
             0x59 => { self.bit(self.registers.c, 3); 8 }, // BIT 3, C
@@ -438,7 +559,7 @@ impl CPU {
             0x5B => { self.bit(self.registers.e, 3); 8 }, // BIT 3, E
             0x5C => { self.bit(self.registers.h, 3); 8 }, // BIT 3, H
             0x5D => { self.bit(self.registers.l, 3); 8 }, // BIT 3, L
-            0x5E => { let v = self.memory.read(self.registers.hl()); self.bit(v, 3); 16 }, // BIT 3, (HL)
+            0x5E => { let v = self.read_byte(self.registers.hl()); self.bit(v, 3); 12 }, // BIT 3, (HL)
             0x5F => { self.bit(self.registers.a, 3); 8 }, // BIT 3, A
             0x60 => { self.bit(self.registers.b, 4); 8 }, // BIT 4, B
             0x61 => { self.bit(self.registers.c, 4); 8 }, // BIT 4, C
@@ -446,7 +567,7 @@ impl CPU {
             0x63 => { self.bit(self.registers.e, 4); 8 }, // BIT 4, E
             0x64 => { self.bit(self.registers.h, 4); 8 }, // BIT 4, H
             0x65 => { self.bit(self.registers.l, 4); 8 }, // BIT 4, L
-            0x66 => { let v = self.memory.read(self.registers.hl()); self.bit(v, 4); 16 }, // BIT 4, (HL)
+            0x66 => { let v = self.read_byte(self.registers.hl()); self.bit(v, 4); 12 }, // BIT 4, (HL)
             0x67 => { self.bit(self.registers.a, 4); 8 }, // BIT 4, A
             0x68 => { self.bit(self.registers.b, 5); 8 }, // BIT 5, B
             0x69 => { self.bit(self.registers.c, 5); 8 }, // BIT 5, C
@@ -454,7 +575,7 @@ impl CPU {
             0x6B => { self.bit(self.registers.e, 5); 8 }, // BIT 5, E
             0x6C => { self.bit(self.registers.h, 5); 8 }, // BIT 5, H
             0x6D => { self.bit(self.registers.l, 5); 8 }, // BIT 5, L
-            0x6E => { let v = self.memory.read(self.registers.hl()); self.bit(v, 5); 16 }, // BIT 5, (HL)
+            0x6E => { let v = self.read_byte(self.registers.hl()); self.bit(v, 5); 12 }, // BIT 5, (HL)
             0x6F => { self.bit(self.registers.a, 5); 8 }, // BIT 5, A
             0x70 => { self.bit(self.registers.b, 6); 8 }, // BIT 6, B
             0x71 => { self.bit(self.registers.c, 6); 8 }, // BIT 6, C
@@ -462,7 +583,7 @@ impl CPU {
             0x73 => { self.bit(self.registers.e, 6); 8 }, // BIT 6, E
             0x74 => { self.bit(self.registers.h, 6); 8 }, // BIT 6, H
             0x75 => { self.bit(self.registers.l, 6); 8 }, // BIT 6, L
-            0x76 => { let v = self.memory.read(self.registers.hl()); self.bit(v, 6); 16 }, // BIT 6, (HL)
+            0x76 => { let v = self.read_byte(self.registers.hl()); self.bit(v, 6); 12 }, // BIT 6, (HL)
             0x77 => { self.bit(self.registers.a, 6); 8 }, // BIT 6, A
             0x78 => { self.bit(self.registers.b, 7); 8 }, // BIT 7, B
             0x79 => { self.bit(self.registers.c, 7); 8 }, // BIT 7, C
@@ -470,7 +591,7 @@ impl CPU {
             0x7B => { self.bit(self.registers.e, 7); 8 }, // BIT 7, E
             0x7C => { self.bit(self.registers.h, 7); 8 }, // BIT 7, H
             0x7D => { self.bit(self.registers.l, 7); 8 }, // BIT 7, L
-            0x7E => { let v = self.memory.read(self.registers.hl()); self.bit(v, 7); 16 }, // BIT 7, (HL)
+            0x7E => { let v = self.read_byte(self.registers.hl()); self.bit(v, 7); 12 }, // BIT 7, (HL)
             0x7F => { self.bit(self.registers.a, 7); 8 }, // BIT 7, A
             0x80 => { self.registers.b = self.res(self.registers.b, 0); 8 }, // RES 0, B
             0x81 => { self.registers.c = self.res(self.registers.c, 0); 8 }, // RES 0, C
@@ -478,7 +599,7 @@ impl CPU {
             0x83 => { self.registers.e = self.res(self.registers.e, 0); 8 }, // RES 0, E
             0x84 => { self.registers.h = self.res(self.registers.h, 0); 8 }, // RES 0, H
             0x85 => { self.registers.l = self.res(self.registers.l, 0); 8 }, // RES 0, L
-            0x86 => { let v = self.memory.read(self.registers.hl()); let v2 = self.res(v, 0); self.memory.write(self.registers.hl(), v2); 16 }, // RES 0, (HL)
+            0x86 => { let v = self.read_byte(self.registers.hl()); let v2 = self.res(v, 0); self.write_byte(self.registers.hl(), v2); 16 }, // RES 0, (HL)
             0x87 => { self.registers.a = self.res(self.registers.a, 0); 8 }, // RES 0, A
             0x88 => { self.registers.b = self.res(self.registers.b, 1); 8 }, // RES 1, B
             0x89 => { self.registers.c = self.res(self.registers.c, 1); 8 }, // RES 1, C
@@ -486,7 +607,7 @@ impl CPU {
             0x8B => { self.registers.e = self.res(self.registers.e, 1); 8 }, // RES 1, E
             0x8C => { self.registers.h = self.res(self.registers.h, 1); 8 }, // RES 1, H
             0x8D => { self.registers.l = self.res(self.registers.l, 1); 8 }, // RES 1, L
-            0x8E => { let v = self.memory.read(self.registers.hl()); let v2 = self.res(v, 1); self.memory.write(self.registers.hl(), v2); 16 }, // RES 1, (HL)
+            0x8E => { let v = self.read_byte(self.registers.hl()); let v2 = self.res(v, 1); self.write_byte(self.registers.hl(), v2); 16 }, // RES 1, (HL)
             0x8F => { self.registers.a = self.res(self.registers.a, 1); 8 }, // RES 1, A
             0x90 => { self.registers.b = self.res(self.registers.b, 2); 8 }, // RES 2, B
             0x91 => { self.registers.c = self.res(self.registers.c, 2); 8 }, // RES 2, C
@@ -494,7 +615,7 @@ impl CPU {
             0x93 => { self.registers.e = self.res(self.registers.e, 2); 8 }, // RES 2, E
             0x94 => { self.registers.h = self.res(self.registers.h, 2); 8 }, // RES 2, H
             0x95 => { self.registers.l = self.res(self.registers.l, 2); 8 }, // RES 2, L
-            0x96 => { let v = self.memory.read(self.registers.hl()); let v2 = self.res(v, 2); self.memory.write(self.registers.hl(), v2); 16 }, // RES 2, (HL)
+            0x96 => { let v = self.read_byte(self.registers.hl()); let v2 = self.res(v, 2); self.write_byte(self.registers.hl(), v2); 16 }, // RES 2, (HL)
             0x97 => { self.registers.a = self.res(self.registers.a, 2); 8 }, // RES 2, A
             0x98 => { self.registers.b = self.res(self.registers.b, 3); 8 }, // RES 3, B
             0x99 => { self.registers.c = self.res(self.registers.c, 3); 8 }, // RES 3, C
@@ -502,7 +623,7 @@ impl CPU {
             0x9B => { self.registers.e = self.res(self.registers.e, 3); 8 }, // RES 3, E
             0x9C => { self.registers.h = self.res(self.registers.h, 3); 8 }, // RES 3, H
             0x9D => { self.registers.l = self.res(self.registers.l, 3); 8 }, // RES 3, L
-            0x9E => { let v = self.memory.read(self.registers.hl()); let v2 = self.res(v, 3); self.memory.write(self.registers.hl(), v2); 16 }, // RES 3, (HL)
+            0x9E => { let v = self.read_byte(self.registers.hl()); let v2 = self.res(v, 3); self.write_byte(self.registers.hl(), v2); 16 }, // RES 3, (HL)
             0x9F => { self.registers.a = self.res(self.registers.a, 3); 8 }, // RES 3, A
             0xA0 => { self.registers.b = self.res(self.registers.b, 4); 8 }, // RES 4, B
             0xA1 => { self.registers.c = self.res(self.registers.c, 4); 8 }, // RES 4, C
@@ -510,7 +631,7 @@ impl CPU {
             0xA3 => { self.registers.e = self.res(self.registers.e, 4); 8 }, // RES 4, E
             0xA4 => { self.registers.h = self.res(self.registers.h, 4); 8 }, // RES 4, H
             0xA5 => { self.registers.l = self.res(self.registers.l, 4); 8 }, // RES 4, L
-            0xA6 => { let v = self.memory.read(self.registers.hl()); let v2 = self.res(v, 4); self.memory.write(self.registers.hl(), v2); 16 }, // RES 4, (HL)
+            0xA6 => { let v = self.read_byte(self.registers.hl()); let v2 = self.res(v, 4); self.write_byte(self.registers.hl(), v2); 16 }, // RES 4, (HL)
             0xA7 => { self.registers.a = self.res(self.registers.a, 4); 8 }, // RES 4, A
             0xA8 => { self.registers.b = self.res(self.registers.b, 5); 8 }, // RES 5, B
             0xA9 => { self.registers.c = self.res(self.registers.c, 5); 8 }, // RES 5, C
@@ -518,7 +639,7 @@ impl CPU {
             0xAB => { self.registers.e = self.res(self.registers.e, 5); 8 }, // RES 5, E
             0xAC => { self.registers.h = self.res(self.registers.h, 5); 8 }, // RES 5, H
             0xAD => { self.registers.l = self.res(self.registers.l, 5); 8 }, // RES 5, L
-            0xAE => { let v = self.memory.read(self.registers.hl()); let v2 = self.res(v, 5); self.memory.write(self.registers.hl(), v2); 16 }, // RES 5, (HL)
+            0xAE => { let v = self.read_byte(self.registers.hl()); let v2 = self.res(v, 5); self.write_byte(self.registers.hl(), v2); 16 }, // RES 5, (HL)
             0xAF => { self.registers.a = self.res(self.registers.a, 5); 8 }, // RES 5, A
             0xB0 => { self.registers.b = self.res(self.registers.b, 6); 8 }, // RES 6, B
             0xB1 => { self.registers.c = self.res(self.registers.c, 6); 8 }, // RES 6, C
@@ -526,7 +647,7 @@ impl CPU {
             0xB3 => { self.registers.e = self.res(self.registers.e, 6); 8 }, // RES 6, E
             0xB4 => { self.registers.h = self.res(self.registers.h, 6); 8 }, // RES 6, H
             0xB5 => { self.registers.l = self.res(self.registers.l, 6); 8 }, // RES 6, L
-            0xB6 => { let v = self.memory.read(self.registers.hl()); let v2 = self.res(v, 6); self.memory.write(self.registers.hl(), v2); 16 }, // RES 6, (HL)
+            0xB6 => { let v = self.read_byte(self.registers.hl()); let v2 = self.res(v, 6); self.write_byte(self.registers.hl(), v2); 16 }, // RES 6, (HL)
             0xB7 => { self.registers.a = self.res(self.registers.a, 6); 8 }, // RES 6, A
             0xB8 => { self.registers.b = self.res(self.registers.b, 7); 8 }, // RES 7, B
             0xB9 => { self.registers.c = self.res(self.registers.c, 7); 8 }, // RES 7, C
@@ -534,7 +655,7 @@ impl CPU {
             0xBB => { self.registers.e = self.res(self.registers.e, 7); 8 }, // RES 7, E
             0xBC => { self.registers.h = self.res(self.registers.h, 7); 8 }, // RES 7, H
             0xBD => { self.registers.l = self.res(self.registers.l, 7); 8 }, // RES 7, L
-            0xBE => { let v = self.memory.read(self.registers.hl()); let v2 = self.res(v, 7); self.memory.write(self.registers.hl(), v2); 16 }, // RES 7, (HL)
+            0xBE => { let v = self.read_byte(self.registers.hl()); let v2 = self.res(v, 7); self.write_byte(self.registers.hl(), v2); 16 }, // RES 7, (HL)
             0xBF => { self.registers.a = self.res(self.registers.a, 7); 8 }, // RES 7, A
             0xC0 => { self.registers.b = self.set(self.registers.b, 0); 8 }, // SET 0, B
             0xC1 => { self.registers.c = self.set(self.registers.c, 0); 8 }, // SET 0, C
@@ -542,7 +663,7 @@ impl CPU {
             0xC3 => { self.registers.e = self.set(self.registers.e, 0); 8 }, // SET 0, E
             0xC4 => { self.registers.h = self.set(self.registers.h, 0); 8 }, // SET 0, H
             0xC5 => { self.registers.l = self.set(self.registers.l, 0); 8 }, // SET 0, L
-            0xC6 => { let v = self.memory.read(self.registers.hl()); let v2 = self.set(v, 0); self.memory.write(self.registers.hl(), v2); 16 }, // SET 0, (HL)
+            0xC6 => { let v = self.read_byte(self.registers.hl()); let v2 = self.set(v, 0); self.write_byte(self.registers.hl(), v2); 16 }, // SET 0, (HL)
             0xC7 => { self.registers.a = self.set(self.registers.a, 0); 8 }, // SET 0, A
             0xC8 => { self.registers.b = self.set(self.registers.b, 1); 8 }, // SET 1, B
             0xC9 => { self.registers.c = self.set(self.registers.c, 1); 8 }, // SET 1, C
@@ -550,7 +671,7 @@ impl CPU {
             0xCB => { self.registers.e = self.set(self.registers.e, 1); 8 }, // SET 1, E
             0xCC => { self.registers.h = self.set(self.registers.h, 1); 8 }, // SET 1, H
             0xCD => { self.registers.l = self.set(self.registers.l, 1); 8 }, // SET 1, L
-            0xCE => { let v = self.memory.read(self.registers.hl()); let v2 = self.set(v, 1); self.memory.write(self.registers.hl(), v2); 16 }, // SET 1, (HL)
+            0xCE => { let v = self.read_byte(self.registers.hl()); let v2 = self.set(v, 1); self.write_byte(self.registers.hl(), v2); 16 }, // SET 1, (HL)
             0xCF => { self.registers.a = self.set(self.registers.a, 1); 8 }, // SET 1, A
             0xD0 => { self.registers.b = self.set(self.registers.b, 2); 8 }, // SET 2, B
             0xD1 => { self.registers.c = self.set(self.registers.c, 2); 8 }, // SET 2, C
@@ -558,7 +679,7 @@ impl CPU {
             0xD3 => { self.registers.e = self.set(self.registers.e, 2); 8 }, // SET 2, E
             0xD4 => { self.registers.h = self.set(self.registers.h, 2); 8 }, // SET 2, H
             0xD5 => { self.registers.l = self.set(self.registers.l, 2); 8 }, // SET 2, L
-            0xD6 => { let v = self.memory.read(self.registers.hl()); let v2 = self.set(v, 2); self.memory.write(self.registers.hl(), v2); 16 }, // SET 2, (HL)
+            0xD6 => { let v = self.read_byte(self.registers.hl()); let v2 = self.set(v, 2); self.write_byte(self.registers.hl(), v2); 16 }, // SET 2, (HL)
             0xD7 => { self.registers.a = self.set(self.registers.a, 2); 8 }, // SET 2, A
             0xD8 => { self.registers.b = self.set(self.registers.b, 3); 8 }, // SET 3, B
             0xD9 => { self.registers.c = self.set(self.registers.c, 3); 8 }, // SET 3, C
@@ -566,7 +687,7 @@ impl CPU {
             0xDB => { self.registers.e = self.set(self.registers.e, 3); 8 }, // SET 3, E
             0xDC => { self.registers.h = self.set(self.registers.h, 3); 8 }, // SET 3, H
             0xDD => { self.registers.l = self.set(self.registers.l, 3); 8 }, // SET 3, L
-            0xDE => { let v = self.memory.read(self.registers.hl()); let v2 = self.set(v, 3); self.memory.write(self.registers.hl(), v2); 16 }, // SET 3, (HL)
+            0xDE => { let v = self.read_byte(self.registers.hl()); let v2 = self.set(v, 3); self.write_byte(self.registers.hl(), v2); 16 }, // SET 3, (HL)
             0xDF => { self.registers.a = self.set(self.registers.a, 3); 8 }, // SET 3, A
             0xE0 => { self.registers.b = self.set(self.registers.b, 4); 8 }, // SET 4, B
             0xE1 => { self.registers.c = self.set(self.registers.c, 4); 8 }, // SET 4, C
@@ -574,7 +695,7 @@ impl CPU {
             0xE3 => { self.registers.e = self.set(self.registers.e, 4); 8 }, // SET 4, E
             0xE4 => { self.registers.h = self.set(self.registers.h, 4); 8 }, // SET 4, H
             0xE5 => { self.registers.l = self.set(self.registers.l, 4); 8 }, // SET 4, L
-            0xE6 => { let v = self.memory.read(self.registers.hl()); let v2 = self.set(v, 4); self.memory.write(self.registers.hl(), v2); 16 }, // SET 4, (HL)
+            0xE6 => { let v = self.read_byte(self.registers.hl()); let v2 = self.set(v, 4); self.write_byte(self.registers.hl(), v2); 16 }, // SET 4, (HL)
             0xE7 => { self.registers.a = self.set(self.registers.a, 4); 8 }, // SET 4, A
             0xE8 => { self.registers.b = self.set(self.registers.b, 5); 8 }, // SET 5, B
             0xE9 => { self.registers.c = self.set(self.registers.c, 5); 8 }, // SET 5, C
@@ -582,7 +703,7 @@ impl CPU {
             0xEB => { self.registers.e = self.set(self.registers.e, 5); 8 }, // SET 5, E
             0xEC => { self.registers.h = self.set(self.registers.h, 5); 8 }, // SET 5, H
             0xED => { self.registers.l = self.set(self.registers.l, 5); 8 }, // SET 5, L
-            0xEE => { let v = self.memory.read(self.registers.hl()); let v2 = self.set(v, 5); self.memory.write(self.registers.hl(), v2); 16 }, // SET 5, (HL)
+            0xEE => { let v = self.read_byte(self.registers.hl()); let v2 = self.set(v, 5); self.write_byte(self.registers.hl(), v2); 16 }, // SET 5, (HL)
             0xEF => { self.registers.a = self.set(self.registers.a, 5); 8 }, // SET 5, A
             0xF0 => { self.registers.b = self.set(self.registers.b, 6); 8 }, // SET 6, B
             0xF1 => { self.registers.c = self.set(self.registers.c, 6); 8 }, // SET 6, C
@@ -590,7 +711,7 @@ impl CPU {
             0xF3 => { self.registers.e = self.set(self.registers.e, 6); 8 }, // SET 6, E
             0xF4 => { self.registers.h = self.set(self.registers.h, 6); 8 }, // SET 6, H
             0xF5 => { self.registers.l = self.set(self.registers.l, 6); 8 }, // SET 6, L
-            0xF6 => { let v = self.memory.read(self.registers.hl()); let v2 = self.set(v, 6); self.memory.write(self.registers.hl(), v2); 16 }, // SET 6, (HL)
+            0xF6 => { let v = self.read_byte(self.registers.hl()); let v2 = self.set(v, 6); self.write_byte(self.registers.hl(), v2); 16 }, // SET 6, (HL)
             0xF7 => { self.registers.a = self.set(self.registers.a, 6); 8 }, // SET 6, A
             0xF8 => { self.registers.b = self.set(self.registers.b, 7); 8 }, // SET 7, B
             0xF9 => { self.registers.c = self.set(self.registers.c, 7); 8 }, // SET 7, C
@@ -598,7 +719,7 @@ impl CPU {
             0xFB => { self.registers.e = self.set(self.registers.e, 7); 8 }, // SET 7, E
             0xFC => { self.registers.h = self.set(self.registers.h, 7); 8 }, // SET 7, H
             0xFD => { self.registers.l = self.set(self.registers.l, 7); 8 }, // SET 7, L
-            0xFE => { let v = self.memory.read(self.registers.hl()); let v2 = self.set(v, 7); self.memory.write(self.registers.hl(), v2); 16 }, // SET 7, (HL)
+            0xFE => { let v = self.read_byte(self.registers.hl()); let v2 = self.set(v, 7); self.write_byte(self.registers.hl(), v2); 16 }, // SET 7, (HL)
             0xFF => { self.registers.a = self.set(self.registers.a, 7); 8 }, // SET 7, A
         }
     }
@@ -751,7 +872,7 @@ impl CPU {
 
     #[inline(always)]
     fn swap(&mut self, value: u8) -> u8 {
-        let result = (value >> 4) | (value << 4);
+        let result = value.rotate_left(4);
         self.registers.set_flag(Flag::Zero, result == 0);
         self.registers.set_flag(Flag::Sub, false);
         self.registers.set_flag(Flag::HalfCarry, false);
@@ -807,17 +928,43 @@ impl CPU {
         value | (1 << bit)
     }
 
+    /// The high byte is pushed first, after an internal M-cycle
     #[inline(always)]
     fn push_stack(&mut self, value: u16) {
-        self.registers.sp = self.registers.sp.wrapping_sub(2);
-        self.memory.write_word(self.registers.sp, value);
+        self.tick();
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write_byte(self.registers.sp, (value >> 8) as u8);
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write_byte(self.registers.sp, value as u8);
     }
 
     #[inline(always)]
     fn pop_stack(&mut self) -> u16 {
-        let value = self.memory.read_word(self.registers.sp);
-        self.registers.sp = self.registers.sp.wrapping_add(2);
-        value
+        let low = self.read_byte(self.registers.sp) as u16;
+        self.registers.sp = self.registers.sp.wrapping_add(1);
+        let high = self.read_byte(self.registers.sp) as u16;
+        self.registers.sp = self.registers.sp.wrapping_add(1);
+        high << 8 | low
+    }
+
+    /// The address is read before PC is pushed
+    #[inline(always)]
+    fn call_a16(&mut self) {
+        let address = self.fetch_word();
+        self.push_stack(self.registers.pc);
+        self.registers.pc = address;
+    }
+
+    /// SP + r8, flags are computed on the low byte as an unsigned addition
+    #[inline(always)]
+    fn add_sp_r8(&mut self) -> u16 {
+        let sp = self.registers.sp;
+        let value = self.fetch_byte() as i8 as i16 as u16;
+        self.registers.set_flag(Flag::Zero, false);
+        self.registers.set_flag(Flag::Sub, false);
+        self.registers.set_flag(Flag::HalfCarry, (sp & 0x0F) + (value & 0x0F) > 0x0F);
+        self.registers.set_flag(Flag::Carry, (sp & 0xFF) + (value & 0xFF) > 0xFF);
+        sp.wrapping_add(value)
     }
 
     #[inline(always)]
@@ -838,3 +985,184 @@ impl CPU {
 
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc;
+
+    /// CPU running the given program from WRAM
+    fn cpu(program: &[u8]) -> CPU {
+        let mut cpu = CPU::new(mbc::from_rom(&[0; 0x8000]).unwrap());
+        for (i, byte) in program.iter().enumerate() {
+            cpu.memory.write(0xC000 + i as u16, *byte);
+        }
+        cpu.registers.pc = 0xC000;
+        cpu
+    }
+
+    #[test]
+    fn test_disasm_length() {
+        // The disassembler has its own opcode table, each length must match the PC advance
+        for opcode in 0..=0xFFu8 {
+            let illegal = [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD].contains(&opcode);
+            let jump = [0xC3, 0xC9, 0xCD, 0xD9, 0xE9].contains(&opcode) || opcode & 0xC7 == 0xC7;
+            if illegal || jump {
+                continue;
+            }
+            let mut cpu = cpu(&[opcode, 0x00, 0x00]);
+            // Conditions not met for JR, JP, CALL and RET cc: Z set for NZ, C set for NC
+            let conditional = matches!(opcode, 0x20 | 0x28 | 0x30 | 0x38) || matches!(opcode & 0xE7, 0xC0 | 0xC2 | 0xC4);
+            if conditional {
+                cpu.registers.f = [0x80, 0x00, 0x10, 0x00][(opcode >> 3 & 0x3) as usize];
+            }
+            cpu.step();
+            let length = cpu.registers.pc.wrapping_sub(0xC000) as usize;
+            assert_eq!(length, crate::disasm::length(opcode), "opcode {:#04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_ei_delay() {
+        let mut cpu = cpu(&[0xFB, 0x00, 0x00]); // EI, NOP, NOP
+        cpu.memory.interrupt_enable = 0x01;
+        cpu.memory.interrupt_flags = 0x01;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0xC002);
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(cpu.registers.pc, 0x0040);
+        assert_eq!(cpu.memory.interrupt_flags, 0x00);
+    }
+
+    #[test]
+    fn test_di_cancels_ei() {
+        let mut cpu = cpu(&[0xFB, 0xF3, 0x00]); // EI, DI, NOP
+        cpu.memory.interrupt_enable = 0x01;
+        cpu.memory.interrupt_flags = 0x01;
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0xC003);
+    }
+
+    #[test]
+    fn test_interrupt_priority() {
+        let mut cpu = cpu(&[0x00]);
+        cpu.ime = true;
+        cpu.memory.interrupt_enable = 0x1F;
+        cpu.memory.interrupt_flags = 0x14;
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0x0050);
+        assert_eq!(cpu.memory.interrupt_flags, 0x10);
+    }
+
+    #[test]
+    fn test_unwired_interrupt_bits() {
+        let mut cpu = cpu(&[0x00]);
+        cpu.ime = true;
+        cpu.memory.interrupt_enable = 0xFF;
+        cpu.memory.interrupt_flags = 0xE0;
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0xC001);
+    }
+
+    #[test]
+    fn test_halt_wakes_without_ime() {
+        let mut cpu = cpu(&[0x76, 0x00]); // HALT, NOP
+        cpu.memory.interrupt_enable = 0x04;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0xC001);
+        cpu.memory.interrupt_flags = 0x04;
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0xC002);
+        assert_eq!(cpu.memory.interrupt_flags, 0x04);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        let mut cpu = cpu(&[0x76, 0x3C]); // HALT, INC A
+        cpu.registers.a = 0;
+        cpu.memory.interrupt_enable = 0x01;
+        cpu.memory.interrupt_flags = 0x01;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0xC001);
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0xC002);
+        assert_eq!(cpu.registers.a, 2);
+    }
+
+    #[test]
+    fn test_ei_halt_bug() {
+        let mut cpu = cpu(&[0xFB, 0x76, 0x00]); // EI, HALT, NOP
+        cpu.memory.poke(0x0040, 0x3C);           // INC A
+        cpu.registers.a = 0;
+        cpu.registers.sp = 0xD000;
+        cpu.memory.interrupt_enable = 0x01;
+        cpu.memory.interrupt_flags = 0x01;
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0x0040);
+        assert_eq!(cpu.memory.read_word(0xCFFE), 0xC001);
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0x0041);
+        assert_eq!(cpu.registers.a, 1);
+    }
+
+    #[test]
+    fn test_call_ret() {
+        let mut cpu = cpu(&[0xCD, 0x10, 0xC0]); // CALL 0xC010
+        cpu.memory.write(0xC010, 0xC9);         // RET
+        cpu.registers.sp = 0xD000;
+        assert_eq!(cpu.step(), 24);
+        assert_eq!(cpu.registers.pc, 0xC010);
+        assert_eq!(cpu.memory.read_word(0xCFFE), 0xC003);
+        assert_eq!(cpu.step(), 16);
+        assert_eq!(cpu.registers.pc, 0xC003);
+        assert_eq!(cpu.registers.sp, 0xD000);
+    }
+
+    #[test]
+    fn test_add_sp_r8() {
+        let mut cpu = cpu(&[0xE8, 0xFF, 0xF8, 0x01]); // ADD SP, -1 / LD HL, SP+1
+        cpu.registers.sp = 0x00FF;
+        cpu.step();
+        assert_eq!(cpu.registers.sp, 0x00FE);
+        assert!(cpu.registers.get_flag(Flag::HalfCarry));
+        assert!(cpu.registers.get_flag(Flag::Carry));
+        cpu.step();
+        assert_eq!(cpu.registers.hl(), 0x00FF);
+        assert!(!cpu.registers.get_flag(Flag::Carry));
+    }
+
+    /// TIMA is read on the 3rd M-cycle of LDH, after it was incremented
+    #[test]
+    fn test_cycle_accurate_read() {
+        for (cycle_accurate, expected) in [(false, 0), (true, 1)] {
+            let mut cpu = cpu(&[0x00, 0xF0, 0x05]); // NOP, LDH A, (0x05)
+            cpu.cycle_accurate = cycle_accurate;
+            cpu.memory.write(0xFF04, 0);
+            cpu.memory.write(0xFF07, 0b101);
+            assert_eq!(cpu.step(), 4);
+            assert_eq!(cpu.step(), 12);
+            assert_eq!(cpu.registers.a, expected);
+        }
+    }
+
+    #[test]
+    fn test_ie_push_cancels_dispatch() {
+        let mut cpu = cpu(&[0x00]);
+        cpu.ime = true;
+        cpu.registers.sp = 0x0000;
+        cpu.memory.interrupt_enable = 0x01;
+        cpu.memory.interrupt_flags = 0x01;
+        cpu.step();
+        assert_eq!(cpu.registers.pc, 0x0000);
+        assert_eq!(cpu.memory.interrupt_enable, 0xC0);
+        assert_eq!(cpu.memory.interrupt_flags, 0x01);
+    }
+}
@@ -0,0 +1,656 @@
+//! Debugger: breakpoints, watchpoints and stepping, usable from code or from a REPL
+//!
+//! ```
+//! use rusty_boy::debugger::{Breakpoint, Debugger, StopReason};
+//! use rusty_boy::gameboy::GameboyBuilder;
+//!
+//! let rom = vec![0; 0x8000];
+//! let gameboy = GameboyBuilder::new(rom).build().unwrap();
+//! let mut debugger = Debugger::new(gameboy);
+//! debugger.add_breakpoint(Breakpoint::new(0x0105));
+//! assert_eq!(debugger.resume(Some(1000)), StopReason::Breakpoint(0));
+//! ```
+
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use crate::disasm::Symbols;
+use crate::gameboy::Gameboy;
+use crate::registers::{Flag, Registers};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    A, F, B, C, D, E, H, L,
+    AF, BC, DE, HL, SP, PC,
+}
+
+impl Register {
+    pub fn value(self, registers: &Registers) -> u16 {
+        match self {
+            Register::A => registers.a as u16,
+            Register::F => registers.f as u16,
+            Register::B => registers.b as u16,
+            Register::C => registers.c as u16,
+            Register::D => registers.d as u16,
+            Register::E => registers.e as u16,
+            Register::H => registers.h as u16,
+            Register::L => registers.l as u16,
+            Register::AF => registers.af(),
+            Register::BC => registers.bc(),
+            Register::DE => registers.de(),
+            Register::HL => registers.hl(),
+            Register::SP => registers.sp,
+            Register::PC => registers.pc,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Register> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "a" => Register::A,
+            "f" => Register::F,
+            "b" => Register::B,
+            "c" => Register::C,
+            "d" => Register::D,
+            "e" => Register::E,
+            "h" => Register::H,
+            "l" => Register::L,
+            "af" => Register::AF,
+            "bc" => Register::BC,
+            "de" => Register::DE,
+            "hl" => Register::HL,
+            "sp" => Register::SP,
+            "pc" => Register::PC,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Eq, Ne, Lt, Le, Gt, Ge,
+}
+
+impl Comparison {
+    const ALL: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+    ];
+
+    fn symbol(self) -> &'static str {
+        Comparison::ALL.iter().find(|(_, c)| *c == self).unwrap().0
+    }
+}
+
+/// Register comparison, e.g. `a == 0x10` or `hl >= c000`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Option<Condition> {
+        let (symbol, comparison) = Comparison::ALL.iter().find(|(symbol, _)| text.contains(symbol))?;
+        let (register, value) = text.split_once(symbol)?;
+        Some(Condition {
+            register: Register::parse(register.trim())?,
+            comparison: *comparison,
+            value: parse_number(value.trim())?,
+        })
+    }
+
+    pub fn matches(&self, registers: &Registers) -> bool {
+        let value = self.register.value(registers);
+        match self.comparison {
+            Comparison::Eq => value == self.value,
+            Comparison::Ne => value != self.value,
+            Comparison::Lt => value < self.value,
+            Comparison::Le => value <= self.value,
+            Comparison::Gt => value > self.value,
+            Comparison::Ge => value >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} {} {:#06x}", self.register, self.comparison.symbol(), self.value)
+    }
+}
+
+/// Stop before executing the instruction at `address`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub address: u16,
+    /// ROM bank mapped at the address, any bank when `None` (only checked for 0x0000-0x7FFF)
+    pub bank: Option<usize>,
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Breakpoint {
+        Breakpoint { address, bank: None, condition: None }
+    }
+
+    pub fn in_bank(mut self, bank: usize) -> Breakpoint {
+        self.bank = Some(bank);
+        self
+    }
+
+    pub fn when(mut self, condition: Condition) -> Breakpoint {
+        self.condition = Some(condition);
+        self
+    }
+
+    fn matches(&self, gameboy: &Gameboy) -> bool {
+        let registers = &gameboy.cpu.registers;
+        let pc = registers.pc;
+        pc == self.address
+            && self.bank.is_none_or(|bank| pc >= 0x8000 || gameboy.cpu.memory.mbc.rom_bank(pc) == bank)
+            && self.condition.is_none_or(|condition| condition.matches(registers))
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02x}:{:04x}", bank, self.address)?,
+            None => write!(f, "{:04x}", self.address)?,
+        }
+        if let Some(condition) = self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// Stop when the CPU reads or writes in `start..=end`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, address: u16, write: bool) -> bool {
+        (self.start..=self.end).contains(&address) && if write { self.write } else { self.read }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{:04x}", self.start)?;
+        } else {
+            write!(f, "{:04x}-{:04x}", self.start, self.end)?;
+        }
+        let kind = match (self.read, self.write) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            _ => "w",
+        };
+        write!(f, " {}", kind)
+    }
+}
+
+/// Access that triggered a watchpoint
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    /// The requested step is complete
+    Step,
+    /// Index of the breakpoint in `breakpoints()`
+    Breakpoint(usize),
+    /// Stopped after the instruction doing the access
+    Watchpoint(WatchHit),
+    Frame,
+    /// The cycle limit given to `resume` was reached
+    Limit,
+}
+
+const RET_OPCODES: [u8; 6] = [0xC9, 0xD9, 0xC0, 0xC8, 0xD0, 0xD8];
+
+pub struct Debugger {
+    gameboy: Gameboy,
+    breakpoints: Vec<Breakpoint>,
+    symbols: Symbols,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new(gameboy: Gameboy) -> Debugger {
+        Debugger { gameboy, breakpoints: Vec::new(), symbols: Symbols::new(), last_command: String::new() }
+    }
+
+    pub fn gameboy(&self) -> &Gameboy {
+        &self.gameboy
+    }
+
+    pub fn gameboy_mut(&mut self) -> &mut Gameboy {
+        &mut self.gameboy
+    }
+
+    /// Give the Gameboy back, without the watchpoints
+    pub fn into_inner(mut self) -> Gameboy {
+        self.gameboy.cpu.memory.watchpoints.clear();
+        self.gameboy
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.gameboy.cpu.registers
+    }
+
+    /// Labels shown in the disassembly and accepted in place of addresses by the commands
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /// `count` instructions from `address`, one per line with the labels
+    pub fn disassemble(&self, address: u16, count: usize) -> String {
+        let mut address = address;
+        let mut lines = Vec::with_capacity(count);
+        for _ in 0..count {
+            let instruction = self.gameboy.disassemble(address);
+            let bytes: Vec<String> = instruction.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let bank = if address < 0x8000 { Some(instruction.bank) } else { None };
+            if let Some(label) = self.symbols.get(bank, address) {
+                lines.push(format!("{}:", label));
+            }
+            lines.push(format!(
+                "  {:02x}:{:04x}  {:<9} {}",
+                instruction.bank, address, bytes.join(" "), instruction.format(Some(&self.symbols))
+            ));
+            address = instruction.next();
+        }
+        lines.join("\n")
+    }
+
+    /// Returns the index of the breakpoint
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        self.breakpoints.push(breakpoint);
+        self.breakpoints.len() - 1
+    }
+
+    pub fn remove_breakpoint(&mut self, index: usize) -> Option<Breakpoint> {
+        (index < self.breakpoints.len()).then(|| self.breakpoints.remove(index))
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        let watchpoints = &mut self.gameboy.cpu.memory.watchpoints;
+        watchpoints.push(watchpoint);
+        watchpoints.len() - 1
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        let watchpoints = &mut self.gameboy.cpu.memory.watchpoints;
+        (index < watchpoints.len()).then(|| watchpoints.remove(index))
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.gameboy.cpu.memory.watchpoints
+    }
+
+    /// Execute one instruction, entering calls
+    pub fn step(&mut self) -> StopReason {
+        self.run_while(None, |_, _| true)
+    }
+
+    /// Execute one instruction, a CALL or a RST is run until it returns
+    pub fn step_over(&mut self) -> StopReason {
+        let registers = &self.gameboy.cpu.registers;
+        let (pc, sp) = (registers.pc, registers.sp);
+        let length = match self.opcode() {
+            0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC => 3,
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => 1,
+            _ => return self.step(),
+        };
+        let target = pc.wrapping_add(length);
+        self.run_while(None, |gameboy, _| {
+            let registers = &gameboy.cpu.registers;
+            registers.pc == target && registers.sp >= sp
+        })
+    }
+
+    /// Run until the current function returns
+    pub fn step_out(&mut self) -> StopReason {
+        let sp = self.gameboy.cpu.registers.sp;
+        self.run_while(None, |gameboy, opcode| RET_OPCODES.contains(&opcode) && gameboy.cpu.registers.sp > sp)
+    }
+
+    /// Run until a breakpoint or a watchpoint is hit, or `max_cycles` have been executed
+    pub fn resume(&mut self, max_cycles: Option<u64>) -> StopReason {
+        self.run_while(max_cycles, |_, _| false)
+    }
+
+    /// Run until the PPU completes a frame
+    pub fn run_to_frame(&mut self) -> StopReason {
+        self.gameboy.cpu.memory.gpu.frame_ready = false;
+        match self.run_while(None, |gameboy, _| gameboy.cpu.memory.gpu.frame_ready) {
+            StopReason::Step => {
+                self.gameboy.end_frame();
+                StopReason::Frame
+            }
+            reason => reason,
+        }
+    }
+
+    fn opcode(&self) -> u8 {
+        self.gameboy.peek(self.gameboy.cpu.registers.pc)
+    }
+
+    /// Execute instructions until `done` returns true, it is given the opcode just executed.
+    /// The breakpoints are checked after each instruction, so resuming from one doesn't stop right away.
+    fn run_while<F>(&mut self, max_cycles: Option<u64>, mut done: F) -> StopReason
+    where
+        F: FnMut(&Gameboy, u8) -> bool,
+    {
+        let mut cycles = 0;
+        let reason = loop {
+            let opcode = self.opcode();
+            cycles += self.gameboy.step() as u64;
+
+            if let Some(hit) = self.gameboy.cpu.memory.watch_hit.take() {
+                break StopReason::Watchpoint(hit);
+            }
+            if done(&self.gameboy, opcode) {
+                break StopReason::Step;
+            }
+            if let Some(index) = self.breakpoints.iter().position(|b| b.matches(&self.gameboy)) {
+                break StopReason::Breakpoint(index);
+            }
+            if max_cycles.is_some_and(|max| cycles >= max) {
+                break StopReason::Limit;
+            }
+        };
+        self.gameboy.cpu.memory.sync();
+        reason
+    }
+
+    /// Registers on one line: `AF=01b0 BC=0013 DE=00d8 HL=014d SP=fffe PC=0100 Z-HC`
+    pub fn format_registers(&self) -> String {
+        let r = &self.gameboy.cpu.registers;
+        let flag = |flag, c| if r.get_flag(flag) { c } else { '-' };
+        format!(
+            "AF={:04x} BC={:04x} DE={:04x} HL={:04x} SP={:04x} PC={:04x} {}{}{}{}",
+            r.af(), r.bc(), r.de(), r.hl(), r.sp, r.pc,
+            flag(Flag::Zero, 'Z'), flag(Flag::Sub, 'N'), flag(Flag::HalfCarry, 'H'), flag(Flag::Carry, 'C'),
+        )
+    }
+
+    fn describe(&mut self, reason: StopReason) -> String {
+        let reason = match reason {
+            StopReason::Step => String::new(),
+            StopReason::Breakpoint(index) => format!("Breakpoint {} ({})\n", index, self.breakpoints[index]),
+            StopReason::Watchpoint(hit) => format!(
+                "Watchpoint: {} {:04x} = {:02x}\n",
+                if hit.write { "write" } else { "read" }, hit.address, hit.value
+            ),
+            StopReason::Frame => "Frame complete\n".to_string(),
+            StopReason::Limit => "Cycle limit reached\n".to_string(),
+        };
+        let pc = self.gameboy.cpu.registers.pc;
+        format!("{}{}\n{}", reason, self.format_registers(), self.disassemble(pc, 1))
+    }
+
+    /// Run a REPL command and return its output. An empty line repeats the last command.
+    pub fn execute(&mut self, line: &str) -> String {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+        match self.command(command, &args) {
+            Ok(output) => output,
+            Err(e) => format!("error: {}", e),
+        }
+    }
+
+    fn command(&mut self, command: &str, args: &[&str]) -> Result<String, String> {
+        let number = |arg: Option<&&str>| -> Result<u16, String> {
+            let arg = arg.ok_or("missing argument")?;
+            parse_number(arg).ok_or_else(|| format!("invalid number '{}'", arg))
+        };
+        // Number or label
+        let address = |arg: Option<&&str>| -> Result<u16, String> {
+            match arg.and_then(|arg| self.symbols.find(arg)) {
+                Some((_, address)) => Ok(address),
+                None => number(arg),
+            }
+        };
+
+        Ok(match command {
+            "s" | "step" => {
+                let count = if args.is_empty() { 1 } else { number(args.first())? };
+                let mut reason = StopReason::Step;
+                for _ in 0..count {
+                    reason = self.step();
+                    if reason != StopReason::Step {
+                        break;
+                    }
+                }
+                self.describe(reason)
+            }
+            "n" | "next" => {
+                let reason = self.step_over();
+                self.describe(reason)
+            }
+            "out" | "finish" => {
+                let reason = self.step_out();
+                self.describe(reason)
+            }
+            "c" | "continue" => {
+                let reason = self.resume(None);
+                self.describe(reason)
+            }
+            "f" | "frame" => {
+                let reason = self.run_to_frame();
+                self.describe(reason)
+            }
+            "r" | "regs" => self.format_registers(),
+            "b" | "break" => {
+                let location = args.first().ok_or("missing address")?;
+                let mut breakpoint = match (location.split_once(':'), self.symbols.find(location)) {
+                    (_, Some((bank, address))) if (0x4000..0x8000).contains(&address) => Breakpoint::new(address).in_bank(bank),
+                    (_, Some((_, address))) => Breakpoint::new(address),
+                    (Some((bank, address)), None) => Breakpoint::new(number(Some(&address))?).in_bank(number(Some(&bank))? as usize),
+                    (None, None) => Breakpoint::new(number(Some(location))?),
+                };
+                match args.get(1) {
+                    Some(&"if") => {
+                        let condition = args[2..].join(" ");
+                        breakpoint.condition = Some(Condition::parse(&condition).ok_or_else(|| format!("invalid condition '{}'", condition))?);
+                    }
+                    Some(arg) => return Err(format!("unexpected '{}'", arg)),
+                    None => {}
+                }
+                let index = self.add_breakpoint(breakpoint);
+                format!("Breakpoint {}: {}", index, breakpoint)
+            }
+            "w" | "watch" => {
+                let range = args.first().ok_or("missing address")?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (address(Some(&start))?, address(Some(&end))?),
+                    None => (address(Some(range))?, address(Some(range))?),
+                };
+                let (read, write) = match args.get(1).copied().unwrap_or("w") {
+                    "r" => (true, false),
+                    "w" => (false, true),
+                    "rw" => (true, true),
+                    kind => return Err(format!("unknown access '{}', use r, w or rw", kind)),
+                };
+                let watchpoint = Watchpoint { start, end, read, write };
+                let index = self.add_watchpoint(watchpoint);
+                format!("Watchpoint {}: {}", index, watchpoint)
+            }
+            "d" | "delete" => {
+                let index = number(args.first())? as usize;
+                let breakpoint = self.remove_breakpoint(index).ok_or("no such breakpoint")?;
+                format!("Deleted breakpoint {}: {}", index, breakpoint)
+            }
+            "dw" => {
+                let index = number(args.first())? as usize;
+                let watchpoint = self.remove_watchpoint(index).ok_or("no such watchpoint")?;
+                format!("Deleted watchpoint {}: {}", index, watchpoint)
+            }
+            "l" | "list" => {
+                let mut output = Vec::new();
+                output.extend(self.breakpoints.iter().enumerate().map(|(i, b)| format!("Breakpoint {}: {}", i, b)));
+                output.extend(self.watchpoints().iter().enumerate().map(|(i, w)| format!("Watchpoint {}: {}", i, w)));
+                output.join("\n")
+            }
+            "x" => {
+                let start = address(args.first())?;
+                let length = if args.len() > 1 { number(args.get(1))? } else { 0x40 };
+                let mut output = Vec::new();
+                for line in (0..length).step_by(16) {
+                    let address = start.wrapping_add(line);
+                    let bytes: Vec<String> = (0..16.min(length - line))
+                        .map(|i| format!("{:02x}", self.gameboy.peek(address.wrapping_add(i))))
+                        .collect();
+                    output.push(format!("{:04x}: {}", address, bytes.join(" ")));
+                }
+                output.join("\n")
+            }
+            "u" | "disas" => {
+                let start = if args.is_empty() { self.gameboy.cpu.registers.pc } else { address(args.first())? };
+                let count = if args.len() > 1 { number(args.get(1))? } else { 10 };
+                self.disassemble(start, count as usize)
+            }
+            "h" | "help" => HELP.to_string(),
+            _ => return Err(format!("unknown command '{}', try 'help'", command)),
+        })
+    }
+
+    /// Read commands from `input` until `q` or the end of the input
+    pub fn repl<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        writeln!(output, "{}", self.format_registers())?;
+        loop {
+            write!(output, "(rb) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 || matches!(line.trim(), "q" | "quit") {
+                return Ok(());
+            }
+            writeln!(output, "{}", self.execute(&line))?;
+        }
+    }
+}
+
+const HELP: &str = "Numbers are hexadecimal, labels can be used as addresses, an empty line repeats the last command
+  s, step [N]               Execute N instructions (1)
+  n, next                   Step over calls
+  out, finish               Run until the current function returns
+  c, continue               Run until a breakpoint or a watchpoint
+  f, frame                  Run until the end of the frame
+  r, regs                   Show the registers
+  b, break [BANK:]ADDR [if REG OP VALUE]
+                            Add a breakpoint, e.g. 'b 03:4a2f if a == 10'
+  w, watch ADDR[-END] [r|w|rw]
+                            Add a watchpoint on reads and/or writes (w)
+  d, delete N / dw N        Delete breakpoint / watchpoint N
+  l, list                   List the breakpoints and watchpoints
+  x ADDR [LEN]              Dump memory
+  u, disas [ADDR] [N]       Disassemble N instructions (10) from ADDR (PC)
+  q, quit                   Leave the debugger";
+
+/// Hexadecimal number with an optional `0x` or `$` prefix
+fn parse_number(text: &str) -> Option<u16> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix('$')).unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::gameboy::test_builder;
+
+    /// Program at 0x0100: JP 0x0150 / at 0x0150: CALL 0x0200, INC A, JR -3 / at 0x0200: LD (0xC000),A, RET
+    pub(crate) fn debugger() -> Debugger {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x150..0x156].copy_from_slice(&[0xCD, 0x00, 0x02, 0x3C, 0x18, 0xFA]);
+        rom[0x200..0x204].copy_from_slice(&[0xEA, 0x00, 0xC0, 0xC9]);
+        Debugger::new(test_builder(rom).build().unwrap())
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut debugger = debugger();
+        debugger.step();
+        assert_eq!(debugger.step(), StopReason::Step);
+        assert_eq!(debugger.registers().pc, 0x0200);
+        assert_eq!(debugger.step_out(), StopReason::Step);
+        assert_eq!(debugger.registers().pc, 0x0153);
+
+        debugger.step();
+        debugger.step();
+        assert_eq!(debugger.registers().pc, 0x0150);
+        assert_eq!(debugger.step_over(), StopReason::Step);
+        assert_eq!(debugger.registers().pc, 0x0153);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = debugger();
+        debugger.add_breakpoint(Breakpoint::new(0x0153).when(Condition::parse("a == 3").unwrap()));
+        assert_eq!(debugger.resume(None), StopReason::Breakpoint(0));
+        assert_eq!(debugger.registers().a, 3);
+
+        // Wrong bank: never hit
+        debugger.remove_breakpoint(0);
+        debugger.add_breakpoint(Breakpoint::new(0x0153).in_bank(1));
+        assert_eq!(debugger.resume(Some(10_000)), StopReason::Limit);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = debugger();
+        debugger.add_watchpoint(Watchpoint { start: 0xC000, end: 0xC000, read: false, write: true });
+        let hit = WatchHit { address: 0xC000, value: 0x01, write: true };
+        assert_eq!(debugger.resume(None), StopReason::Watchpoint(hit));
+        assert_eq!(debugger.registers().pc, 0x0203);
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.execute("b 0:0200"), "Breakpoint 0: 00:0200");
+        assert!(debugger.execute("c").starts_with("Breakpoint 0 (00:0200)\nAF="));
+        assert!(debugger.execute("x 0200 4").starts_with("0200: ea 00 c0 c9"));
+        assert_eq!(debugger.execute("b zz"), "error: invalid number 'zz'");
+
+        debugger.set_symbols(Symbols::parse("00:0150 Main\n00:0200 Store\n"));
+        assert_eq!(debugger.execute("u Main 1"), "Main:\n  00:0150  cd 00 02  CALL Store");
+        assert_eq!(debugger.execute("b Store"), "Breakpoint 1: 0200");
+
+        let mut output = Vec::new();
+        debugger.repl(&b"s\n\nq\n"[..], &mut output).unwrap();
+        assert_eq!(debugger.registers().pc, 0x0153);
+    }
+}
@@ -0,0 +1,310 @@
+//! SM83 disassembler, with the labels of RGBDS `.sym` files
+//!
+//! ```
+//! use rusty_boy::disasm::{decode, Symbols};
+//!
+//! let symbols = Symbols::parse("00:0150 Main\n00:0153 Main.loop\n");
+//! let instruction = decode(&[0x18, 0xFE], 0x0153, 0);
+//! assert_eq!(instruction.to_string(), "JR $0153");
+//! assert_eq!(instruction.format(Some(&symbols)), "JR Main.loop");
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// Mnemonics of the opcodes, in the notation of the comments of `CPU::call`.
+/// The operand placeholders are d8/d16 (immediate), a8/a16 (address) and r8 (signed offset).
+const OPCODES: [&str; 256] = [
+    "NOP", "LD BC, d16", "LD (BC), A", "INC BC",
+    "INC B", "DEC B", "LD B, d8", "RLCA",
+    "LD (a16), SP", "ADD HL, BC", "LD A, (BC)", "DEC BC",
+    "INC C", "DEC C", "LD C, d8", "RRCA",
+    "STOP d8", "LD DE, d16", "LD (DE), A", "INC DE",
+    "INC D", "DEC D", "LD D, d8", "RLA",
+    "JR r8", "ADD HL, DE", "LD A, (DE)", "DEC DE",
+    "INC E", "DEC E", "LD E, d8", "RRA",
+    "JR NZ, r8", "LD HL, d16", "LD (HL+), A", "INC HL",
+    "INC H", "DEC H", "LD H, d8", "DAA",
+    "JR Z, r8", "ADD HL, HL", "LD A, (HL+)", "DEC HL",
+    "INC L", "DEC L", "LD L, d8", "CPL",
+    "JR NC, r8", "LD SP, d16", "LD (HL-), A", "INC SP",
+    "INC (HL)", "DEC (HL)", "LD (HL), d8", "SCF",
+    "JR C, r8", "ADD HL, SP", "LD A, (HL-)", "DEC SP",
+    "INC A", "DEC A", "LD A, d8", "CCF",
+    "LD B, B", "LD B, C", "LD B, D", "LD B, E",
+    "LD B, H", "LD B, L", "LD B, (HL)", "LD B, A",
+    "LD C, B", "LD C, C", "LD C, D", "LD C, E",
+    "LD C, H", "LD C, L", "LD C, (HL)", "LD C, A",
+    "LD D, B", "LD D, C", "LD D, D", "LD D, E",
+    "LD D, H", "LD D, L", "LD D, (HL)", "LD D, A",
+    "LD E, B", "LD E, C", "LD E, D", "LD E, E",
+    "LD E, H", "LD E, L", "LD E, (HL)", "LD E, A",
+    "LD H, B", "LD H, C", "LD H, D", "LD H, E",
+    "LD H, H", "LD H, L", "LD H, (HL)", "LD H, A",
+    "LD L, B", "LD L, C", "LD L, D", "LD L, E",
+    "LD L, H", "LD L, L", "LD L, (HL)", "LD L, A",
+    "LD (HL), B", "LD (HL), C", "LD (HL), D", "LD (HL), E",
+    "LD (HL), H", "LD (HL), L", "HALT", "LD (HL), A",
+    "LD A, B", "LD A, C", "LD A, D", "LD A, E",
+    "LD A, H", "LD A, L", "LD A, (HL)", "LD A, A",
+    "ADD A, B", "ADD A, C", "ADD A, D", "ADD A, E",
+    "ADD A, H", "ADD A, L", "ADD A, (HL)", "ADD A, A",
+    "ADC A, B", "ADC A, C", "ADC A, D", "ADC A, E",
+    "ADC A, H", "ADC A, L", "ADC A, (HL)", "ADC A, A",
+    "SUB B", "SUB C", "SUB D", "SUB E",
+    "SUB H", "SUB L", "SUB (HL)", "SUB A",
+    "SBC A, B", "SBC A, C", "SBC A, D", "SBC A, E",
+    "SBC A, H", "SBC A, L", "SBC A, (HL)", "SBC A, A",
+    "AND B", "AND C", "AND D", "AND E",
+    "AND H", "AND L", "AND (HL)", "AND A",
+    "XOR B", "XOR C", "XOR D", "XOR E",
+    "XOR H", "XOR L", "XOR (HL)", "XOR A",
+    "OR B", "OR C", "OR D", "OR E",
+    "OR H", "OR L", "OR (HL)", "OR A",
+    "CP B", "CP C", "CP D", "CP E",
+    "CP H", "CP L", "CP (HL)", "CP A",
+    "RET NZ", "POP BC", "JP NZ, a16", "JP a16",
+    "CALL NZ, a16", "PUSH BC", "ADD A, d8", "RST 00H",
+    "RET Z", "RET", "JP Z, a16", "PREFIX CB",
+    "CALL Z, a16", "CALL a16", "ADC A, d8", "RST 08H",
+    "RET NC", "POP DE", "JP NC, a16", "",
+    "CALL NC, a16", "PUSH DE", "SUB d8", "RST 10H",
+    "RET C", "RETI", "JP C, a16", "",
+    "CALL C, a16", "", "SBC A, d8", "RST 18H",
+    "LDH (a8), A", "POP HL", "LD (C), A", "",
+    "", "PUSH HL", "AND d8", "RST 20H",
+    "ADD SP, r8", "JP (HL)", "LD (a16), A", "",
+    "", "", "XOR d8", "RST 28H",
+    "LDH A, (a8)", "POP AF", "LD A, (C)", "DI",
+    "", "PUSH AF", "OR d8", "RST 30H",
+    "LD HL, SP+r8", "LD SP, HL", "LD A, (a16)", "EI",
+    "", "", "CP d8", "RST 38H",
+];
+
+const CB_OPERATIONS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const CB_REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+
+/// Length in bytes of the instruction starting with `opcode`
+pub fn length(opcode: u8) -> usize {
+    let mnemonic = OPCODES[opcode as usize];
+    if opcode == 0xCB || ["d8", "a8", "r8"].iter().any(|p| mnemonic.contains(p)) {
+        2
+    } else if mnemonic.contains("16") {
+        3
+    } else {
+        1
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operand {
+    None,
+    Byte(u8),
+    Word(u16),
+    /// a8, a16 and the target of a JR
+    Address(u16),
+    Offset(i8),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    /// ROM bank of the instruction, used to look the labels up
+    pub bank: usize,
+    pub bytes: Vec<u8>,
+    mnemonic: String,
+    operand: Operand,
+}
+
+/// Decode the instruction at the start of `bytes`, missing bytes are read as 0.
+/// `bank` is the ROM bank the instruction is read from.
+pub fn decode(bytes: &[u8], address: u16, bank: usize) -> Instruction {
+    let byte = |i: usize| bytes.get(i).copied().unwrap_or(0);
+    let opcode = byte(0);
+    let length = length(opcode);
+    let word = u16::from_le_bytes([byte(1), byte(2)]);
+
+    let mnemonic = match OPCODES[opcode as usize] {
+        "" => format!("DB ${:02X}", opcode),
+        "PREFIX CB" => {
+            let cb = byte(1);
+            let register = CB_REGISTERS[cb as usize & 7];
+            match cb >> 6 {
+                0 => format!("{} {}", CB_OPERATIONS[cb as usize >> 3], register),
+                1 => format!("BIT {}, {}", (cb >> 3) & 7, register),
+                2 => format!("RES {}, {}", (cb >> 3) & 7, register),
+                _ => format!("SET {}, {}", (cb >> 3) & 7, register),
+            }
+        }
+        mnemonic => mnemonic.to_string(),
+    };
+
+    let operand = if mnemonic.contains("d16") {
+        Operand::Word(word)
+    } else if mnemonic.contains("a16") {
+        Operand::Address(word)
+    } else if mnemonic.contains("d8") {
+        Operand::Byte(byte(1))
+    } else if mnemonic.contains("a8") {
+        Operand::Address(0xFF00 | byte(1) as u16)
+    } else if mnemonic.starts_with("JR") {
+        Operand::Address(address.wrapping_add(2).wrapping_add(byte(1) as i8 as u16))
+    } else if mnemonic.contains("r8") {
+        Operand::Offset(byte(1) as i8)
+    } else {
+        Operand::None
+    };
+
+    let bytes = (0..length).map(byte).collect();
+    Instruction { address, bank, bytes, mnemonic, operand }
+}
+
+impl Instruction {
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Address of the next instruction
+    pub fn next(&self) -> u16 {
+        self.address.wrapping_add(self.len() as u16)
+    }
+
+    /// Mnemonic with its operand, addresses are replaced by labels when `symbols` has them
+    pub fn format(&self, symbols: Option<&Symbols>) -> String {
+        let label = |address: u16| {
+            // A switchable bank address refers to the bank of the instruction when it is in one too
+            let switchable = |address| (0x4000..0x8000).contains(&address);
+            let bank = (switchable(address) && switchable(self.address)).then_some(self.bank);
+            symbols.and_then(|symbols| symbols.get(bank, address))
+        };
+        let (placeholder, value) = match self.operand {
+            Operand::None => return self.mnemonic.clone(),
+            Operand::Byte(value) => ("d8", format!("${:02X}", value)),
+            Operand::Word(value) => ("d16", label(value).map_or_else(|| format!("${:04X}", value), str::to_string)),
+            Operand::Address(address) => {
+                let placeholder = ["a16", "a8", "r8"].into_iter().find(|p| self.mnemonic.contains(p)).unwrap();
+                let value = match label(address) {
+                    Some(label) => label.to_string(),
+                    None if placeholder == "a8" => format!("$FF{:02X}", address as u8),
+                    None => format!("${:04X}", address),
+                };
+                (placeholder, value)
+            }
+            Operand::Offset(offset) if self.mnemonic.contains("+r8") => ("+r8", format!("{:+}", offset)),
+            Operand::Offset(offset) => ("r8", offset.to_string()),
+        };
+        self.mnemonic.replacen(placeholder, &value, 1)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format(None))
+    }
+}
+
+/// Labels of an RGBDS `.sym` file: `BB:AAAA Name` lines, `;` starts a comment
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Symbols {
+    by_address: HashMap<u16, Vec<(usize, String)>>,
+    by_name: HashMap<String, (usize, u16)>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    /// Lines that aren't `BB:AAAA Name` are skipped
+    pub fn parse(text: &str) -> Symbols {
+        let mut symbols = Symbols::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            let (Some(location), Some(name)) = (fields.next(), fields.next()) else { continue };
+            let Some((bank, address)) = location.split_once(':') else { continue };
+            if let (Ok(bank), Ok(address)) = (usize::from_str_radix(bank, 16), u16::from_str_radix(address, 16)) {
+                symbols.insert(bank, address, name);
+            }
+        }
+        symbols
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Symbols> {
+        Ok(Symbols::parse(&std::fs::read_to_string(path)?))
+    }
+
+    pub fn insert(&mut self, bank: usize, address: u16, name: &str) {
+        self.by_address.entry(address).or_default().push((bank, name.to_string()));
+        self.by_name.insert(name.to_string(), (bank, address));
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// First label at `address`, in `bank` when given
+    pub fn get(&self, bank: Option<usize>, address: u16) -> Option<&str> {
+        self.by_address
+            .get(&address)?
+            .iter()
+            .find(|(b, _)| bank.is_none_or(|bank| *b == bank))
+            .map(|(_, name)| name.as_str())
+    }
+
+    /// Bank and address of a label
+    pub fn find(&self, name: &str) -> Option<(usize, u16)> {
+        self.by_name.get(name).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let cases: [(&[u8], &str); 12] = [
+            (&[0x00], "NOP"),
+            (&[0x01, 0x34, 0x12], "LD BC, $1234"),
+            (&[0x3E, 0x42], "LD A, $42"),
+            (&[0xE0, 0x40], "LDH ($FF40), A"),
+            (&[0xFA, 0x00, 0xC0], "LD A, ($C000)"),
+            (&[0x20, 0xFC], "JR NZ, $00FE"),
+            (&[0xE8, 0xFE], "ADD SP, -2"),
+            (&[0xF8, 0x05], "LD HL, SP+5"),
+            (&[0xCB, 0x7C], "BIT 7, H"),
+            (&[0xCB, 0x36], "SWAP (HL)"),
+            (&[0x10, 0x00], "STOP $00"),
+            (&[0xD3], "DB $D3"),
+        ];
+        for (bytes, text) in cases {
+            let instruction = decode(bytes, 0x0100, 0);
+            assert_eq!(instruction.to_string(), text);
+            assert_eq!(instruction.len(), bytes.len(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_symbols() {
+        let symbols = Symbols::parse("; File generated by rgblink\n00:0150 Main\n01:4000 Data ; comment\n02:4000 Other\nbad line\n");
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.find("Data"), Some((1, 0x4000)));
+        assert_eq!(symbols.get(Some(2), 0x4000), Some("Other"));
+        assert_eq!(symbols.get(None, 0x4000), Some("Data"));
+
+        assert_eq!(decode(&[0xCD, 0x50, 0x01], 0x4100, 2).format(Some(&symbols)), "CALL Main");
+        assert_eq!(decode(&[0x21, 0x00, 0x40], 0x4100, 2).format(Some(&symbols)), "LD HL, Other");
+        assert_eq!(decode(&[0x21, 0x00, 0x40], 0x0200, 0).format(Some(&symbols)), "LD HL, Data");
+    }
+}
@@ -1,76 +1,356 @@
+use std::fmt;
+
+use crate::archive::{self, ArchiveError};
+use crate::cheats::{self, Cheats};
 use crate::cpu::CPU;
-use crate::gpu::SCREEN_SIZE_RGB;
-use crate::header::Header;
+use crate::disasm::Instruction;
+use crate::gpu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::header::{Header, HeaderError};
+use crate::image::{Image, PixelFormat};
+use crate::inspect::{self, OamEntry, RgbaImage, TileMap};
 use crate::keypad::KeyEvent;
+use crate::palette::{self, Palettes};
+use crate::patch::{self, PatchError};
+use crate::recorder::Recorder;
+use crate::trace::Trace;
+use crate::registers::Registers;
 use crate::{mbc, time};
 
 const FRAME_TIME: f64 = 1.0 / 60.0;
 const CYCLES_PER_SECOND: u32 = 4_194_304;
 const CYCLES_PER_FRAME: u32 = CYCLES_PER_SECOND / 60;
 
-#[derive(PartialEq)]
+type RenderCallback = Box<dyn FnMut(&[u8]) + 'static>;
+type InputCallback = Box<dyn FnMut() -> Option<KeyEvent> + 'static>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum GBMode {
     DMG,
     CGB,
 }
- 
+
+/// Error returned when a ROM can't be loaded
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    TooSmall(usize),
+    BadHeader(HeaderError),
+    UnsupportedMbc(u8),
+    BadRomSize(u8),
+    BadRamSize(u8),
+    RomSizeMismatch { expected: usize, actual: usize },
+    BadBootRomSize(usize),
+    SaveRamSize { expected: usize, actual: usize },
+    BadArchive(ArchiveError),
+    /// The patch at `index`, in the order they were given, can't be applied
+    BadPatch { index: usize, error: PatchError },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "Can't read the ROM: {}", e),
+            LoadError::TooSmall(size) => write!(f, "ROM is too small to have a header: {} bytes", size),
+            LoadError::BadHeader(reason) => write!(f, "Bad header: {}", reason),
+            LoadError::UnsupportedMbc(value) => write!(f, "Unsupported MBC: {:#04x}", value),
+            LoadError::BadRomSize(value) => write!(f, "Invalid ROM size code: {:#04x}", value),
+            LoadError::BadRamSize(value) => write!(f, "Invalid RAM size code: {:#04x}", value),
+            LoadError::RomSizeMismatch { expected, actual } => {
+                write!(f, "ROM size mismatch: header says {} bytes, got {}", expected, actual)
+            }
+            LoadError::BadBootRomSize(size) => {
+                write!(f, "Boot ROM must be 256 (DMG) or 2304 (CGB) bytes, got {}", size)
+            }
+            LoadError::SaveRamSize { expected, actual } => {
+                write!(f, "Save RAM size mismatch: cartridge has {} bytes, got {}", expected, actual)
+            }
+            LoadError::BadArchive(error) => write!(f, "Can't extract the ROM: {}", error),
+            LoadError::BadPatch { error, .. } => write!(f, "Can't apply the patch: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            LoadError::BadArchive(error) => Some(error),
+            LoadError::BadPatch { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<HeaderError> for LoadError {
+    fn from(e: HeaderError) -> Self {
+        match e {
+            HeaderError::TooSmall(size) => LoadError::TooSmall(size),
+            e => LoadError::BadHeader(e),
+        }
+    }
+}
+
+impl From<ArchiveError> for LoadError {
+    fn from(e: ArchiveError) -> Self {
+        LoadError::BadArchive(e)
+    }
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(e: std::io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+/// Configure and create a `Gameboy`
+///
+/// # Example
+/// ```
+/// use rusty_boy::gameboy::{GameboyBuilder, GBMode};
+/// let rom = vec![0; 0x8000];
+/// let game = GameboyBuilder::new(rom)
+///     .model(GBMode::DMG)
+///     .build()
+///     .unwrap();
+/// ```
+pub struct GameboyBuilder {
+    rom: Vec<u8>,
+    patches: Vec<Vec<u8>>,
+    boot_rom: Option<Vec<u8>>,
+    model: Option<GBMode>,
+    save_ram: Option<Vec<u8>>,
+    palette: Palettes,
+    pixel_format: PixelFormat,
+    strict_boot: bool,
+}
+
+impl GameboyBuilder {
+    /// The ROM can also be a .zip (the first .gb or .gbc inside is used) or a .gz,
+    /// it is extracted before the patches are applied
+    pub fn new(rom: Vec<u8>) -> GameboyBuilder {
+        GameboyBuilder {
+            rom,
+            patches: Vec::new(),
+            boot_rom: None,
+            model: None,
+            save_ram: None,
+            palette: Palettes::uniform(palette::GREY),
+            pixel_format: PixelFormat::Rgb24,
+            strict_boot: false,
+        }
+    }
+
+    /// Apply an IPS, UPS or BPS patch to the ROM before loading it,
+    /// several patches are applied in the order they are given
+    pub fn patch(mut self, patch: Vec<u8>) -> Self {
+        self.patches.push(patch);
+        self
+    }
+
+    /// Run the given boot ROM instead of starting directly at 0x0100
+    pub fn boot_rom(mut self, boot_rom: Vec<u8>) -> Self {
+        self.boot_rom = Some(boot_rom);
+        self
+    }
+
+    /// Force the model, by default it is detected from the header
+    /// (CGB only for CGB-only cartridges, the CGB rendering isn't complete yet)
+    pub fn model(mut self, model: GBMode) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    /// Restore the content of the cartridge RAM
+    pub fn save_ram(mut self, save_ram: Vec<u8>) -> Self {
+        self.save_ram = Some(save_ram);
+        self
+    }
+
+    /// Colours used to display the 4 DMG shades, a single `Palette` or separate
+    /// background and object `Palettes`
+    pub fn palette<P: Into<Palettes>>(mut self, palette: P) -> Self {
+        self.palette = palette.into();
+        self
+    }
+
+    /// Layout of the screen data given to the render callback, RGB24 by default
+    pub fn pixel_format(mut self, format: PixelFormat) -> Self {
+        self.pixel_format = format;
+        self
+    }
+
+    /// Without a boot ROM, refuse to start when the logo or the header checksum
+    /// are wrong, as the boot ROM would. Off by default.
+    pub fn strict_boot(mut self, strict_boot: bool) -> Self {
+        self.strict_boot = strict_boot;
+        self
+    }
+
+    pub fn build(mut self) -> Result<Gameboy, LoadError> {
+        self.rom = archive::extract_rom(std::mem::take(&mut self.rom))?;
+        for (index, patch) in self.patches.iter().enumerate() {
+            self.rom = patch::apply(&self.rom, patch).map_err(|error| LoadError::BadPatch { index, error })?;
+        }
+
+        let header = Header::load_rom(&self.rom)?;
+        let mut mbc = mbc::from_rom(&self.rom)?;
+
+        if let Some(save_ram) = &self.save_ram {
+            if save_ram.len() != mbc.ram().len() {
+                return Err(LoadError::SaveRamSize { expected: mbc.ram().len(), actual: save_ram.len() });
+            }
+            mbc.load_ram(save_ram);
+        }
+
+        let mode = self.model.unwrap_or(if header.cgb_flag() == 0xC0 { GBMode::CGB } else { GBMode::DMG });
+
+        if self.strict_boot && self.boot_rom.is_none() {
+            header.check_boot(mode)?;
+        }
+
+        let mut cpu = CPU::new(mbc);
+        cpu.memory.gpu.set_mode(mode);
+        cpu.memory.gpu.set_palettes(self.palette);
+        cpu.memory.gpu.set_pixel_format(self.pixel_format);
+
+        if let Some(boot_rom) = self.boot_rom {
+            if boot_rom.len() != 0x100 && boot_rom.len() != 0x900 {
+                return Err(LoadError::BadBootRomSize(boot_rom.len()));
+            }
+            cpu.memory.load_boot_rom(boot_rom);
+            cpu.registers = Registers::power_on();
+        }
+
+        Ok(Gameboy {
+            cpu,
+            header,
+            mode,
+
+            render_callback: Box::new(|_| {}),
+            input_callback: Box::new(|| None),
+            recorder: None,
+            recording_error: None,
+
+            previous_time: 0.0,
+            lag: 0.0,
+        })
+    }
+}
+
+/// Value written back at `address` when every VBlank starts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Freeze {
+    pub address: u16,
+    pub value: u8,
+}
+
 pub struct Gameboy {
     pub cpu: CPU,
     header: Header,
-    
-    render_callback: Box<dyn FnMut(&[u8; 160 * 144 * 3]) + 'static>,
-    input_callback: Box<dyn FnMut() -> Option<KeyEvent> + 'static>,
+    mode: GBMode,
+
+    render_callback: RenderCallback,
+    input_callback: InputCallback,
+    recorder: Option<Recorder>,
+    recording_error: Option<std::io::Error>,
 
     pub previous_time: f64,
     pub lag: f64,
 }
 
 impl Gameboy {
-    pub fn new(rom: &Vec<u8>) -> Gameboy {
+    /// Create a Gameboy with the default options, see `GameboyBuilder` for more
+    pub fn new(rom: &[u8]) -> Result<Gameboy, LoadError> {
+        GameboyBuilder::new(rom.to_vec()).build()
+    }
 
-        let header = Header::load_rom(&rom);
-        let mbc = crate::mbc::from_rom(&rom);
+    /// Load a ROM file, it can also be a .zip (the first .gb or .gbc inside is used) or a .gz
+    pub fn new_from_file(file: &str) -> Result<Gameboy, LoadError> {
+        GameboyBuilder::new(std::fs::read(file)?).build()
+    }
 
-        Gameboy {
-            cpu: CPU::new(mbc),
-            header,
+    /// Cycles elapsed since power on
+    pub fn cycles(&self) -> u64 {
+        self.cpu.memory.cycles()
+    }
 
-            render_callback: Box::new(|_| { panic!("No render callback set!"); }),
-            input_callback: Box::new(|| { panic!("No input callback set!"); }),
+    /// Model being emulated
+    pub fn mode(&self) -> GBMode {
+        self.mode
+    }
 
-            previous_time: 0.0,
-            lag: 0.0,
-        }
+    /// Colours the PPU uses for the 4 DMG shades
+    pub fn palette(&self) -> &Palettes {
+        self.cpu.memory.gpu.palettes()
+    }
+
+    /// Change the colours, pixels already on screen keep theirs until they are redrawn
+    pub fn set_palette<P: Into<Palettes>>(&mut self, palette: P) {
+        self.cpu.memory.gpu.set_palettes(palette.into());
     }
 
-    pub fn new_from_file(file: &str) -> Gameboy {
-        let rom = std::fs::read(file).unwrap();
-        Gameboy::new(&rom)
+    /// Content of the cartridge RAM, to be written to a save file when the cartridge has a battery
+    pub fn save_ram(&self) -> Option<&[u8]> {
+        let mbc = &self.cpu.memory.mbc;
+        if mbc.has_battery() { Some(mbc.ram()) } else { None }
     }
 
 
     /// Get the screen data
-    pub fn get_screen_data(&self) -> &[u8; SCREEN_SIZE_RGB] {
-        return self.cpu.memory.gpu.screen_data();
+    /// The layout of the pixels depends on the `PixelFormat`
+    pub fn get_screen_data(&self) -> &[u8] {
+        self.cpu.memory.gpu.screen_data()
+    }
+
+    /// Copy of the current frame
+    pub fn screenshot(&self) -> Image {
+        let gpu = &self.cpu.memory.gpu;
+        Image::from_framebuffer(SCREEN_WIDTH, SCREEN_HEIGHT, gpu.pixel_format(), gpu.palettes(), gpu.screen_data())
+    }
+
+    /// Tile data of VRAM: 384 tiles, 768 in CGB mode, `inspect::TILES_PER_ROW` per row
+    pub fn render_tiles(&self) -> RgbaImage {
+        inspect::tiles(&self.cpu.memory.gpu)
+    }
+
+    /// A background map, with the scroll viewport outlined when the background uses it
+    pub fn render_tile_map(&self, map: TileMap) -> RgbaImage {
+        inspect::tile_map(&self.cpu.memory.gpu, map)
+    }
+
+    /// The 40 objects of OAM
+    pub fn oam_entries(&self) -> Vec<OamEntry> {
+        inspect::oam_entries(&self.cpu.memory.gpu)
+    }
+
+    /// The 40 objects of OAM, 8 per row
+    pub fn render_oam(&self) -> RgbaImage {
+        inspect::oam(&self.cpu.memory.gpu)
+    }
+
+    /// Colours selected by BGP, OBP0 and OBP1
+    pub fn render_palettes(&self) -> RgbaImage {
+        inspect::palettes(&self.cpu.memory.gpu)
     }
 
     /// Set the render callback
     /// The render callback is a function that will be called every frame to render the screen
-    /// The function must take a slice of 160*144*3 u8 as argument -- 160*144 pixels with 3 bytes per pixel (RGB)
+    /// The function must take a slice of 160*144 pixels as argument, in the `PixelFormat` chosen
+    /// with `set_pixel_format` -- 3 bytes per pixel (RGB) by default
     /// 
     /// # Example
     /// ```
     /// use rusty_boy::gameboy::Gameboy;
-    /// let mut game = Gameboy::new();
+    /// let rom = vec![0; 0x8000];
+    /// let mut game = Gameboy::new(&rom).unwrap();
     /// game.set_render_callback(|screen_data| {
     ///     for y in 0..144 {
     ///         for x in 0..160 {
     ///             print!( "{}", 
     ///                 match screen_data[y * 160 * 3 + x * 3] {
-    ///                     3 => "  ",
-    ///                     2 => "░░",
-    ///                     1 => "▒▒",
-    ///                     0 => "▓▓",
+    ///                     0xC0..=0xFF => "▓▓",
+    ///                     0x80..=0xBF => "▒▒",
+    ///                     0x40..=0x7F => "░░",
     ///                     _ => "  ",
     ///                 }
     ///             );
@@ -81,11 +361,50 @@ impl Gameboy {
     /// ```
     pub fn set_render_callback<F>(&mut self, callback: F)
     where
-        F: FnMut(&[u8; SCREEN_SIZE_RGB]) + 'static,
+        F: FnMut(&[u8]) + 'static,
     {
         self.render_callback = Box::new(callback);
     }
 
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.cpu.memory.gpu.pixel_format()
+    }
+
+    /// Change the layout of the screen data, the screen is cleared
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.cpu.memory.gpu.set_pixel_format(format);
+    }
+
+    /// Enable or disable the VRAM/OAM locking during PPU modes 2 and 3
+    /// When disabled, the CPU can always reach VRAM and OAM (useful for debugging)
+    pub fn set_ppu_access_blocking(&mut self, enabled: bool) {
+        self.cpu.memory.ppu_access_blocking = enabled;
+    }
+
+    /// Enable or disable the M-cycle accurate mode
+    /// When enabled, every memory access of the CPU advances the timer, the PPU and the DMA by one M-cycle
+    pub fn set_cycle_accurate(&mut self, enabled: bool) {
+        self.cpu.cycle_accurate = enabled;
+    }
+
+    /// Press or release a button, for hosts driving the emulation with `run_frame`
+    pub fn handle_key(&mut self, event: KeyEvent) {
+        match event {
+            KeyEvent::Press(key)    => { self.cpu.memory.keypad.press(key); }
+            KeyEvent::Release(key)  => { self.cpu.memory.keypad.release(key); }
+        }
+    }
+
+    /// Decode the instruction at `address`, in the ROM bank currently mapped there
+    pub fn disassemble(&self, address: u16) -> Instruction {
+        self.cpu.disassemble(address)
+    }
+
+    /// Execute a single instruction, returns the number of cycles it took
+    pub(crate) fn step(&mut self) -> u8 {
+        self.cpu.step()
+    }
+
     /// Set the input callback
     /// The input callback is a function that will be called every frame to get the input from the user
     /// The function must return an Option<KeyEvent>
@@ -106,11 +425,8 @@ impl Gameboy {
         self.lag += elapsed;
 
         // Call the input callback to get the input from the user
-        if let Some(key) = (self.input_callback)() {
-            match key {
-                KeyEvent::Press(key)    => { self.cpu.memory.keypad.press(key); }
-                KeyEvent::Release(key)  => { self.cpu.memory.keypad.release(key); }
-            }
+        if let Some(event) = (self.input_callback)() {
+            self.handle_key(event);
         }
 
 
@@ -165,81 +481,168 @@ impl Gameboy {
     /// Update the game state
     /// This function will update the game state by running the CPU for a fixed number of cycles
     fn update(&mut self) {
+        self.run_cycles(CYCLES_PER_FRAME as u64);
+    }
 
-        // Execute the CPU for a fixed number of cycles
-        let mut cycles = 0;
-        while cycles < CYCLES_PER_FRAME {
-            cycles += self.cpu.step() as u32;
+    /// Run the emulation until the PPU completes a frame
+    /// No callback is called and there is no wall-clock timing: the frame is emulated as fast as possible
+    /// Returns the number of cycles executed
+    pub fn run_frame(&mut self) -> u64 {
+        self.cpu.memory.gpu.frame_ready = false;
+        let cycles = self.run_until(|gameboy| gameboy.cpu.memory.gpu.frame_ready);
+        self.end_frame();
+        cycles
+    }
+
+    /// Run the emulation for at least `cycles` cycles
+    /// Returns the number of cycles executed, it can be a bit more than requested as instructions aren't split
+    pub fn run_cycles(&mut self, cycles: u64) -> u64 {
+        let mut executed = 0;
+        while executed < cycles {
+            executed += self.step() as u64;
         }
+        self.cpu.memory.sync();
+        executed
+    }
+
+    /// Run the emulation until the predicate returns true, it is checked after each instruction
+    /// Returns the number of cycles executed
+    pub fn run_until<F>(&mut self, mut predicate: F) -> u64
+    where
+        F: FnMut(&Gameboy) -> bool,
+    {
+        let mut executed = 0;
+        while !predicate(self) {
+            executed += self.step() as u64;
+        }
+        self.cpu.memory.sync();
+        executed
     }
 
     /// Render the screen
     /// This function will call the render callback to render the screen
     fn render(&mut self) {
         (self.render_callback)(self.cpu.memory.gpu.screen_data());
+        self.end_frame();
     }
 
-    #[deprecated]
-    // This function was used to debug opcodes
-    pub fn run_debug(&mut self) {
-        // 2F2A --> Intro
-        // 6A6B --> Title screen
-        // 650C
-        // 2CF --> CFFB est remis (64D3)
-
-        // 0x5b7 --> 0x3c5
-
-        // List of 10 last opcodes
-        let mut last_addr = [0u16; 5_000];
-
-        //while self.cpu.registers.pc != 0x01 {
-        let mut c = 0;
-        while true {
-            self.cpu.step();
-            if self.cpu.registers.pc == 0x2892 && self.cpu.registers.hl() == 0x6f94 {
-                c += 1;
-                if c == 982 {
-                    break;
-                }
-            }
-        }
+    /// Record the frame, the freezes and the GameShark codes are applied by `Memory` at VBlank
+    pub(crate) fn end_frame(&mut self) {
+        self.record_frame();
+    }
 
-        //0x40e --> load scx into a
-        //0x7b9e --> increment 0xC103
-        while true {
-            self.cpu.step();
-        }
+    /// Read a byte without side effects: no PPU lock, the MBC and the I/O registers are untouched
+    pub fn peek(&self, address: u16) -> u8 {
+        self.cpu.memory.peek(address)
+    }
+
+    /// Write a byte without side effects: ROM and external RAM are patched in the mapped bank
+    /// instead of writing the MBC registers, writing DIV doesn't reset it, DMA isn't started...
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.cpu.memory.poke(address, value);
+    }
+
+    /// Byte of any ROM bank, `None` past the end of the ROM
+    pub fn peek_rom(&self, bank: usize, address: u16) -> Option<u8> {
+        self.cpu.memory.peek_rom(bank, address)
+    }
+
+    /// Byte of any external RAM bank, `None` past the end of the RAM
+    pub fn peek_ram(&self, bank: usize, address: u16) -> Option<u8> {
+        self.cpu.memory.peek_ram(bank, address)
+    }
+
+    pub fn rom_banks(&self) -> usize {
+        self.cpu.memory.mbc.rom().len().div_ceil(0x4000)
+    }
+
+    pub fn ram_banks(&self) -> usize {
+        self.cpu.memory.mbc.ram().len().div_ceil(0x2000)
+    }
 
-        for _ in 0..100 {
-            self.cpu.step_debug();
+    /// Write `value` at `address` now and when every VBlank starts, replaces a previous freeze of the address.
+    /// Returns false, and does nothing, when the address isn't in cartridge RAM, WRAM or HRAM.
+    pub fn freeze(&mut self, address: u16, value: u8) -> bool {
+        if !cheats::is_ram(address) {
+            return false;
         }
+        self.unfreeze(address);
+        self.cpu.memory.freezes.push(Freeze { address, value });
+        self.poke(address, value);
+        true
+    }
+
+    /// Returns false when the address wasn't frozen
+    pub fn unfreeze(&mut self, address: u16) -> bool {
+        let freezes = &mut self.cpu.memory.freezes;
+        let count = freezes.len();
+        freezes.retain(|freeze| freeze.address != address);
+        freezes.len() != count
+    }
 
-        println!("Registers: {:?}", self.cpu.registers);
-        println!("0xC103: {:#04x}", self.cpu.memory.read(0xC103));
-        
-        /* let mut str_buffer = String::new();
-        for addr in last_addr.iter() {
-            let s = format!("0x{:04x} :: ", addr);
-            str_buffer.push_str(&s);
+    pub fn freezes(&self) -> &[Freeze] {
+        &self.cpu.memory.freezes
+    }
+
+    pub fn cheats(&self) -> &Cheats {
+        &self.cpu.memory.cheats
+    }
+
+    /// Add, remove or toggle the cheat codes, see `Cheats`
+    pub fn cheats_mut(&mut self) -> &mut Cheats {
+        &mut self.cpu.memory.cheats
+    }
+
+    /// Record the frames shown by `run` or produced by `run_frame` into a GIF or an AVI,
+    /// depending on the extension of the file
+    pub fn start_recording<P: AsRef<std::path::Path>>(&mut self, path: P) -> std::io::Result<()> {
+        self.stop_recording()?;
+        self.recorder = Some(Recorder::create(path, SCREEN_WIDTH, SCREEN_HEIGHT)?);
+        Ok(())
+    }
+
+    /// Complete the file, returns the first error met while recording
+    pub fn stop_recording(&mut self) -> std::io::Result<()> {
+        let recorder = self.recorder.take();
+        if let Some(e) = self.recording_error.take() {
+            return Err(e);
         }
-        println!("{}", str_buffer); */
-        println!("ROM: {:?}", self.cpu.memory.mbc.info());
+        recorder.map_or(Ok(()), Recorder::finish)
+    }
 
-        /* for _ in 0..1_000 {
-            self.cpu.step(false);
-            for _ in 0..500_000 {
-                self.cpu.step(false);
-            }
-            //println!("Registers: {:?}", self.cpu.registers);
-        } */
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Log every executed instruction, in the gameboy-doctor format or disassembled, see `Trace`
+    pub fn start_trace(&mut self, trace: Trace) -> std::io::Result<()> {
+        self.stop_trace()?;
+        self.cpu.trace = Some(trace);
+        Ok(())
+    }
 
+    /// Flush the trace, returns the first error met while writing it
+    pub fn stop_trace(&mut self) -> std::io::Result<()> {
+        self.cpu.trace.take().map_or(Ok(()), Trace::finish)
+    }
 
-        println!("Registers: {:?}", self.cpu.registers);
-        println!("0x9820: {:#04x}", self.cpu.memory.read(0x9820));
-        println!("OAM: {:#04x}", self.cpu.memory.read(0xfe10));
-        println!("OAM: {:#04x}", self.cpu.memory.read(0xfe11));
-        println!("OAM: {:#04x}", self.cpu.memory.read(0xfe12));
-        println!("OAM: {:#04x}", self.cpu.memory.read(0xfe13));
+    /// Make LY (0xFF44) always read 0x90, which gameboy-doctor requires to compare the traces
+    pub fn set_ly_stub(&mut self, enabled: bool) {
+        self.cpu.memory.ly_stub = enabled;
+    }
+
+    /// A write error stops the recording, it is reported by `stop_recording`
+    fn record_frame(&mut self) {
+        if self.recorder.is_none() {
+            return;
+        }
+        let frame = self.screenshot();
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.add_frame(&frame) {
+                self.recorder = None;
+                self.recording_error = Some(e);
+            }
+        }
     }
 
     /// Get the header of the loaded ROM
@@ -248,3 +651,273 @@ impl Gameboy {
         &self.header
     }
 }
+
+/// Builder of the test fixtures: the ROM gets the logo and the header checksum, and strict boot
+/// is on so the fixtures pass the checks of the boot ROM
+#[cfg(test)]
+pub(crate) fn test_builder(mut rom: Vec<u8>) -> GameboyBuilder {
+    crate::header::make_bootable(&mut rom);
+    GameboyBuilder::new(rom).strict_boot(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::make_bootable;
+
+    /// ROM looping on INC A
+    fn gameboy() -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x3C, 0xC3, 0x00, 0x01]); // INC A, JP 0x0100
+        test_builder(rom).build().unwrap()
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        assert!(matches!(Gameboy::new(&[0; 0x100]), Err(LoadError::TooSmall(0x100))));
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0xFD;
+        assert!(matches!(Gameboy::new(&rom), Err(LoadError::UnsupportedMbc(0xFD))));
+
+        rom[0x147] = 0x00;
+        rom[0x148] = 0x01;
+        assert!(matches!(Gameboy::new(&rom), Err(LoadError::RomSizeMismatch { expected: 0x10000, actual: 0x8000 })));
+
+        let builder = GameboyBuilder::new(vec![0; 0x8000]).boot_rom(vec![0; 0x10]);
+        assert!(matches!(builder.build(), Err(LoadError::BadBootRomSize(0x10))));
+    }
+
+    #[test]
+    fn test_builder_patch() {
+        let ips = b"PATCH\x00\x01\x00\x00\x01\x3DEOF".to_vec();
+        let gb = test_builder(vec![0; 0x8000]).patch(ips.clone()).build().unwrap();
+        assert_eq!(gb.peek_rom(0, 0x0100), Some(0x3D));
+
+        let builder = test_builder(vec![0; 0x8000]).patch(ips.clone()).patch(b"NOPE".to_vec());
+        assert!(matches!(builder.build(), Err(LoadError::BadPatch { index: 1, error: PatchError::UnknownFormat })));
+
+        // Gzip of the ROM in a stored deflate block, patched once extracted
+        let mut rom = vec![0; 0x8000];
+        make_bootable(&mut rom);
+        let mut gz = vec![0x1F, 0x8B, 0x08, 0, 0, 0, 0, 0, 0, 0xFF, 0x01, 0x00, 0x80, 0xFF, 0x7F];
+        gz.extend_from_slice(&rom);
+        gz.extend_from_slice(&crate::image::crc32(&rom).to_le_bytes());
+        gz.extend_from_slice(&(rom.len() as u32).to_le_bytes());
+        let gb = GameboyBuilder::new(gz.clone()).strict_boot(true).patch(ips).build().unwrap();
+        assert_eq!(gb.peek_rom(0, 0x0100), Some(0x3D));
+
+        gz[20] = 1;
+        assert!(matches!(GameboyBuilder::new(gz).build(), Err(LoadError::BadArchive(ArchiveError::BadChecksum { .. }))));
+    }
+
+    #[test]
+    fn test_builder_save_ram() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x03;
+        let gb = test_builder(rom.clone()).save_ram(vec![0x42; 0x8000]).build().unwrap();
+        assert_eq!(gb.save_ram().unwrap()[0x1234], 0x42);
+
+        let builder = test_builder(rom).save_ram(vec![0x42; 0x10]);
+        assert!(matches!(builder.build(), Err(LoadError::SaveRamSize { expected: 0x8000, actual: 0x10 })));
+    }
+
+    #[test]
+    fn test_mbc1_ram() {
+        // MBC1 without RAM: reads of the enabled RAM are open bus
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x01;
+        let mut gb = test_builder(rom.clone()).build().unwrap();
+        gb.cpu.memory.write(0x0000, 0x0A);
+        assert_eq!(gb.cpu.memory.read(0xA000), 0xFF);
+        gb.cpu.memory.write(0xA000, 0x42);
+
+        // 4 banks, the bank selected in mode 1 is the one read back
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x03;
+        let mut gb = test_builder(rom).build().unwrap();
+        gb.cpu.memory.write(0x0000, 0x0A);
+        gb.cpu.memory.write(0x6000, 0x01);
+        gb.cpu.memory.write(0x4000, 0x02);
+        gb.cpu.memory.write(0xA123, 0x42);
+        assert_eq!(gb.cpu.memory.read(0xA123), 0x42);
+        assert_eq!(gb.save_ram().unwrap()[2 * 0x2000 + 0x123], 0x42);
+        gb.cpu.memory.write(0x4000, 0x00);
+        assert_eq!(gb.cpu.memory.read(0xA123), 0x00);
+    }
+
+    #[test]
+    fn test_builder_strict_boot() {
+        let strict_builder = |rom: &[u8]| GameboyBuilder::new(rom.to_vec()).strict_boot(true);
+        let mut rom = vec![0; 0x8000];
+        assert!(matches!(strict_builder(&rom).build(), Err(LoadError::BadHeader(HeaderError::BadLogo))));
+        assert!(Gameboy::new(&rom).is_ok());
+
+        make_bootable(&mut rom);
+        assert!(strict_builder(&rom).build().is_ok());
+        rom[0x14D] ^= 0xFF;
+        assert!(matches!(strict_builder(&rom).build(), Err(LoadError::BadHeader(HeaderError::BadHeaderChecksum { .. }))));
+
+        // The boot ROM does the checks itself
+        assert!(strict_builder(&rom).boot_rom(vec![0; 0x100]).build().is_ok());
+    }
+
+    #[test]
+    fn test_builder_boot_rom() {
+        let gb = test_builder(vec![0; 0x8000]).boot_rom(vec![0; 0x100]).build().unwrap();
+        assert_eq!(gb.cpu.registers.pc, 0x0000);
+    }
+
+    #[test]
+    fn test_screenshot() {
+        let mut gb = gameboy();
+        gb.set_palette(Palettes::uniform([[1, 2, 3], [0; 3], [0; 3], [7, 8, 9]]));
+        gb.cpu.memory.gpu.set_color(5, 3, 0);
+
+        let image = gb.screenshot();
+        assert_eq!((image.width, image.height), (160, 144));
+        assert_eq!(image.pixel(0, 0), [0xFF; 3]);
+        assert_eq!(image.pixel(5, 0), [7, 8, 9]);
+    }
+
+    #[test]
+    fn test_pixel_format() {
+        let mut gb = test_builder(vec![0; 0x8000]).pixel_format(PixelFormat::Rgba32).build().unwrap();
+        assert_eq!(gb.get_screen_data().len(), 160 * 144 * 4);
+        assert_eq!(&gb.get_screen_data()[..4], &[0xFF; 4]);
+
+        gb.set_pixel_format(PixelFormat::Rgb565);
+        gb.cpu.memory.gpu.set_color(1, 3, 0);
+        assert_eq!(&gb.get_screen_data()[..4], &[0xFF, 0xFF, 0x00, 0x00]);
+        assert_eq!(gb.screenshot().pixel(1, 0), [0; 3]);
+
+        gb.set_pixel_format(PixelFormat::Indexed);
+        gb.set_palette(Palettes { obj1: palette::GREEN, ..palette::GREY.into() });
+        gb.cpu.memory.gpu.set_color(1, 2, 2);
+        assert_eq!(&gb.get_screen_data()[..2], &[0, 2 << 2 | 2]);
+        assert_eq!(gb.screenshot().pixel(1, 0), palette::GREEN[2]);
+    }
+
+    #[test]
+    fn test_palette() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]); // JR -2
+        let mut gb = test_builder(rom).palette(palette::GREEN).build().unwrap();
+        assert_eq!(gb.palette().obj1, palette::GREEN);
+
+        // LCD on, BG shade 3 for colour 0
+        gb.cpu.memory.write(0xFF47, 0x03);
+        gb.cpu.memory.write(0xFF40, 0x91);
+        gb.set_palette(palette::CGB_RED);
+        gb.run_frame();
+        assert_eq!(gb.screenshot().pixel(0, 0), palette::CGB_RED.bg[3]);
+    }
+
+    #[test]
+    fn test_recording() {
+        let path = std::env::temp_dir().join(format!("rusty_boy_test_{}.avi", std::process::id()));
+        let mut gb = gameboy();
+        gb.start_recording(&path).unwrap();
+        assert!(gb.is_recording());
+        gb.run_frame();
+        gb.run_frame();
+        gb.stop_recording().unwrap();
+        assert!(!gb.is_recording());
+
+        let avi = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(u32::from_le_bytes(avi[48..52].try_into().unwrap()), 2);
+
+        assert!(gb.start_recording("recording.mp4").is_err());
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut gb = gameboy();
+        gb.run_frame();
+        let cycles = gb.run_frame();
+        assert!(cycles.abs_diff(70224) < 24);
+        assert!(gb.cpu.memory.gpu.frame_ready);
+    }
+
+    #[test]
+    fn test_run_cycles() {
+        let mut gb = gameboy();
+        let cycles = gb.run_cycles(1000);
+        assert!((1000..1020).contains(&cycles));
+    }
+
+    #[test]
+    fn test_run_until() {
+        let mut gb = gameboy();
+        gb.cpu.registers.a = 0;
+        gb.run_until(|gb| gb.cpu.registers.a == 10);
+        assert_eq!(gb.cpu.registers.a, 10);
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut gb = gameboy();
+        assert!(gb.freeze(0xC000, 0x07));
+        assert!(gb.freeze(0xC000, 0x42));
+        assert!(!gb.freeze(0x0100, 0x00));
+        assert_eq!(gb.freezes(), &[Freeze { address: 0xC000, value: 0x42 }]);
+        assert_eq!(gb.peek(0xC000), 0x42);
+
+        gb.poke(0xC000, 0x01);
+        gb.run_frame();
+        assert_eq!(gb.peek(0xC000), 0x42);
+        // Written at VBlank whatever runs the emulation
+        gb.poke(0xC000, 0x01);
+        gb.run_cycles(70224);
+        assert_eq!(gb.peek(0xC000), 0x42);
+
+        assert!(gb.unfreeze(0xC000));
+        assert!(!gb.unfreeze(0xC000));
+        gb.poke(0xC000, 0x01);
+        gb.run_frame();
+        assert_eq!(gb.peek(0xC000), 0x01);
+        assert_eq!((gb.rom_banks(), gb.ram_banks()), (2, 0));
+    }
+
+    #[test]
+    fn test_cheats() {
+        let mut gb = gameboy();
+        // INC A at 0x0100 becomes DEC A
+        gb.cheats_mut().add("3D1-00F-1EA", "").unwrap();
+        gb.cheats_mut().add("0142C0C0", "").unwrap();
+        assert_eq!(gb.peek(0x0100), 0x3D);
+        assert_eq!(gb.peek_rom(0, 0x0100), Some(0x3C));
+
+        gb.cpu.registers.a = 10;
+        gb.step();
+        assert_eq!(gb.cpu.registers.a, 9);
+        assert_eq!(gb.peek(0xC0C0), 0x00);
+        // Written at VBlank whatever runs the emulation
+        gb.run_cycles(70224);
+        assert_eq!(gb.peek(0xC0C0), 0x42);
+
+        gb.cheats_mut().set_enabled(0, false);
+        assert_eq!(gb.peek(0x0100), 0x3C);
+    }
+
+    #[test]
+    fn test_trace() {
+        let lines = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let log = lines.clone();
+        let mut gb = gameboy();
+        gb.start_trace(Trace::to_callback(move |line| log.borrow_mut().push(line.to_string())).pc_range(0x0100..=0x0100)).unwrap();
+        gb.run_cycles(40);
+        gb.stop_trace().unwrap();
+
+        let lines = lines.borrow();
+        assert!(lines.len() >= 2);
+        assert!(lines.iter().all(|line| line.contains("PC:0100 PCMEM:3C,C3,00,01")));
+        assert!(lines[0].starts_with("A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE"));
+        assert!(lines[1].starts_with("A:02 F:10"));
+
+        gb.set_ly_stub(true);
+        assert_eq!(gb.cpu.memory.read(0xFF44), 0x90);
+    }
+}
@@ -0,0 +1,385 @@
+//! GDB remote serial protocol stub, so GDB-compatible frontends can debug a running ROM over TCP
+//!
+//! The registers are AF, BC, DE, HL, SP and PC, 16 bits each, as described by the target
+//! description sent to the client. Breakpoints and watchpoints go through the `Debugger`.
+//!
+//! ```no_run
+//! use std::net::TcpListener;
+//! use rusty_boy::debugger::Debugger;
+//! use rusty_boy::gameboy::Gameboy;
+//!
+//! let mut debugger = Debugger::new(Gameboy::new_from_file("game.gb").unwrap());
+//! let listener = TcpListener::bind("127.0.0.1:2159").unwrap();
+//! rusty_boy::gdb::serve_tcp(&mut debugger, &listener).unwrap();
+//! ```
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::debugger::{Breakpoint, Debugger, StopReason, Watchpoint};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rusty-boy.sm83">
+    <reg name="af" bitsize="16" type="int" regnum="0"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTER_COUNT: usize = 6;
+
+/// Cycles run between two checks for an interrupt (Ctrl-C) from the client, about a frame
+const CONTINUE_SLICE: u64 = 70224;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// Accept one client on `listener` and serve it until it detaches or disconnects
+pub fn serve_tcp(debugger: &mut Debugger, listener: &TcpListener) -> io::Result<()> {
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    serve(debugger, stream)
+}
+
+/// Serve a connected client until it detaches or disconnects
+pub fn serve(debugger: &mut Debugger, stream: TcpStream) -> io::Result<()> {
+    let mut connection = Connection { stream, last: Vec::new() };
+    while let Some(packet) = connection.receive()? {
+        match command(debugger, &mut connection, &packet)? {
+            Some(reply) => connection.send(&reply)?,
+            None => {
+                connection.send("OK")?;
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// Last packet sent, resent when the client doesn't acknowledge it
+    last: Vec<u8>,
+}
+
+impl Connection {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Next packet with a valid checksum, `None` when the client is gone
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(b'-') => {
+                    self.stream.write_all(&self.last)?;
+                    continue;
+                }
+                // Acks, and interrupts received while already stopped
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+            if expected == Some(checksum_of(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        self.last = format!("${}#{:02x}", data, checksum_of(data.as_bytes())).into_bytes();
+        self.stream.write_all(&self.last)?;
+        self.stream.flush()
+    }
+
+    /// True when the client sent an interrupt (0x03) while the target was running
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = match self.stream.read(&mut byte) {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Reply to a packet, `None` ends the session
+fn command(debugger: &mut Debugger, connection: &mut Connection, packet: &str) -> io::Result<Option<String>> {
+    let (kind, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+    let reply = match kind {
+        "?" => format!("S{:02x}", SIGTRAP),
+        "g" => (0..REGISTER_COUNT).map(|n| hex(&register(debugger, n).to_le_bytes())).collect(),
+        "G" => match parse_hex(args) {
+            Some(bytes) if bytes.len() == REGISTER_COUNT * 2 => {
+                for (n, value) in bytes.chunks(2).enumerate() {
+                    set_register(debugger, n, u16::from_le_bytes([value[0], value[1]]));
+                }
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        },
+        "p" => match usize::from_str_radix(args, 16) {
+            Ok(n) if n < REGISTER_COUNT => hex(&register(debugger, n).to_le_bytes()),
+            _ => "E01".to_string(),
+        },
+        "P" => {
+            let parsed = args.split_once('=').and_then(|(n, value)| Some((usize::from_str_radix(n, 16).ok()?, parse_hex(value)?)));
+            match parsed {
+                Some((n, value)) if n < REGISTER_COUNT && value.len() == 2 => {
+                    set_register(debugger, n, u16::from_le_bytes([value[0], value[1]]));
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            }
+        }
+        "m" => match parse_range(args) {
+            Some((address, length)) => {
+                let gameboy = debugger.gameboy();
+                let bytes: Vec<u8> = (0..length).map(|i| gameboy.peek(address.wrapping_add(i))).collect();
+                hex(&bytes)
+            }
+            None => "E01".to_string(),
+        },
+        "M" => {
+            let parsed = args.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, parse_hex(data)?)));
+            match parsed {
+                Some(((address, length), data)) if data.len() == length as usize => {
+                    let gameboy = debugger.gameboy_mut();
+                    for (i, &value) in data.iter().enumerate() {
+                        gameboy.poke(address.wrapping_add(i as u16), value);
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            }
+        }
+        "Z" | "z" => breakpoint(debugger, kind == "Z", args),
+        "c" => run(debugger, connection, false)?,
+        "s" => run(debugger, connection, true)?,
+        "v" if args == "Cont?" => "vCont;c;C;s;S".to_string(),
+        "v" if args.starts_with("Cont;") => {
+            let step = matches!(args[5..].chars().next(), Some('s' | 'S'));
+            run(debugger, connection, step)?
+        }
+        "q" if args.starts_with("Supported") => "PacketSize=1000;qXfer:features:read+".to_string(),
+        "q" if args.starts_with("Xfer:features:read:target.xml:") => {
+            let range = &args["Xfer:features:read:target.xml:".len()..];
+            match range.split_once(',').and_then(|(o, l)| Some((usize::from_str_radix(o, 16).ok()?, usize::from_str_radix(l, 16).ok()?))) {
+                Some((offset, length)) => {
+                    let data = TARGET_XML.get(offset.min(TARGET_XML.len())..).unwrap_or("");
+                    match data.get(..length) {
+                        Some(chunk) if length < data.len() => format!("m{}", chunk),
+                        _ => format!("l{}", data),
+                    }
+                }
+                None => "E01".to_string(),
+            }
+        }
+        "q" if args == "Attached" => "1".to_string(),
+        "q" if args == "C" => "QC1".to_string(),
+        "q" if args == "fThreadInfo" => "m1".to_string(),
+        "q" if args == "sThreadInfo" => "l".to_string(),
+        "H" | "T" => "OK".to_string(),
+        "D" | "k" => return Ok(None),
+        // Empty reply: not supported
+        _ => String::new(),
+    };
+    Ok(Some(reply))
+}
+
+/// `Z`/`z` packets: 0 and 1 are breakpoints, 2, 3 and 4 write, read and access watchpoints
+fn breakpoint(debugger: &mut Debugger, insert: bool, args: &str) -> String {
+    let mut fields = args.split(',');
+    let (Some(kind), Some(address), Some(length)) = (fields.next(), fields.next(), fields.next()) else {
+        return "E01".to_string();
+    };
+    let (Ok(address), Ok(length)) = (u16::from_str_radix(address, 16), u16::from_str_radix(length, 16)) else {
+        return "E01".to_string();
+    };
+
+    match kind {
+        "0" | "1" => {
+            let breakpoint = Breakpoint::new(address);
+            let index = debugger.breakpoints().iter().position(|b| *b == breakpoint);
+            match (insert, index) {
+                (true, None) => { debugger.add_breakpoint(breakpoint); }
+                (false, Some(index)) => { debugger.remove_breakpoint(index); }
+                _ => {}
+            }
+        }
+        "2" | "3" | "4" => {
+            let watchpoint = Watchpoint {
+                start: address,
+                end: address.wrapping_add(length.max(1) - 1),
+                read: kind != "2",
+                write: kind != "3",
+            };
+            let index = debugger.watchpoints().iter().position(|w| *w == watchpoint);
+            match (insert, index) {
+                (true, None) => { debugger.add_watchpoint(watchpoint); }
+                (false, Some(index)) => { debugger.remove_watchpoint(index); }
+                _ => {}
+            }
+        }
+        _ => return String::new(),
+    }
+    "OK".to_string()
+}
+
+/// Step or continue, then build the stop reply
+fn run(debugger: &mut Debugger, connection: &mut Connection, step: bool) -> io::Result<String> {
+    let reason = if step {
+        debugger.step()
+    } else {
+        loop {
+            match debugger.resume(Some(CONTINUE_SLICE)) {
+                StopReason::Limit if connection.interrupted()? => return Ok(format!("S{:02x}", SIGINT)),
+                StopReason::Limit => {}
+                reason => break reason,
+            }
+        }
+    };
+
+    Ok(match reason {
+        StopReason::Watchpoint(hit) => {
+            let kind = match debugger.watchpoints().iter().find(|w| w.matches(hit.address, hit.write)) {
+                Some(w) if w.read && w.write => "awatch",
+                _ if hit.write => "watch",
+                _ => "rwatch",
+            };
+            format!("T{:02x}{}:{:04x};", SIGTRAP, kind, hit.address)
+        }
+        _ => format!("S{:02x}", SIGTRAP),
+    })
+}
+
+fn register(debugger: &Debugger, n: usize) -> u16 {
+    let registers = debugger.registers();
+    match n {
+        0 => registers.af(),
+        1 => registers.bc(),
+        2 => registers.de(),
+        3 => registers.hl(),
+        4 => registers.sp,
+        _ => registers.pc,
+    }
+}
+
+fn set_register(debugger: &mut Debugger, n: usize, value: u16) {
+    let registers = &mut debugger.gameboy_mut().cpu.registers;
+    match n {
+        0 => registers.set_af(value),
+        1 => registers.set_bc(value),
+        2 => registers.set_de(value),
+        3 => registers.set_hl(value),
+        4 => registers.sp = value,
+        _ => registers.pc = value,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// `addr,length`
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let (address, length) = text.split_once(',')?;
+    Some((u16::from_str_radix(address, 16).ok()?, u16::from_str_radix(length, 16).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::tests::debugger;
+
+    /// Send each packet and return the replies, as GDB would
+    fn client(port: u16, packets: &'static [&'static str]) -> Vec<String> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut replies = Vec::new();
+        for packet in packets {
+            write!(stream, "${}#{:02x}", packet, checksum_of(packet.as_bytes())).unwrap();
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'+', "packet {} not acknowledged", packet);
+            loop {
+                stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            stream.read_exact(&mut checksum).unwrap();
+            assert_eq!(reply[0], b'$');
+            assert_eq!(u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap(), checksum_of(&reply[1..]));
+            stream.write_all(b"+").unwrap();
+            replies.push(String::from_utf8(reply[1..].to_vec()).unwrap());
+        }
+        replies
+    }
+
+    #[test]
+    fn test_session() {
+        // Breaks after the CALL at 0x0150, then on the write of the subroutine at 0x0200
+        let mut debugger = debugger();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = std::thread::spawn(move || {
+            client(port, &[
+                "qSupported:swbreak+", "?", "g", "m200,4", "Z0,153,1", "c", "p5",
+                "z0,153,1", "Z2,c000,1", "c", "mc000,1", "Mc000,1:42", "mc000,1",
+                "P5=0001", "s", "p5", "qXfer:features:read:target.xml:0,10", "D",
+            ])
+        });
+        serve_tcp(&mut debugger, &listener).unwrap();
+        let replies = client.join().unwrap();
+
+        assert_eq!(replies, [
+            "PacketSize=1000;qXfer:features:read+", "S05", "b0011300d8004d01feff0001", "ea00c0c9", "OK", "S05", "5301",
+            "OK", "OK", "T05watch:c000;", "02", "OK", "42",
+            "OK", "S05", "5001", "m<?xml version=\"1", "OK",
+        ]);
+    }
+}
@@ -1,13 +1,13 @@
 use crate::gameboy::GBMode;
+use crate::image::PixelFormat;
+use crate::palette::{self, Palettes};
 
-const SCREEN_WIDTH: usize = 160;
-const SCREEN_HEIGHT: usize = 144;
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
 
 const VRAM_SIZE: usize = 0x2000;
 const OAM_SIZE: usize = 0xA0;
 
-pub const SCREEN_SIZE_RGB: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 3;
-
 #[derive(PartialEq)]
 enum Mode {
     HBlank, // 204 cycles : termine le rendu d'une ligne horizontale et attend la prochaine ligne à dessiner
@@ -28,8 +28,9 @@ pub struct GPU {
     mode: Mode,
     clock: u32,
     pub interrupt: u8,
+    pub frame_ready: bool,  // Set when the PPU enters VBlank, a full frame is in screen_data
 
-    vram: [u8; VRAM_SIZE],
+    vram: [u8; VRAM_SIZE * 2], // Bank 1 is only reachable in CGB mode
     oam: [u8; OAM_SIZE],
 
     lcdc: u8, // 0xff40 LCD Control (LCDC)
@@ -46,7 +47,9 @@ pub struct GPU {
     wx: u8,   // 0xff4b WX -- Window X Position
     pub vram_bank: u8,
 
-    screen_data: [u8; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+    screen_data: Vec<u8>,
+    pixel_format: PixelFormat,
+    palettes: Palettes, // Colours of the DMG shades written in screen_data
     gb_mode: GBMode,
 }
 
@@ -56,7 +59,8 @@ impl GPU {
             mode: Mode::OAM,
             clock: 0,
             interrupt: 0,
-            vram: [0; VRAM_SIZE],
+            frame_ready: false,
+            vram: [0; VRAM_SIZE * 2],
             oam: [0; OAM_SIZE],
             vram_bank: 0,
             lcdc: 0,
@@ -71,53 +75,57 @@ impl GPU {
             obp1: 0,
             wy: 0,
             wx: 0,
-            screen_data: [0; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+            screen_data: vec![0xFF; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+            pixel_format: PixelFormat::Rgb24,
+            palettes: Palettes::uniform(palette::GREY),
             gb_mode: GBMode::DMG,
         }
     }
 
-    pub fn step(&mut self, cycles: u8) {
-        self.clock += cycles as u32;
+    /// Advance the PPU, any number of mode changes can happen during the given cycles
+    pub fn step(&mut self, cycles: u32) {
+        self.clock += cycles;
+
+        while self.clock >= self.mode_duration() {
+            self.clock -= self.mode_duration();
+            self.next_mode();
+        }
+    }
+
+    fn mode_duration(&self) -> u32 {
+        match self.mode {
+            Mode::HBlank => 204,
+            Mode::VBlank => 456, // For each line
+            Mode::OAM => 80,
+            Mode::DRAWING => 172,
+        }
+    }
 
+    fn next_mode(&mut self) {
         match self.mode {
             Mode::HBlank => {
-                if self.clock >= 204 {
-                    self.clock -= 204;
-                    self.ly += 1;
-                    self.check_interrupt_lyc();
-    
-                    if self.ly == SCREEN_HEIGHT as u8 {
-                        self.mode = Mode::VBlank;
-                        self.interrupt = 0x01;
-                        // Début de VBlank, appeler la routine d'interruption ici si nécessaire
-                    } else {
-                        self.mode = Mode::OAM;
-                    }
+                self.ly += 1;
+                if self.ly == SCREEN_HEIGHT as u8 {
+                    self.mode = Mode::VBlank;
+                    self.interrupt |= 0x01;
+                    self.frame_ready = true;
+                } else {
+                    self.mode = Mode::OAM;
                 }
             }
             Mode::VBlank => {
-                if self.clock >= 456 {
-                    self.clock -= 456;
-                    self.ly += 1;
-    
-                    if self.ly > 153 {
-                        self.ly = 0;
-                        self.mode = Mode::OAM;
-                    }
+                self.ly += 1;
+                if self.ly > 153 {
+                    self.ly = 0;
+                    self.mode = Mode::OAM;
                 }
             }
             Mode::OAM => {
-                if self.clock >= 80 {
-                    self.clock -= 80;
-                    self.mode = Mode::DRAWING;
-                }
+                self.mode = Mode::DRAWING;
             }
             Mode::DRAWING => {
-                if self.clock >= 172 {
-                    self.clock -= 172;
-                    self.mode = Mode::HBlank;
-                    self.render_scanline();
-                }
+                self.mode = Mode::HBlank;
+                self.render_scanline();
             }
         }
 
@@ -126,15 +134,23 @@ impl GPU {
         } else {
             self.stat &= !0x04;
         }
+    }
 
-        if self.ly >= 144 {
-            self.mode = Mode::VBlank;
+    /// Cycles left before the next VBlank interrupt
+    pub fn cycles_until_vblank(&self) -> u32 {
+        let line = 456;
+        let visible_lines = SCREEN_HEIGHT as u32 - 1;
+        let ly = self.ly as u32;
+        match self.mode {
+            Mode::VBlank => 153u32.saturating_sub(ly) * line + (line - self.clock) + SCREEN_HEIGHT as u32 * line,
+            Mode::OAM => (80 - self.clock) + 172 + 204 + visible_lines.saturating_sub(ly) * line,
+            Mode::DRAWING => (172 - self.clock) + 204 + visible_lines.saturating_sub(ly) * line,
+            Mode::HBlank => (204 - self.clock) + visible_lines.saturating_sub(ly) * line,
         }
+    }
 
-        if self.mode == Mode::VBlank && self.ly == 153 {
-            self.ly = 0;
-            self.mode = Mode::OAM;
-        }
+    pub fn set_mode(&mut self, mode: GBMode) {
+        self.gb_mode = mode;
     }
 
     pub fn render_scanline(&mut self) {
@@ -143,10 +159,32 @@ impl GPU {
     }
 
     #[inline(always)]
-    pub fn screen_data(&self) -> &[u8; SCREEN_WIDTH * SCREEN_HEIGHT * 3] {
+    pub fn screen_data(&self) -> &[u8] {
         &self.screen_data
     }
 
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    /// Resize the framebuffer for the new format, it is cleared to white
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.pixel_format = format;
+        self.screen_data = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * format.bytes_per_pixel()];
+        for pixel in self.screen_data.chunks_mut(format.bytes_per_pixel()) {
+            format.encode([0xFF; 3], pixel);
+        }
+    }
+
+    pub fn palettes(&self) -> &Palettes {
+        &self.palettes
+    }
+
+    /// Only the pixels drawn after the change use the new colours
+    pub fn set_palettes(&mut self, palettes: Palettes) {
+        self.palettes = palettes;
+    }
+
     pub fn draw_tiles(&mut self) {
         let _win_on = self.lcdc & 0x20 == 0x20;
         let _bg_on = self.lcdc & 0x01 == 0x01;
@@ -180,7 +218,7 @@ impl GPU {
             };
 
             let tile_addr = tilemap_addr + tile_y as u16 * 32 + tile_x as u16;
-            let tile_num = self.read_vram(tile_addr) as u16;
+            let tile_num = self.read_vram_bank(0, tile_addr) as u16;
 
             let tile_data_addr = if tilemap_addr == 0x9800 {
                 0x8000 + tile_num * 16
@@ -188,18 +226,18 @@ impl GPU {
                 0x8800 + ((tile_num as i8 as i16 + 128) * 16) as u16
             };
 
-            let low_byte = self.read_vram(tile_data_addr + pixel_y as u16 * 2);
-            let high_byte = self.read_vram(tile_data_addr + pixel_y as u16 * 2 + 1);
+            let low_byte = self.read_vram_bank(0, tile_data_addr + pixel_y as u16 * 2);
+            let high_byte = self.read_vram_bank(0, tile_data_addr + pixel_y as u16 * 2 + 1);
 
             let color_bit = if true { 7 - pixel_x } else { pixel_x };
             let color_id = ((high_byte >> color_bit) & 0x1) << 1 | ((low_byte >> color_bit) & 0x1);
 
             if self.gb_mode == GBMode::CGB {
                 let color = 0x00; // TD
-                self.set_color(x, color);
+                self.set_color(x, color, 0);
             } else {
                 let color = self.get_monochrome_color(color_id, self.bgp);
-                self.set_color(x, color);
+                self.set_color(x, color, 0);
             }
         }
     }
@@ -253,44 +291,81 @@ impl GPU {
                 line - sprite.y
             };
             let tile_addr = 0x8000 + sprite.tile as u16 * 16 + tile_y as u16 * 2;
-            let low_byte = self.read_vram(tile_addr);
-            let high_byte = self.read_vram(tile_addr + 1);
+            let low_byte = self.read_vram_bank(0, tile_addr);
+            let high_byte = self.read_vram_bank(0, tile_addr + 1);
 
             for x in 0..8 {
                 let tile_x = if flip_x { x } else { 7 - x };
                 let color_bit = tile_x;
                 let color_id = ((high_byte >> color_bit) & 0x1) << 1 | ((low_byte >> color_bit) & 0x1);
-                let color = match color_id {
-                    0 => self.obp0 & 0x03,
-                    1 => (self.obp0 >> 2) & 0x03,
-                    2 => (self.obp0 >> 4) & 0x03,
-                    3 => (self.obp0 >> 6) & 0x03,
-                    _ => 0,
+                // Colour 0 is transparent for objects
+                if color_id == 0 { continue; }
+                let (obp, palette) = if sprite.flags & 0x10 == 0x10 {
+                    (self.obp1, 2)
+                } else {
+                    (self.obp0, 1)
                 };
+                let color = self.get_monochrome_color(color_id, obp);
 
                 let x_pos = sprite.x.wrapping_add(x);
                 if x_pos >= SCREEN_WIDTH as u8 { continue; }
                 if sprite.flags & 0x80 == 0x80 && self.lcdc & 0x20 == 0x20 { continue; }
                 
-                self.screen_data[(self.ly as usize * SCREEN_WIDTH + x_pos as usize) * 3] = color;
-                self.screen_data[(self.ly as usize * SCREEN_WIDTH + x_pos as usize) * 3 + 1] = color;
-                self.screen_data[(self.ly as usize * SCREEN_WIDTH + x_pos as usize) * 3 + 2] = color;
+                self.set_color(x_pos as usize, color, palette);
             }
         }
     }
 
-    pub fn set_color(&mut self, x: usize, color: u8) {
-        let index = self.ly as usize * SCREEN_WIDTH * 3 + x * 3;
+    /// Write a shade (0-3) on the current line, `palette` is 0 for the background, 1 for OBJ0 and 2 for OBJ1
+    pub fn set_color(&mut self, x: usize, color: u8, palette: u8) {
+        let size = self.pixel_format.bytes_per_pixel();
+        let index = (self.ly as usize * SCREEN_WIDTH + x) * size;
         assert!(color < 4);
-        assert!(index < SCREEN_HEIGHT * SCREEN_WIDTH * 3);
-        self.screen_data[index] = color;
-        self.screen_data[index + 1] = color;
-        self.screen_data[index + 2] = color;
+        assert!(index < self.screen_data.len());
+        let colors = match palette {
+            1 => self.palettes.obj0,
+            2 => self.palettes.obj1,
+            _ => self.palettes.bg,
+        };
+        match self.pixel_format {
+            PixelFormat::Indexed => self.screen_data[index] = palette << 2 | color,
+            format => format.encode(colors[color as usize], &mut self.screen_data[index..index + size]),
+        }
     }
 
+    /// VRAM is locked for the CPU while the PPU is drawing (mode 3)
+    /// Everything is accessible when the LCD is off
+    #[inline(always)]
+    pub fn vram_accessible(&self) -> bool {
+        self.lcdc & 0x80 == 0 || self.mode != Mode::DRAWING
+    }
+
+    /// OAM is locked for the CPU during the OAM scan (mode 2) and while drawing (mode 3)
+    #[inline(always)]
+    pub fn oam_accessible(&self) -> bool {
+        self.lcdc & 0x80 == 0 || (self.mode != Mode::OAM && self.mode != Mode::DRAWING)
+    }
+
+    /// Number of VRAM banks of the emulated model
+    pub fn vram_banks(&self) -> usize {
+        if self.gb_mode == GBMode::CGB { 2 } else { 1 }
+    }
+
+    /// Offset of the bank selected with VBK (0xFF4F)
+    #[inline(always)]
+    fn vram_offset(&self) -> usize {
+        if self.gb_mode == GBMode::CGB { (self.vram_bank as usize & 1) * VRAM_SIZE } else { 0 }
+    }
+
+    /// VRAM as seen by the CPU, in the selected bank
     #[inline(always)]
     pub fn read_vram(&self, address: u16) -> u8 {
-        self.vram[address as usize & 0x1FFF]
+        self.vram[self.vram_offset() + (address as usize & 0x1FFF)]
+    }
+
+    #[inline(always)]
+    pub fn read_vram_bank(&self, bank: usize, address: u16) -> u8 {
+        self.vram[bank * VRAM_SIZE + (address as usize & 0x1FFF)]
     }
 
     fn check_interrupt_lyc(&mut self) {
@@ -326,7 +401,7 @@ impl GPU {
 
     #[inline(always)]
     pub fn write_vram(&mut self, address: u16, value: u8) {
-        self.vram[address as usize] = value;
+        self.vram[self.vram_offset() + address as usize] = value;
     }
 
     #[inline(always)]
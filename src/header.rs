@@ -1,10 +1,49 @@
-use std::ffi::CStr;
+use std::fmt;
+
+use crate::gameboy::GBMode;
+
+/// Largest ROM the header can describe, 8 MiB
+pub(crate) const MAX_ROM_SIZE: usize = 0x8000 << 8;
+
+/// Logo the boot ROM compares with 0x0104-0x0133 before starting the cartridge
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// Error found while parsing or validating the cartridge header
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    TooSmall(usize),
+    BadLogo,
+    BadHeaderChecksum { expected: u8, computed: u8 },
+    BadGlobalChecksum { expected: u16, computed: u16 },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::TooSmall(size) => write!(f, "ROM is too small to have a header: {} bytes", size),
+            HeaderError::BadLogo => write!(f, "Nintendo logo doesn't match"),
+            HeaderError::BadHeaderChecksum { expected, computed } => {
+                write!(f, "header checksum is {:#04x}, computed {:#04x}", expected, computed)
+            }
+            HeaderError::BadGlobalChecksum { expected, computed } => {
+                write!(f, "global checksum is {:#06x}, computed {:#06x}", expected, computed)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
 
 pub struct Header {
+    logo: [u8; 48],
     title: String,
     manufacturer_code: String,
     cgb_flag: u8,
-    new_licensee_code: u8,
+    new_licensee_code: String,
     sgb_flag: u8,
     cartridge_type: u8,
     rom_size: u8,
@@ -13,22 +52,58 @@ pub struct Header {
     old_licensee_code: u8,
     mask_rom_version_number: u8,
     header_checksum: u8,
-    global_checksum: u16
+    global_checksum: u16,
+
+    computed_header_checksum: u8,
+    computed_global_checksum: u16,
 }
 
+/// Printable part of a text field, up to the first null byte
+fn read_text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// Checksum computed by the boot ROM over 0x0134-0x014C
+pub fn compute_header_checksum(rom: &[u8]) -> u8 {
+    rom[0x0134..=0x014C].iter().fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1))
+}
 
+/// Sum of every byte of the ROM except the global checksum itself
+pub fn compute_global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 0x014E && i != 0x014F)
+        .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16))
+}
 
 impl Header {
-    pub fn load_rom(header: &[u8]) -> Self {
-        Header {
-            title: CStr::from_bytes_until_nul(&header[0x0134..0x0143])
-                .unwrap().to_str()
-                .unwrap().to_string(),
-            manufacturer_code: CStr::from_bytes_until_nul(&header[0x013F..0x0143])
-                .unwrap().to_str()
-                .unwrap().to_string(),
+    pub fn load_rom(header: &[u8]) -> Result<Self, HeaderError> {
+        if header.len() < 0x0150 {
+            return Err(HeaderError::TooSmall(header.len()));
+        }
+
+        // The title takes 16 bytes on DMG cartridges, 0x0143 is the CGB flag on newer ones
+        // which can also use the end of the title for a 4 characters manufacturer code
+        let title_end = if matches!(header[0x0143], 0x80 | 0xC0) { 0x0143 } else { 0x0144 };
+        let title = read_text(&header[0x0134..title_end]);
+        let title_ends_early = header[0x0134..0x013F].contains(&0);
+        let manufacturer_code = &header[0x013F..0x0143];
+        let manufacturer_code = if title_ends_early && manufacturer_code.iter().all(u8::is_ascii_alphanumeric) {
+            read_text(manufacturer_code)
+        } else {
+            String::new()
+        };
+
+        let mut logo = [0; 48];
+        logo.copy_from_slice(&header[0x0104..0x0134]);
+
+        Ok(Header {
+            logo,
+            title,
+            manufacturer_code,
             cgb_flag: header[0x0143],
-            new_licensee_code: header[0x0144],
+            new_licensee_code: read_text(&header[0x0144..0x0146]),
             sgb_flag: header[0x0146],
             cartridge_type: header[0x0147],
             rom_size: header[0x0148],
@@ -37,8 +112,52 @@ impl Header {
             old_licensee_code: header[0x014B],
             mask_rom_version_number: header[0x014C],
             header_checksum: header[0x014D],
-            global_checksum: (header[0x014E] as u16) << 8 | header[0x014F] as u16
+            global_checksum: (header[0x014E] as u16) << 8 | header[0x014F] as u16,
+
+            computed_header_checksum: compute_header_checksum(header),
+            computed_global_checksum: compute_global_checksum(header),
+        })
+    }
+
+    /// Check both checksums. Only the header checksum is verified by the boot ROM,
+    /// a bad global checksum doesn't prevent a game from running on hardware.
+    pub fn validate(&self) -> Result<(), HeaderError> {
+        if !self.header_checksum_valid() {
+            return Err(HeaderError::BadHeaderChecksum { expected: self.header_checksum, computed: self.computed_header_checksum });
+        }
+        if !self.global_checksum_valid() {
+            return Err(HeaderError::BadGlobalChecksum { expected: self.global_checksum, computed: self.computed_global_checksum });
         }
+        Ok(())
+    }
+
+    /// Checks done by the boot ROM, which locks up when they fail.
+    /// The CGB only compares the first half of the logo.
+    pub fn check_boot(&self, mode: GBMode) -> Result<(), HeaderError> {
+        if !self.logo_valid(mode) {
+            return Err(HeaderError::BadLogo);
+        }
+        if !self.header_checksum_valid() {
+            return Err(HeaderError::BadHeaderChecksum { expected: self.header_checksum, computed: self.computed_header_checksum });
+        }
+        Ok(())
+    }
+
+    pub fn logo_valid(&self, mode: GBMode) -> bool {
+        let len = if mode == GBMode::CGB { 0x18 } else { 0x30 };
+        self.logo[..len] == NINTENDO_LOGO[..len]
+    }
+
+    pub fn header_checksum_valid(&self) -> bool {
+        self.header_checksum == self.computed_header_checksum
+    }
+
+    pub fn global_checksum_valid(&self) -> bool {
+        self.global_checksum == self.computed_global_checksum
+    }
+
+    pub fn logo(&self) -> &[u8; 48] {
+        &self.logo
     }
 
     pub fn title(&self) -> &str {
@@ -53,19 +172,386 @@ impl Header {
         self.cgb_flag
     }
 
+    pub fn new_licensee_code(&self) -> &str {
+        &self.new_licensee_code
+    }
+
+    pub fn sgb_flag(&self) -> u8 {
+        self.sgb_flag
+    }
+
+    pub fn cartridge_type(&self) -> u8 {
+        self.cartridge_type
+    }
+
+    pub fn rom_size(&self) -> u8 {
+        self.rom_size
+    }
+
+    pub fn ram_size(&self) -> u8 {
+        self.ram_size
+    }
+
+    pub fn destination_code(&self) -> u8 {
+        self.destination_code
+    }
+
+    pub fn old_licensee_code(&self) -> u8 {
+        self.old_licensee_code
+    }
+
+    pub fn mask_rom_version_number(&self) -> u8 {
+        self.mask_rom_version_number
+    }
+
+    pub fn header_checksum(&self) -> u8 {
+        self.header_checksum
+    }
+
+    pub fn global_checksum(&self) -> u16 {
+        self.global_checksum
+    }
+
+    /// The CGB can use its colour features
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    /// The game doesn't run on DMG
+    pub fn cgb_only(&self) -> bool {
+        self.cgb_flag == 0xC0
+    }
+
+    /// SGB functions are only available with the old licensee code 0x33
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee_code == 0x33
+    }
+
+    /// `None` for an invalid size code
+    pub fn rom_size_bytes(&self) -> Option<usize> {
+        match self.rom_size {
+            0x00..=0x08 => Some(0x8000 << self.rom_size),
+            0x52 => Some(72 * 0x4000),
+            0x53 => Some(80 * 0x4000),
+            0x54 => Some(96 * 0x4000),
+            _ => None,
+        }
+    }
+
+    /// `None` for an invalid size code
+    pub fn ram_size_bytes(&self) -> Option<usize> {
+        match self.ram_size {
+            0x00 => Some(0),
+            0x01 => Some(0x800),
+            0x02 => Some(0x2000),
+            0x03 => Some(0x8000),
+            0x04 => Some(0x20000),
+            0x05 => Some(0x10000),
+            _ => None,
+        }
+    }
+
+    pub fn destination(&self) -> &'static str {
+        match self.destination_code {
+            0x00 => "Japan",
+            0x01 => "Overseas",
+            _ => "Unknown",
+        }
+    }
+
+    pub fn cartridge_type_name(&self) -> &'static str {
+        match self.cartridge_type {
+            0x00 => "ROM ONLY",
+            0x01 => "MBC1",
+            0x02 => "MBC1+RAM",
+            0x03 => "MBC1+RAM+BATTERY",
+            0x05 => "MBC2",
+            0x06 => "MBC2+BATTERY",
+            0x08 => "ROM+RAM",
+            0x09 => "ROM+RAM+BATTERY",
+            0x0B => "MMM01",
+            0x0C => "MMM01+RAM",
+            0x0D => "MMM01+RAM+BATTERY",
+            0x0F => "MBC3+TIMER+BATTERY",
+            0x10 => "MBC3+TIMER+RAM+BATTERY",
+            0x11 => "MBC3",
+            0x12 => "MBC3+RAM",
+            0x13 => "MBC3+RAM+BATTERY",
+            0x19 => "MBC5",
+            0x1A => "MBC5+RAM",
+            0x1B => "MBC5+RAM+BATTERY",
+            0x1C => "MBC5+RUMBLE",
+            0x1D => "MBC5+RUMBLE+RAM",
+            0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+            0x20 => "MBC6",
+            0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+            0xFC => "POCKET CAMERA",
+            0xFD => "BANDAI TAMA5",
+            0xFE => "HuC3",
+            0xFF => "HuC1+RAM+BATTERY",
+            _ => "Unknown",
+        }
+    }
+
+    /// Publisher, from the old licensee code or the new one when the old code is 0x33
+    pub fn licensee(&self) -> &'static str {
+        if self.old_licensee_code == 0x33 {
+            new_licensee(&self.new_licensee_code)
+        } else {
+            old_licensee(self.old_licensee_code)
+        }
+    }
+}
+
+fn new_licensee(code: &str) -> &'static str {
+    match code {
+        "00" => "None",
+        "01" => "Nintendo R&D1",
+        "08" => "Capcom",
+        "13" => "Electronic Arts",
+        "18" => "Hudson Soft",
+        "19" => "b-ai",
+        "20" => "kss",
+        "22" => "pow",
+        "24" => "PCM Complete",
+        "25" => "san-x",
+        "28" => "Kemco Japan",
+        "29" => "seta",
+        "30" => "Viacom",
+        "31" => "Nintendo",
+        "32" => "Bandai",
+        "33" => "Ocean/Acclaim",
+        "34" => "Konami",
+        "35" => "Hector",
+        "37" => "Taito",
+        "38" => "Hudson",
+        "39" => "Banpresto",
+        "41" => "Ubi Soft",
+        "42" => "Atlus",
+        "44" => "Malibu",
+        "46" => "angel",
+        "47" => "Bullet-Proof",
+        "49" => "irem",
+        "50" => "Absolute",
+        "51" => "Acclaim",
+        "52" => "Activision",
+        "53" => "American sammy",
+        "54" => "Konami",
+        "55" => "Hi tech entertainment",
+        "56" => "LJN",
+        "57" => "Matchbox",
+        "58" => "Mattel",
+        "59" => "Milton Bradley",
+        "60" => "Titus",
+        "61" => "Virgin",
+        "64" => "LucasArts",
+        "67" => "Ocean",
+        "69" => "Electronic Arts",
+        "70" => "Infogrames",
+        "71" => "Interplay",
+        "72" => "Broderbund",
+        "73" => "sculptured",
+        "75" => "sci",
+        "78" => "THQ",
+        "79" => "Accolade",
+        "80" => "misawa",
+        "83" => "lozc",
+        "86" => "Tokuma Shoten Intermedia",
+        "87" => "Tsukuda Original",
+        "91" => "Chunsoft",
+        "92" => "Video system",
+        "93" => "Ocean/Acclaim",
+        "95" => "Varie",
+        "96" => "Yonezawa/s'pal",
+        "97" => "Kaneko",
+        "99" => "Pack in soft",
+        "9H" => "Bottom Up",
+        "A4" => "Konami (Yu-Gi-Oh!)",
+        _ => "Unknown",
+    }
+}
 
-    
+fn old_licensee(code: u8) -> &'static str {
+    match code {
+        0x00 => "None",
+        0x01 | 0x31 => "Nintendo",
+        0x08 | 0x38 => "Capcom",
+        0x09 => "Hot-B",
+        0x0A | 0xE0 => "Jaleco",
+        0x0B => "Coconuts Japan",
+        0x0C | 0x6E => "Elite Systems",
+        0x13 | 0x69 => "Electronic Arts",
+        0x18 => "Hudsonsoft",
+        0x19 => "ITC Entertainment",
+        0x1A => "Yanoman",
+        0x1D => "Japan Clary",
+        0x1F | 0x4A | 0x61 => "Virgin Interactive",
+        0x24 => "PCM Complete",
+        0x25 => "San-X",
+        0x28 => "Kotobuki Systems",
+        0x29 => "Seta",
+        0x30 | 0x70 => "Infogrames",
+        0x32 | 0xA2 | 0xB2 => "Bandai",
+        0x34 | 0xA4 => "Konami",
+        0x35 => "HectorSoft",
+        0x39 | 0x9D | 0xD9 => "Banpresto",
+        0x3C => "Entertainment i",
+        0x3E => "Gremlin",
+        0x41 => "Ubisoft",
+        0x42 | 0xEB => "Atlus",
+        0x44 | 0x4D => "Malibu",
+        0x46 | 0xCF => "Angel",
+        0x47 => "Spectrum Holoby",
+        0x49 => "Irem",
+        0x4F => "U.S. Gold",
+        0x50 => "Absolute",
+        0x51 | 0xB0 => "Acclaim",
+        0x52 => "Activision",
+        0x53 => "American Sammy",
+        0x54 => "GameTek",
+        0x55 => "Park Place",
+        0x56 | 0xDB | 0xFF => "LJN",
+        0x57 => "Matchbox",
+        0x59 => "Milton Bradley",
+        0x5A => "Mindscape",
+        0x5B => "Romstar",
+        0x5C | 0xD6 => "Naxat Soft",
+        0x5D => "Tradewest",
+        0x60 => "Titus",
+        0x67 => "Ocean Interactive",
+        0x6F => "Electro Brain",
+        0x71 => "Interplay",
+        0x72 | 0xAA => "Broderbund",
+        0x73 => "Sculptered Soft",
+        0x75 => "The Sales Curve",
+        0x78 => "THQ",
+        0x79 => "Accolade",
+        0x7A => "Triffix Entertainment",
+        0x7C => "Microprose",
+        0x7F | 0xC2 => "Kemco",
+        0x80 => "Misawa Entertainment",
+        0x83 => "Lozc",
+        0x86 | 0xC4 => "Tokuma Shoten Intermedia",
+        0x8B => "Bullet-Proof Software",
+        0x8C => "Vic Tokai",
+        0x8E => "Ape",
+        0x8F => "I'Max",
+        0x91 => "Chunsoft",
+        0x92 => "Video System",
+        0x93 => "Tsubaraya Productions",
+        0x95 | 0xE3 => "Varie",
+        0x96 => "Yonezawa/S'Pal",
+        0x97 => "Kaneko",
+        0x99 => "Arc",
+        0x9A => "Nihon Bussan",
+        0x9B => "Tecmo",
+        0x9C => "Imagineer",
+        0x9F => "Nova",
+        0xA1 => "Hori Electric",
+        0xA6 => "Kawada",
+        0xA7 => "Takara",
+        0xA9 => "Technos Japan",
+        0xAC => "Toei Animation",
+        0xAD => "Toho",
+        0xAF => "Namco",
+        0xB1 => "ASCII or Nexsoft",
+        0xB4 => "Square Enix",
+        0xB6 => "HAL Laboratory",
+        0xB7 => "SNK",
+        0xB9 | 0xCE => "Pony Canyon",
+        0xBA => "Culture Brain",
+        0xBB => "Sunsoft",
+        0xBD => "Sony Imagesoft",
+        0xBF => "Sammy",
+        0xC0 | 0xD0 => "Taito",
+        0xC3 => "Squaresoft",
+        0xC5 => "Data East",
+        0xC6 => "Tonkinhouse",
+        0xC8 => "Koei",
+        0xC9 => "UFL",
+        0xCA => "Ultra",
+        0xCB => "Vap",
+        0xCC => "Use Corporation",
+        0xCD => "Meldac",
+        0xD1 => "Sofel",
+        0xD2 => "Quest",
+        0xD3 => "Sigma Enterprises",
+        0xD4 => "ASK Kodansha",
+        0xD7 => "Copya System",
+        0xDA => "Tomy",
+        0xDD => "NCS",
+        0xDE => "Human",
+        0xDF => "Altron",
+        0xE1 => "Towa Chiki",
+        0xE2 => "Yutaka",
+        0xE5 => "Epoch",
+        0xE7 => "Athena",
+        0xE8 => "Asmik ACE Entertainment",
+        0xE9 => "Natsume",
+        0xEA => "King Records",
+        0xEC => "Epic/Sony Records",
+        0xEE => "IGS",
+        0xF0 => "A Wave",
+        0xF3 => "Extreme Entertainment",
+        _ => "Unknown",
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = |bytes: Option<usize>| match bytes {
+            Some(bytes) => format!("{} KiB", bytes / 1024),
+            None => "Invalid".to_string(),
+        };
+        let status = |valid: bool| if valid { "OK" } else { "BAD" };
+
+        writeln!(f, "Title:           {}", self.title)?;
+        if !self.manufacturer_code.is_empty() {
+            writeln!(f, "Manufacturer:    {}", self.manufacturer_code)?;
+        }
+        writeln!(f, "Licensee:        {}", self.licensee())?;
+        writeln!(f, "Cartridge:       {} ({:#04x})", self.cartridge_type_name(), self.cartridge_type)?;
+        writeln!(f, "ROM size:        {}", size(self.rom_size_bytes()))?;
+        writeln!(f, "RAM size:        {}", size(self.ram_size_bytes()))?;
+        writeln!(f, "Destination:     {}", self.destination())?;
+        writeln!(f, "CGB:             {}", if self.cgb_only() { "Only" } else if self.supports_cgb() { "Yes" } else { "No" })?;
+        writeln!(f, "SGB:             {}", if self.supports_sgb() { "Yes" } else { "No" })?;
+        writeln!(f, "Version:         {}", self.mask_rom_version_number)?;
+        writeln!(f, "Logo:            {}", status(self.logo_valid(GBMode::DMG)))?;
+        writeln!(f, "Header checksum: {:#04x} ({})", self.header_checksum, status(self.header_checksum_valid()))?;
+        write!(f, "Global checksum: {:#06x} ({})", self.global_checksum, status(self.global_checksum_valid()))
+    }
 }
 
+/// Write the logo and header checksum so the ROM passes `Header::check_boot`
+#[cfg(test)]
+pub(crate) fn make_bootable(rom: &mut [u8]) {
+    rom[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x014D] = compute_header_checksum(rom);
+}
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use crate::gameboy::Gameboy;
 
+    /// ROM with a valid header and global checksum
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x13A].copy_from_slice(b"TETRIS");
+        rom[0x14B] = 0x01;
+        make_bootable(&mut rom);
+        let global = compute_global_checksum(&rom);
+        rom[0x14E] = (global >> 8) as u8;
+        rom[0x14F] = global as u8;
+        rom
+    }
+
     #[test]
     fn test_header() {
-        let mut gb = Gameboy::new();
-        gb.load_rom_from_filename("roms/tetris.gb");
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x13A].copy_from_slice(b"TETRIS");
+        let gb = Gameboy::new(&rom).unwrap();
 
         let header = gb.header();
 
@@ -73,4 +559,75 @@ mod tests {
         assert_eq!(header.manufacturer_code(), "");
         assert_eq!(header.cgb_flag(), 0x00);
     }
+
+    #[test]
+    fn test_header_non_utf8_title() {
+        let mut rom = rom();
+        rom[0x134..0x137].copy_from_slice(&[b'A', 0xFF, b'B']);
+        let header = Header::load_rom(&rom).unwrap();
+        assert_eq!(header.title(), "A\u{FFFD}BRIS");
+    }
+
+    #[test]
+    fn test_header_too_small() {
+        assert_eq!(Header::load_rom(&[0; 0x100]).err(), Some(HeaderError::TooSmall(0x100)));
+    }
+
+    #[test]
+    fn test_header_checksums() {
+        let mut rom = rom();
+        assert_eq!(Header::load_rom(&rom).unwrap().validate(), Ok(()));
+
+        rom[0x4000] = 0x01;
+        let header = Header::load_rom(&rom).unwrap();
+        assert!(header.header_checksum_valid());
+        assert!(!header.global_checksum_valid());
+
+        rom[0x14D] ^= 0xFF;
+        let header = Header::load_rom(&rom).unwrap();
+        assert!(matches!(header.validate(), Err(HeaderError::BadHeaderChecksum { .. })));
+    }
+
+    #[test]
+    fn test_header_check_boot() {
+        let mut rom = rom();
+        assert_eq!(Header::load_rom(&rom).unwrap().check_boot(GBMode::DMG), Ok(()));
+
+        // Only the DMG checks the second half of the logo
+        rom[0x0130] ^= 0xFF;
+        let header = Header::load_rom(&rom).unwrap();
+        assert_eq!(header.check_boot(GBMode::DMG), Err(HeaderError::BadLogo));
+        assert_eq!(header.check_boot(GBMode::CGB), Ok(()));
+
+        rom[0x0134] ^= 0xFF;
+        let header = Header::load_rom(&rom).unwrap();
+        assert!(matches!(header.check_boot(GBMode::CGB), Err(HeaderError::BadHeaderChecksum { .. })));
+    }
+
+    #[test]
+    fn test_header_title_length() {
+        let mut rom = rom();
+        rom[0x134..0x144].copy_from_slice(b"SIXTEEN CHARS 16");
+        assert_eq!(Header::load_rom(&rom).unwrap().title(), "SIXTEEN CHARS 16");
+        rom[0x143] = 0x80;
+        assert_eq!(Header::load_rom(&rom).unwrap().title(), "SIXTEEN CHARS 1");
+    }
+
+    #[test]
+    fn test_header_metadata() {
+        let mut rom = rom();
+        rom[0x147] = 0x03;
+        rom[0x148] = 0x05;
+        rom[0x149] = 0x03;
+        rom[0x14B] = 0x33;
+        rom[0x144..0x146].copy_from_slice(b"01");
+        let header = Header::load_rom(&rom).unwrap();
+
+        assert_eq!(header.cartridge_type_name(), "MBC1+RAM+BATTERY");
+        assert_eq!(header.rom_size_bytes(), Some(1024 * 1024));
+        assert_eq!(header.ram_size_bytes(), Some(32 * 1024));
+        assert_eq!(header.licensee(), "Nintendo R&D1");
+        assert_eq!(header.destination(), "Japan");
+        assert!(header.to_string().contains("Cartridge:       MBC1+RAM+BATTERY (0x03)"));
+    }
 }
@@ -0,0 +1,260 @@
+//! RGB images with PPM and PNG encoders, used for screenshots and golden-image tests
+
+use std::io;
+use std::path::Path;
+
+use crate::palette::Palettes;
+
+/// Layout of the pixels in the framebuffer the PPU writes to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelFormat {
+    /// 3 bytes per pixel: R, G, B
+    Rgb24,
+    /// 4 bytes per pixel: R, G, B, A (always 0xFF), the layout of a canvas `ImageData`
+    Rgba32,
+    /// 2 bytes per pixel, little-endian `RRRRRGGG GGGBBBBB`
+    Rgb565,
+    /// 1 byte per pixel: the shade (0-3) in bits 0-1 and the palette in bits 2-3, 0 for the
+    /// background, 1 for OBJ0 and 2 for OBJ1, for hosts applying their own colours
+    Indexed,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Rgba32 => 4,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Indexed => 1,
+        }
+    }
+
+    /// Write a colour in `out`, which is `bytes_per_pixel` long.
+    /// Indexed pixels get the closest background shade of grey, the PPU writes them itself.
+    #[inline(always)]
+    pub fn encode(self, [r, g, b]: [u8; 3], out: &mut [u8]) {
+        match self {
+            PixelFormat::Rgb24 => out.copy_from_slice(&[r, g, b]),
+            PixelFormat::Rgba32 => out.copy_from_slice(&[r, g, b, 0xFF]),
+            PixelFormat::Rgb565 => {
+                let pixel = (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3;
+                out.copy_from_slice(&pixel.to_le_bytes());
+            }
+            PixelFormat::Indexed => out[0] = 3 - ((r as u16 + g as u16 + b as u16) / 3 / 64) as u8,
+        }
+    }
+
+    /// Colour of an encoded pixel, RGB565 loses the low bits. `palettes` are the colours of the indexes.
+    pub fn decode(self, pixel: &[u8], palettes: &Palettes) -> [u8; 3] {
+        match self {
+            PixelFormat::Rgb24 | PixelFormat::Rgba32 => [pixel[0], pixel[1], pixel[2]],
+            PixelFormat::Rgb565 => {
+                let pixel = u16::from_le_bytes([pixel[0], pixel[1]]);
+                let (r, g, b) = ((pixel >> 11) as u8, (pixel >> 5) as u8 & 0x3F, pixel as u8 & 0x1F);
+                // Repeat the high bits in the low ones so white stays white
+                [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+            }
+            PixelFormat::Indexed => {
+                let palette = match pixel[0] >> 2 {
+                    1 => palettes.obj0,
+                    2 => palettes.obj1,
+                    _ => palettes.bg,
+                };
+                palette[pixel[0] as usize & 0x3]
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// RGB24, row by row
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, data: Vec<u8>) -> Image {
+        assert_eq!(data.len(), width * height * 3, "image data doesn't match its size");
+        Image { width, height, data }
+    }
+
+    /// Convert a framebuffer in the given format, indexed pixels take the colours of `palettes`
+    pub fn from_framebuffer(width: usize, height: usize, format: PixelFormat, palettes: &Palettes, framebuffer: &[u8]) -> Image {
+        let data = framebuffer.chunks(format.bytes_per_pixel()).flat_map(|pixel| format.decode(pixel, palettes)).collect();
+        Image::new(width, height, data)
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let i = (y * self.width + x) * 3;
+        [self.data[i], self.data[i + 1], self.data[i + 2]]
+    }
+
+    /// Binary PPM (P6)
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        ppm.extend_from_slice(&self.data);
+        ppm
+    }
+
+    /// Parse a binary PPM with 8-bit channels, as written by `to_ppm`
+    pub fn from_ppm(ppm: &[u8]) -> Option<Image> {
+        // Magic, width, height and max value, separated by whitespace, comments aren't supported
+        let mut fields = Vec::with_capacity(4);
+        let mut i = 0;
+        while fields.len() < 4 {
+            while ppm.get(i)?.is_ascii_whitespace() {
+                i += 1;
+            }
+            let start = i;
+            while !ppm.get(i)?.is_ascii_whitespace() {
+                i += 1;
+            }
+            fields.push(std::str::from_utf8(&ppm[start..i]).ok()?);
+        }
+
+        let width = fields[1].parse().ok()?;
+        let height = fields[2].parse().ok()?;
+        if fields[0] != "P6" || fields[3] != "255" {
+            return None;
+        }
+
+        // A single whitespace separates the header from the data
+        let data = ppm.get(i + 1..)?;
+        if data.len() != width * height * 3 {
+            return None;
+        }
+        Some(Image::new(width, height, data.to_vec()))
+    }
+
+    /// PNG with uncompressed deflate blocks, nothing fancy but readable everywhere
+    pub fn to_png(&self) -> Vec<u8> {
+        // Every line starts with the filter type, 0 = None
+        let mut raw = Vec::with_capacity(self.height * (self.width * 3 + 1));
+        for line in self.data.chunks(self.width * 3) {
+            raw.push(0);
+            raw.extend_from_slice(line);
+        }
+
+        let mut zlib = vec![0x78, 0x01];
+        let count = raw.len().div_ceil(0xFFFF).max(1);
+        for i in 0..count {
+            let block = &raw[i * 0xFFFF..raw.len().min((i + 1) * 0xFFFF)];
+            zlib.push((i + 1 == count) as u8);
+            zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&(self.width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(self.height as u32).to_be_bytes());
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bits, truecolour
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut png, b"IHDR", &ihdr);
+        png_chunk(&mut png, b"IDAT", &zlib);
+        png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    /// Write the image as PPM when the extension is `.ppm`, PNG otherwise
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let ppm = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("ppm"));
+        std::fs::write(path, if ppm { self.to_ppm() } else { self.to_png() })
+    }
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+pub(crate) fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette;
+
+    fn image() -> Image {
+        Image::new(2, 1, vec![255, 0, 0, 0, 0, 255])
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_pixel_formats() {
+        let mut out = [0; 4];
+        PixelFormat::Rgba32.encode([1, 2, 3], &mut out);
+        assert_eq!(out, [1, 2, 3, 0xFF]);
+
+        let mut out = [0; 2];
+        PixelFormat::Rgb565.encode([0xFF, 0x00, 0xFF], &mut out);
+        assert_eq!(out, [0x1F, 0xF8]);
+        let palettes = Palettes::uniform(palette::GREY);
+        assert_eq!(PixelFormat::Rgb565.decode(&out, &palettes), [0xFF, 0x00, 0xFF]);
+        PixelFormat::Rgb565.encode([0x84, 0x31, 0x00], &mut out);
+        assert_eq!(PixelFormat::Rgb565.decode(&out, &palettes), [0x84, 0x30, 0x00]);
+
+        let mut out = [0; 1];
+        PixelFormat::Indexed.encode([0xFF; 3], &mut out);
+        assert_eq!(out, [0]);
+        PixelFormat::Indexed.encode([0x60; 3], &mut out);
+        assert_eq!(out, [2]);
+        let palettes = Palettes { obj1: palette::GREEN, ..palettes };
+        assert_eq!(PixelFormat::Indexed.decode(&[2 << 2 | 1], &palettes), palette::GREEN[1]);
+        assert_eq!(PixelFormat::Indexed.decode(&[3], &palettes), palette::GREY[3]);
+    }
+
+    #[test]
+    fn test_ppm() {
+        let ppm = image().to_ppm();
+        assert_eq!(&ppm[..11], b"P6\n2 1\n255\n");
+        assert_eq!(Image::from_ppm(&ppm), Some(image()));
+        assert_eq!(Image::from_ppm(b"P6\n2 1\n255\n\xFF"), None);
+    }
+
+    #[test]
+    fn test_png() {
+        let png = image().to_png();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+        // Stored block holding the filter byte and the pixels of the only line
+        let idat = 8 + 12 + 13;
+        assert_eq!(&png[idat + 4..idat + 8], b"IDAT");
+        assert_eq!(&png[idat + 8..idat + 15], &[0x78, 0x01, 0x01, 7, 0, !7, 0xFF]);
+        assert_eq!(&png[idat + 15..idat + 22], &[0, 255, 0, 0, 0, 0, 255]);
+    }
+}
@@ -0,0 +1,318 @@
+//! Views of the PPU state for debug UIs: tile data, tile maps, OAM and palettes rendered into
+//! RGBA buffers that can be put in a canvas `ImageData` or a texture as they are.
+//!
+//! DMG colours go through BGP/OBP0/OBP1 and the palettes of the `Gameboy`, like on screen.
+
+use crate::gpu::GPU;
+use crate::image::Image;
+
+/// Tiles per row in `tiles`
+pub const TILES_PER_ROW: usize = 16;
+/// Tiles in one VRAM bank, 768 in CGB mode with the second bank
+pub const TILES_PER_BANK: usize = 384;
+
+/// Colour of the scroll viewport outline on the tile maps
+const VIEWPORT_COLOR: [u8; 3] = [0xFF, 0x00, 0x00];
+
+/// RGBA, 4 bytes per pixel, row by row. Transparent pixels are all 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RgbaImage {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl RgbaImage {
+    fn new(width: usize, height: usize) -> RgbaImage {
+        RgbaImage { width, height, data: vec![0; width * height * 4] }
+    }
+
+    fn set(&mut self, x: usize, y: usize, [r, g, b]: [u8; 3]) {
+        let i = (y * self.width + x) * 4;
+        self.data[i..i + 4].copy_from_slice(&[r, g, b, 0xFF]);
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * self.width + x) * 4;
+        [self.data[i], self.data[i + 1], self.data[i + 2], self.data[i + 3]]
+    }
+
+    /// Drop the alpha channel, to save the view with `Image::save`
+    pub fn to_image(&self) -> Image {
+        let data = self.data.chunks(4).flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect();
+        Image::new(self.width, self.height, data)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileMap {
+    Map9800,
+    Map9C00,
+}
+
+impl TileMap {
+    fn address(self) -> u16 {
+        match self {
+            TileMap::Map9800 => 0x9800,
+            TileMap::Map9C00 => 0x9C00,
+        }
+    }
+}
+
+/// Object attributes, as stored in OAM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OamEntry {
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub flags: u8,
+}
+
+impl OamEntry {
+    /// Position of the top-left corner on the screen
+    pub fn screen_position(&self) -> (i16, i16) {
+        (self.x as i16 - 8, self.y as i16 - 16)
+    }
+
+    pub fn behind_background(&self) -> bool {
+        self.flags & 0x80 != 0
+    }
+
+    pub fn flip_y(&self) -> bool {
+        self.flags & 0x40 != 0
+    }
+
+    pub fn flip_x(&self) -> bool {
+        self.flags & 0x20 != 0
+    }
+
+    /// OBP0 or OBP1 on DMG
+    pub fn dmg_palette(&self) -> u8 {
+        (self.flags >> 4) & 1
+    }
+
+    pub fn cgb_bank(&self) -> u8 {
+        (self.flags >> 3) & 1
+    }
+
+    pub fn cgb_palette(&self) -> u8 {
+        self.flags & 0x07
+    }
+
+    /// True when some of the object is on screen
+    pub fn visible(&self, height: u8) -> bool {
+        let (x, y) = self.screen_position();
+        x > -8 && x < 160 && y > -(height as i16) && y < 144
+    }
+}
+
+/// Shade (0-3) a DMG palette register gives to a colour id
+fn shade(register: u8, color_id: u8) -> usize {
+    (register >> (color_id * 2)) as usize & 0x03
+}
+
+/// Colour id of a pixel of the tile at `address` (0x8000-0x97FF)
+fn tile_pixel(gpu: &GPU, bank: usize, address: u16, x: u8, y: u8) -> u8 {
+    let low = gpu.read_vram_bank(bank, address + y as u16 * 2);
+    let high = gpu.read_vram_bank(bank, address + y as u16 * 2 + 1);
+    let bit = 7 - x;
+    ((high >> bit) & 1) << 1 | ((low >> bit) & 1)
+}
+
+/// Every tile of each VRAM bank, `TILES_PER_ROW` per row, the second bank below the first
+pub(crate) fn tiles(gpu: &GPU) -> RgbaImage {
+    let banks = gpu.vram_banks();
+    let rows = TILES_PER_BANK / TILES_PER_ROW;
+    let mut image = RgbaImage::new(TILES_PER_ROW * 8, banks * rows * 8);
+    let (bgp, colors) = (gpu.read(0xFF47), gpu.palettes().bg);
+
+    for bank in 0..banks {
+        for tile in 0..TILES_PER_BANK {
+            let address = 0x8000 + tile as u16 * 16;
+            let (left, top) = (tile % TILES_PER_ROW * 8, (bank * rows + tile / TILES_PER_ROW) * 8);
+            for y in 0..8 {
+                for x in 0..8 {
+                    let color_id = tile_pixel(gpu, bank, address, x, y);
+                    image.set(left + x as usize, top + y as usize, colors[shade(bgp, color_id)]);
+                }
+            }
+        }
+    }
+    image
+}
+
+/// The 32x32 tiles of a map with the tile data selected by LCDC, 256x256 pixels.
+/// The 160x144 viewport at SCX/SCY is outlined on the map used by the background.
+pub(crate) fn tile_map(gpu: &GPU, map: TileMap) -> RgbaImage {
+    let mut image = RgbaImage::new(256, 256);
+    let lcdc = gpu.read(0xFF40);
+    let (bgp, colors) = (gpu.read(0xFF47), gpu.palettes().bg);
+
+    for row in 0..32u16 {
+        for column in 0..32u16 {
+            let tile = gpu.read_vram_bank(0, map.address() + row * 32 + column);
+            let address = if lcdc & 0x10 != 0 {
+                0x8000 + tile as u16 * 16
+            } else {
+                (0x9000 + tile as i8 as i32 * 16) as u16
+            };
+            for y in 0..8 {
+                for x in 0..8 {
+                    let color_id = tile_pixel(gpu, 0, address, x, y);
+                    image.set((column * 8) as usize + x as usize, (row * 8) as usize + y as usize, colors[shade(bgp, color_id)]);
+                }
+            }
+        }
+    }
+
+    let bg_map = if lcdc & 0x08 != 0 { TileMap::Map9C00 } else { TileMap::Map9800 };
+    if map == bg_map {
+        let (scx, scy) = (gpu.read(0xFF43) as usize, gpu.read(0xFF42) as usize);
+        for i in 0..160 {
+            image.set((scx + i) % 256, scy, VIEWPORT_COLOR);
+            image.set((scx + i) % 256, (scy + 143) % 256, VIEWPORT_COLOR);
+        }
+        for i in 0..144 {
+            image.set(scx, (scy + i) % 256, VIEWPORT_COLOR);
+            image.set((scx + 159) % 256, (scy + i) % 256, VIEWPORT_COLOR);
+        }
+    }
+    image
+}
+
+pub(crate) fn oam_entries(gpu: &GPU) -> Vec<OamEntry> {
+    (0..40u16)
+        .map(|i| {
+            let byte = |offset: u16| gpu.read_oam(i * 4 + offset);
+            OamEntry { y: byte(0), x: byte(1), tile: byte(2), flags: byte(3) }
+        })
+        .collect()
+}
+
+/// The 40 objects, 8 per row, each in an 8x16 cell (the lower half is empty with 8x8 objects).
+/// Flips and palettes are applied, colour 0 is transparent.
+pub(crate) fn oam(gpu: &GPU) -> RgbaImage {
+    let mut image = RgbaImage::new(8 * 8, 5 * 16);
+    let tall = gpu.read(0xFF40) & 0x04 != 0;
+    let height = if tall { 16 } else { 8 };
+    let palettes = gpu.palettes();
+
+    for (i, entry) in oam_entries(gpu).iter().enumerate() {
+        let (register, colors) = match entry.dmg_palette() {
+            0 => (gpu.read(0xFF48), palettes.obj0),
+            _ => (gpu.read(0xFF49), palettes.obj1),
+        };
+        // The lowest bit of the tile number is ignored for 8x16 objects
+        let first_tile = if tall { entry.tile & 0xFE } else { entry.tile };
+        for y in 0..height {
+            let tile_y = if entry.flip_y() { height - 1 - y } else { y };
+            let address = 0x8000 + first_tile as u16 * 16 + (tile_y / 8) as u16 * 16;
+            for x in 0..8 {
+                let tile_x = if entry.flip_x() { 7 - x } else { x };
+                let color_id = tile_pixel(gpu, 0, address, tile_x, tile_y % 8);
+                if color_id != 0 {
+                    image.set(i % 8 * 8 + x as usize, i / 8 * 16 + y as usize, colors[shade(register, color_id)]);
+                }
+            }
+        }
+    }
+    image
+}
+
+/// BGP, OBP0 and OBP1 on a row each, as four 8x8 swatches of the shades they select
+pub(crate) fn palettes(gpu: &GPU) -> RgbaImage {
+    let mut image = RgbaImage::new(4 * 8, 3 * 8);
+    let palettes = gpu.palettes();
+    let rows = [(0xFF47, palettes.bg), (0xFF48, palettes.obj0), (0xFF49, palettes.obj1)];
+
+    for (row, (address, colors)) in rows.into_iter().enumerate() {
+        let register = gpu.read(address);
+        for color_id in 0..4u8 {
+            for y in 0..8 {
+                for x in 0..8 {
+                    image.set(color_id as usize * 8 + x, row * 8 + y, colors[shade(register, color_id)]);
+                }
+            }
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::GREY;
+
+    const WHITE: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
+    const BLACK: [u8; 4] = [0x00, 0x00, 0x00, 0xFF];
+    const RED: [u8; 4] = [0xFF, 0x00, 0x00, 0xFF];
+
+    /// Tile 1 is black on its first line only
+    fn gpu() -> GPU {
+        let mut gpu = GPU::new();
+        gpu.write_vram(0x10, 0xFF);
+        gpu.write_vram(0x11, 0xFF);
+        gpu.write(0xFF47, 0xE4); // Identity palette
+        gpu.write(0xFF48, 0xE4);
+        gpu.write(0xFF40, 0x91); // BG on 0x9800 with tiles at 0x8000
+        gpu
+    }
+
+    #[test]
+    fn test_tiles() {
+        let image = tiles(&gpu());
+        assert_eq!((image.width, image.height), (128, 192));
+        assert_eq!(image.pixel(7, 0), WHITE);
+        assert_eq!(image.pixel(8, 0), BLACK);
+        assert_eq!(image.pixel(15, 1), WHITE);
+    }
+
+    #[test]
+    fn test_tile_map() {
+        let mut gpu = gpu();
+        gpu.write_vram(0x1800 + 33, 1); // Second row, second column
+        gpu.write(0xFF43, 200);
+        gpu.write(0xFF42, 4);
+
+        let image = tile_map(&gpu, TileMap::Map9800);
+        assert_eq!(image.pixel(9, 8), BLACK);
+        assert_eq!(image.pixel(9, 9), WHITE);
+        // Viewport from (200, 4), wrapping horizontally
+        assert_eq!(image.pixel(200, 10), RED);
+        assert_eq!(image.pixel(103, 4), RED);
+        assert_eq!(image.pixel(104, 4), WHITE);
+
+        let image = tile_map(&gpu, TileMap::Map9C00);
+        assert_eq!(image.pixel(200, 10), WHITE);
+    }
+
+    #[test]
+    fn test_oam() {
+        let mut gpu = gpu();
+        gpu.write_oam(4, 16);
+        gpu.write_oam(5, 8);
+        gpu.write_oam(6, 1);
+        gpu.write_oam(7, 0x40); // Flipped vertically
+
+        let entries = oam_entries(&gpu);
+        assert_eq!(entries.len(), 40);
+        assert_eq!(entries[1].screen_position(), (0, 0));
+        assert!(entries[1].flip_y() && entries[1].visible(8));
+        assert!(!entries[0].visible(8));
+
+        let image = oam(&gpu);
+        assert_eq!(image.pixel(8, 7), BLACK);
+        assert_eq!(image.pixel(8, 0), [0; 4]);
+    }
+
+    #[test]
+    fn test_palettes() {
+        let mut gpu = gpu();
+        gpu.write(0xFF49, 0x1B); // Reversed
+        let image = palettes(&gpu);
+        assert_eq!(image.pixel(0, 0), WHITE);
+        assert_eq!(image.pixel(0, 16), BLACK);
+        assert_eq!(image.pixel(31, 16)[..3], GREY[0]);
+        assert_eq!(image.to_image().pixel(8, 8), GREY[1]);
+    }
+}
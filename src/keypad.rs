@@ -1,7 +1,7 @@
 const ROW0_FLAG: u8 = 0x10;
 const ROW1_FLAG: u8 = 0x20;
 
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, PartialEq)]
     pub enum Key {
         A,
         B,
@@ -13,6 +13,7 @@ const ROW1_FLAG: u8 = 0x20;
         Down,
     }
 
+    #[derive(Clone, Copy, Debug, PartialEq)]
     pub enum KeyEvent {
         Press(Key),
         Release(Key),
@@ -25,6 +26,12 @@ const ROW1_FLAG: u8 = 0x20;
         pub interrupt: u8,
     }
 
+    impl Default for Keypad {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
     impl Keypad {
         
         pub fn new() -> Keypad {
@@ -139,13 +146,13 @@ const ROW1_FLAG: u8 = 0x20;
         fn test_keypad_is_pressed() {
             let mut keypad = Keypad::new();
             keypad.press(Key::Right);
-            assert_eq!(keypad.is_pressed(Key::Right), true);
+            assert!(keypad.is_pressed(Key::Right));
         }
 
         #[test]
         fn test_keypad_is_not_pressed() {
             let keypad = Keypad::new();
-            assert_eq!(keypad.is_pressed(Key::Left), false);
+            assert!(!keypad.is_pressed(Key::Left));
         }        
 
 
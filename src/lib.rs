@@ -1,9 +1,27 @@
+#![allow(clippy::upper_case_acronyms)]
+
 pub mod gameboy;
 mod cpu;
 mod gpu;
 mod registers;
 mod memory;
 pub mod keypad;
-mod header;
+pub mod header;
 mod time;
-mod timer;
\ No newline at end of file
+mod timer;
+mod mbc;
+mod scheduler;
+pub mod palette;
+pub mod image;
+pub mod recorder;
+pub mod debugger;
+pub mod disasm;
+pub mod trace;
+pub mod gdb;
+pub mod inspect;
+pub mod cheats;
+pub mod search;
+pub mod patch;
+pub mod archive;
+#[cfg(unix)]
+pub mod terminal;
@@ -1,28 +1,388 @@
-use rusty_boy::gameboy::Gameboy;
+use std::io;
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process;
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+
+use rusty_boy::cheats::CheatError;
+use rusty_boy::debugger::Debugger;
+use rusty_boy::disasm::Symbols;
+use rusty_boy::gdb;
+use rusty_boy::gameboy::{GBMode, Gameboy, GameboyBuilder, LoadError};
+use rusty_boy::palette::{self, Palettes};
+use rusty_boy::trace::Trace;
+#[cfg(unix)]
+use rusty_boy::terminal::Terminal;
+
+// Exit codes, from sysexits.h
+const EXIT_USAGE: i32 = 64;
+const EXIT_DATA: i32 = 65;
+const EXIT_NO_INPUT: i32 = 66;
+const EXIT_IO: i32 = 74;
+
+#[cfg(unix)]
+const FRAME_TIME: f64 = 1.0 / 60.0;
+/// Frames between two writes of the save file
+#[cfg(unix)]
+const SAVE_INTERVAL: u64 = 300;
+
+const USAGE: &str = "Usage: rusty_boy [OPTIONS] <ROM>
+
+The ROM can be in a .zip or a .gz
+
+Options:
+    --model <dmg|cgb|auto>  Model to emulate (default: auto, from the header)
+    --boot-rom <FILE>       Run the boot ROM before the cartridge
+    --patch <FILE>          Apply an IPS, UPS or BPS patch to the ROM, can be
+                            given several times
+    --palette <NAME>        Colours of the DMG shades: grey (default), green, pocket
+                            or a CGB preset: cgb-brown, cgb-red, cgb-dark-brown,
+                            cgb-blue, cgb-dark-blue, cgb-grayscale, cgb-pale-yellow,
+                            cgb-orange, cgb-yellow, cgb-green, cgb-dark-green,
+                            cgb-inverted
+    --cheats <FILE>         Load Game Genie and GameShark codes, one per line
+    --save-dir <DIR>        Directory of the .sav files (default: next to the ROM)
+    --speed <FACTOR>        Emulation speed, 1.0 is real time (default: 1.0)
+    --frames <N>            Run N frames without a display then exit
+    --screenshot <FILE>     Write the last frame as PNG, or PPM for a .ppm file
+                            (requires --frames)
+    --record <FILE>         Record the frames into an animated .gif or an .avi
+    --trace                 Print every executed instruction on stderr
+    --doctor-log <FILE>     Log the CPU state of every instruction in the
+                            gameboy-doctor format, LY reads always return 0x90
+    --doctor-range <S-E>    Only log the instructions between two addresses (hex)
+    --debug                 Start in the debugger, 'help' lists its commands.
+                            Labels are read from the .sym file next to the ROM
+    --gdb <PORT>            Wait for a GDB client on 127.0.0.1:PORT
+    -h, --help              Print this help
+
+Exit codes:
+    0   Success
+    64  Bad command line
+    65  Invalid ROM, boot ROM, patch, save file or cheat code
+    66  Can't read the ROM, boot ROM, patch, save file or cheat file
+    74  Can't write the screenshot, the recording, the trace or the save file,
+        or no terminal
+
+Controls:
+    Arrows or WASD  D-pad
+    X or K          A
+    Z or J          B
+    Enter           Start
+    Space           Select
+    Q or Ctrl-C     Quit";
+
+struct Options {
+    rom: PathBuf,
+    model: Option<GBMode>,
+    boot_rom: Option<PathBuf>,
+    patches: Vec<PathBuf>,
+    palette: Option<Palettes>,
+    cheats: Option<PathBuf>,
+    save_dir: Option<PathBuf>,
+    speed: f64,
+    frames: Option<u64>,
+    screenshot: Option<PathBuf>,
+    record: Option<PathBuf>,
+    trace: bool,
+    doctor_log: Option<PathBuf>,
+    doctor_range: Option<RangeInclusive<u16>>,
+    debug: bool,
+    gdb: Option<u16>,
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("error: {}\n\n{}", message, USAGE);
+    process::exit(EXIT_USAGE);
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Options {
+    let mut rom = None;
+    let mut options = Options {
+        rom: PathBuf::new(),
+        model: None,
+        boot_rom: None,
+        patches: Vec::new(),
+        palette: None,
+        cheats: None,
+        save_dir: None,
+        speed: 1.0,
+        frames: None,
+        screenshot: None,
+        record: None,
+        trace: false,
+        doctor_log: None,
+        doctor_range: None,
+        debug: false,
+        gdb: None,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().unwrap_or_else(|| usage_error(&format!("{} needs a value", name)));
+        match arg.as_str() {
+            "--model" => {
+                options.model = match value("--model").to_lowercase().as_str() {
+                    "dmg" => Some(GBMode::DMG),
+                    "cgb" => Some(GBMode::CGB),
+                    "auto" => None,
+                    other => usage_error(&format!("unknown model '{}'", other)),
+                }
+            }
+            "--boot-rom" => options.boot_rom = Some(value("--boot-rom").into()),
+            "--patch" => options.patches.push(value("--patch").into()),
+            "--palette" => {
+                let name = value("--palette");
+                options.palette = Some(palette::preset(&name).unwrap_or_else(|| usage_error(&format!("unknown palette '{}'", name))));
+            }
+            "--cheats" => options.cheats = Some(value("--cheats").into()),
+            "--save-dir" => options.save_dir = Some(value("--save-dir").into()),
+            "--speed" => {
+                options.speed = match value("--speed").parse::<f64>() {
+                    Ok(speed) if speed > 0.0 && speed.is_finite() => speed,
+                    _ => usage_error("--speed must be a positive number"),
+                }
+            }
+            "--frames" => {
+                let frames = value("--frames");
+                options.frames = Some(frames.parse().unwrap_or_else(|_| usage_error(&format!("invalid frame count '{}'", frames))));
+            }
+            "--screenshot" => options.screenshot = Some(value("--screenshot").into()),
+            "--record" => options.record = Some(value("--record").into()),
+            "--trace" => options.trace = true,
+            "--doctor-log" => options.doctor_log = Some(value("--doctor-log").into()),
+            "--doctor-range" => {
+                let range = value("--doctor-range");
+                let parse = |address: &str| u16::from_str_radix(address.trim_start_matches("0x"), 16).ok();
+                options.doctor_range = match range.split_once('-').map(|(start, end)| (parse(start), parse(end))) {
+                    Some((Some(start), Some(end))) if start <= end => Some(start..=end),
+                    _ => usage_error(&format!("invalid address range '{}'", range)),
+                }
+            }
+            "--debug" => options.debug = true,
+            "--gdb" => {
+                let port = value("--gdb");
+                options.gdb = Some(port.parse().unwrap_or_else(|_| usage_error(&format!("invalid port '{}'", port))));
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') => usage_error(&format!("unknown option '{}'", arg)),
+            _ if rom.is_some() => usage_error(&format!("unexpected argument '{}'", arg)),
+            _ => rom = Some(PathBuf::from(arg)),
+        }
+    }
+
+    options.rom = rom.unwrap_or_else(|| usage_error("no ROM given"));
+    if options.screenshot.is_some() && options.frames.is_none() {
+        usage_error("--screenshot requires --frames");
+    }
+    if options.doctor_range.is_some() && options.doctor_log.is_none() {
+        usage_error("--doctor-range requires --doctor-log");
+    }
+    if options.trace && options.doctor_log.is_some() {
+        usage_error("--trace and --doctor-log can't be used together");
+    }
+    options
+}
+
+/// Print the load error and exit with the matching code
+fn load_failed(path: &Path, e: LoadError) -> ! {
+    eprintln!("error: {}: {}", path.display(), e);
+    process::exit(match e {
+        LoadError::Io(_) => EXIT_NO_INPUT,
+        _ => EXIT_DATA,
+    });
+}
+
+fn read_file(path: &Path) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| load_failed(path, e.into()))
+}
+
+fn write_file(path: &Path, data: &[u8]) {
+    if let Err(e) = std::fs::write(path, data) {
+        eprintln!("error: can't write {}: {}", path.display(), e);
+        process::exit(EXIT_IO);
+    }
+}
+
+fn save_path(options: &Options) -> PathBuf {
+    let name = options.rom.file_stem().unwrap_or_default();
+    let dir = match &options.save_dir {
+        Some(dir) => dir.as_path(),
+        None => options.rom.parent().unwrap_or(Path::new("")),
+    };
+    dir.join(name).with_extension("sav")
+}
+
+fn write_save(game: &Gameboy, path: &Path) {
+    if let Some(ram) = game.save_ram() {
+        write_file(path, ram);
+    }
+}
+
+fn load(options: &Options) -> Gameboy {
+    let mut builder = GameboyBuilder::new(read_file(&options.rom));
+    if let Some(model) = options.model {
+        builder = builder.model(model);
+    }
+    for path in &options.patches {
+        builder = builder.patch(read_file(path));
+    }
+    if let Some(path) = &options.boot_rom {
+        builder = builder.boot_rom(read_file(path));
+    }
+    if let Some(palette) = options.palette {
+        builder = builder.palette(palette);
+    }
+
+    let save = save_path(options);
+    if save.exists() {
+        builder = builder.save_ram(read_file(&save));
+    }
+
+    let mut game = builder.build().unwrap_or_else(|e| match e {
+        LoadError::BadBootRomSize(_) => load_failed(options.boot_rom.as_deref().unwrap(), e),
+        LoadError::SaveRamSize { .. } => load_failed(&save, e),
+        LoadError::BadPatch { index, .. } => load_failed(&options.patches[index], e),
+        _ => load_failed(&options.rom, e),
+    });
+    if let Some(path) = &options.cheats {
+        if let Err(e) = game.cheats_mut().load(path) {
+            eprintln!("error: {}: {}", path.display(), e);
+            process::exit(match e {
+                CheatError::Io(_) => EXIT_NO_INPUT,
+                _ => EXIT_DATA,
+            });
+        }
+    }
+    if options.trace {
+        game.start_trace(Trace::to_writer(io::stderr()).disassembly()).unwrap();
+    }
+    if let Some(path) = &options.doctor_log {
+        let trace = Trace::to_file(path).unwrap_or_else(|e| {
+            eprintln!("error: can't create {}: {}", path.display(), e);
+            process::exit(EXIT_IO);
+        });
+        let trace = match &options.doctor_range {
+            Some(range) => trace.pc_range(range.clone()),
+            None => trace,
+        };
+        game.start_trace(trace).unwrap();
+        game.set_ly_stub(true);
+    }
+    if let Some(path) = &options.record {
+        if let Err(e) = game.start_recording(path) {
+            eprintln!("error: can't record to {}: {}", path.display(), e);
+            process::exit(EXIT_IO);
+        }
+    }
+    game
+}
+
+/// Write the save file, complete the recording and flush the trace before exiting
+fn shutdown(game: &mut Gameboy, save: &Path) {
+    write_save(game, save);
+    if let Err(e) = game.stop_recording() {
+        eprintln!("error: recording failed: {}", e);
+        process::exit(EXIT_IO);
+    }
+    if let Err(e) = game.stop_trace() {
+        eprintln!("error: can't write the trace: {}", e);
+        process::exit(EXIT_IO);
+    }
+}
 
 fn main() {
+    let options = parse_args(std::env::args().skip(1));
+    let mut game = load(&options);
+    let save = save_path(&options);
+
+    if let Some(port) = options.gdb {
+        let mut debugger = Debugger::new(game);
+        let result = TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+            eprintln!("Waiting for GDB on 127.0.0.1:{}", port);
+            gdb::serve_tcp(&mut debugger, &listener)
+        });
+        if let Err(e) = result {
+            eprintln!("error: GDB connection: {}", e);
+        }
+        shutdown(&mut debugger.into_inner(), &save);
+        return;
+    }
+
+    if options.debug {
+        let mut debugger = Debugger::new(game);
+        if let Ok(symbols) = Symbols::load(options.rom.with_extension("sym")) {
+            debugger.set_symbols(symbols);
+        }
+        if let Err(e) = debugger.repl(io::stdin().lock(), io::stdout()) {
+            eprintln!("error: {}", e);
+        }
+        shutdown(&mut debugger.into_inner(), &save);
+        return;
+    }
 
-    env_logger::init();
-
-    let mut game = Gameboy::new();
-    game.load_rom(&std::fs::read("roms/tetris.gb").unwrap());
-    game.set_render_callback(|screen_data| {
-        for y in 0..144 {
-            for x in 0..160 {
-                print!(
-                    "{}",
-                    match screen_data[y * 160 * 3 + x * 3] {
-                        3 => "  ",
-                        2 => "░░",
-                        1 => "▒▒",
-                        0 => "▓▓",
-                        _ => "  ",
-                    }
-                );
-            }
-            println!();
+    if let Some(frames) = options.frames {
+        for _ in 0..frames {
+            game.run_frame();
+        }
+        if let Some(path) = &options.screenshot {
+            if let Err(e) = game.screenshot().save(path) {
+                eprintln!("error: can't write {}: {}", path.display(), e);
+                process::exit(EXIT_IO);
+            }
         }
+        shutdown(&mut game, &save);
+        return;
+    }
+
+    play(game, &save, options.speed);
+}
+
+/// Play in the terminal until Q is pressed
+#[cfg(unix)]
+fn play(mut game: Gameboy, save: &Path, speed: f64) {
+    let mut terminal = Terminal::new().unwrap_or_else(|e| {
+        eprintln!("error: can't set up the terminal: {}", e);
+        process::exit(EXIT_IO);
     });
-    game.run_debug();
-    
+
+    let frame_time = Duration::from_secs_f64(FRAME_TIME / speed);
+    let mut frames = 0u64;
+    while !terminal.quit_requested() {
+        let start = Instant::now();
+        for event in terminal.poll() {
+            game.handle_key(event);
+        }
+        game.run_frame();
+        let frame = game.screenshot();
+        if let Err(e) = terminal.draw(&frame.data, frame.width, frame.height) {
+            drop(terminal);
+            shutdown(&mut game, save);
+            eprintln!("error: can't draw the screen: {}", e);
+            process::exit(EXIT_IO);
+        }
+
+        frames += 1;
+        if frames.is_multiple_of(SAVE_INTERVAL) {
+            write_save(&game, save);
+        }
+
+        if let Some(remaining) = frame_time.checked_sub(start.elapsed()) {
+            std::thread::sleep(remaining);
+        }
+    }
+
+    drop(terminal);
+    shutdown(&mut game, save);
+}
+
+#[cfg(not(unix))]
+fn play(mut game: Gameboy, save: &Path, _speed: f64) {
+    shutdown(&mut game, save);
+    eprintln!("error: the terminal frontend is only available on Unix, use --frames to run headless");
+    process::exit(EXIT_IO);
 }
@@ -1,6 +1,6 @@
 use std::cmp::max;
 
-use super::{get_number_ram_banks, get_number_rom_banks, MBC};
+use super::MBC;
 
 pub struct MBC1 {
     rom: Vec<u8>,
@@ -18,34 +18,33 @@ pub struct MBC1 {
 }
 
 impl MBC1 {
-    pub fn new(rom: &Vec<u8>) -> Self {
-        let (has_battery, ram_size) = match rom[0x0149] {
-            0x01 => (false, 0),
-            0x02 => (false, get_number_ram_banks(rom[0x0149])),
-            0x03 => (true, get_number_ram_banks(rom[0x0149])),
-            _ => panic!("Invalid MBC1 RAM size: {:02x}", rom[0x0149]),
-        };
-
+    pub fn new(rom: &[u8], rom_banks: usize, ram_banks: usize, has_battery: bool) -> Self {
         MBC1 {
             rom: rom.to_vec(),
-            ram: std::iter::repeat(0).take(ram_size * 0x2000).collect(),
+            ram: vec![0; ram_banks * 0x2000],
             rom_bank: 1,
             ram_bank: 0,
 
             ram_enabled: false,
             mode: 0,
 
-            rom_banks_number: get_number_rom_banks(rom[0x0148]),
-            ram_banks_number: ram_size,
+            rom_banks_number: rom_banks,
+            ram_banks_number: ram_banks,
 
-            has_battery: has_battery,
+            has_battery,
         }
     }
 }
 
 impl MBC for MBC1 {
     fn read_rom(&self, address: u16) -> u8 {
-        let bank = if address < 0x4000 {
+        let bank = self.rom_bank(address);
+        let offset = address as usize & 0x3FFF;
+        self.rom.get(bank * 0x4000 + offset).copied().unwrap_or(0xff)
+    }
+
+    fn rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
             if self.mode == 0 {
                 0
             } else {
@@ -53,10 +52,19 @@ impl MBC for MBC1 {
             }
         } else {
             self.rom_bank
-        };
+        }
+    }
 
-        let offset = address as usize & 0x3FFF;
-        self.rom.get(bank * 0x4000 + offset).copied().unwrap_or(0xff)
+    fn ram_bank(&self) -> usize {
+        if self.mode == 1 { self.ram_bank } else { 0 }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
     }
 
     fn write_rom(&mut self, address: u16, value: u8) {
@@ -78,9 +86,9 @@ impl MBC for MBC1 {
 
     fn read_ram(&self, address: u16) -> u8 {
         if !self.ram_enabled { return 0xff; }
-        let bank = if self.mode == 0 { 0 } else { self.ram_bank };
-        let offset = (bank * 0x2000 | address as usize) & 0x1FFF;
-        self.ram[offset]
+        let rambank = if self.mode == 1 { self.ram_bank } else { 0 };
+        let offset = (rambank * 0x2000) | ((address & 0x1FFF) as usize);
+        self.ram.get(offset).copied().unwrap_or(0xff)
     }
 
     fn write_ram(&mut self, address: u16, value: u8) {
@@ -92,12 +100,24 @@ impl MBC for MBC1 {
         }
     }
 
-    fn has_battery(&self) -> bool { false }
+    fn has_battery(&self) -> bool { self.has_battery }
 
     fn info(&self) -> String {
         format!(
-            "MBC1: {:02x}, {:02x}, {}, {}",
-            self.rom_bank, self.ram_bank, self.ram_enabled, self.mode
+            "MBC1: {:02x}/{:02x}, {:02x}/{:02x}, {}, {}",
+            self.rom_bank, self.rom_banks_number, self.ram_bank, self.ram_banks_number, self.ram_enabled, self.mode
         )
     }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        self.ram.copy_from_slice(data);
+    }
 }
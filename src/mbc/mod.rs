@@ -1,6 +1,8 @@
 use mbc1::MBC1;
 use no_mbc::NoMBC;
 
+use crate::gameboy::LoadError;
+
 
 
 mod no_mbc;
@@ -13,45 +15,68 @@ pub trait MBC {
     fn write_ram(&mut self, address: u16, value: u8);
     fn has_battery(&self) -> bool;
     fn info(&self) -> String;
+    /// ROM bank mapped at `address` (0x0000-0x7FFF)
+    fn rom_bank(&self, address: u16) -> usize;
+    /// RAM bank mapped at 0xA000-0xBFFF
+    fn ram_bank(&self) -> usize;
+
+    /// Whole ROM, for debuggers
+    fn rom(&self) -> &[u8];
+    fn rom_mut(&mut self) -> &mut [u8];
+
+    /// External RAM content, used to persist battery-backed saves
+    fn ram(&self) -> &[u8];
+    fn ram_mut(&mut self) -> &mut [u8];
+    /// Restore the external RAM, `data` has the same size as `ram()`
+    fn load_ram(&mut self, data: &[u8]);
 }
 
-pub fn from_rom(rom: &Vec<u8>) -> Box<dyn MBC> {
-    if rom.len() <= 0x0150 {
-        panic!("ROM is too small to have a MBC");
+pub fn from_rom(rom: &[u8]) -> Result<Box<dyn MBC>, LoadError> {
+    if rom.len() < 0x0150 {
+        return Err(LoadError::TooSmall(rom.len()));
     }
-    
+
+    let rom_banks = get_number_rom_banks(rom[0x148]).ok_or(LoadError::BadRomSize(rom[0x148]))?;
+    let ram_banks = get_number_ram_banks(rom[0x149]).ok_or(LoadError::BadRamSize(rom[0x149]))?;
+    if rom.len() < rom_banks * 0x4000 {
+        return Err(LoadError::RomSizeMismatch { expected: rom_banks * 0x4000, actual: rom.len() });
+    }
+
     match rom[0x147] {
-        0x00 => Box::new(NoMBC::new(rom)),
-        0x01 ..= 0x03 => Box::new(MBC1::new(rom)),
-        _ => panic!("Unsupported MBC: {:02x}", rom[0x147]),
+        0x00 => Ok(Box::new(NoMBC::new(rom))),
+        0x01 => Ok(Box::new(MBC1::new(rom, rom_banks, 0, false))),
+        0x02 => Ok(Box::new(MBC1::new(rom, rom_banks, ram_banks, false))),
+        0x03 => Ok(Box::new(MBC1::new(rom, rom_banks, ram_banks, true))),
+        cartridge_type => Err(LoadError::UnsupportedMbc(cartridge_type)),
     }
 }
 
-fn get_number_rom_banks(value: u8) -> usize {
+fn get_number_rom_banks(value: u8) -> Option<usize> {
     match value {
-        0x00 => 2,
-        0x01 => 4,
-        0x02 => 8,
-        0x03 => 16,
-        0x04 => 32,
-        0x05 => 64,
-        0x06 => 128,
-        0x07 => 256,
-        0x52 => 72,
-        0x53 => 80,
-        0x54 => 96,
-        _ => panic!("Invalid MBC1 bank size: {:02x}", value),
+        0x00 => Some(2),
+        0x01 => Some(4),
+        0x02 => Some(8),
+        0x03 => Some(16),
+        0x04 => Some(32),
+        0x05 => Some(64),
+        0x06 => Some(128),
+        0x07 => Some(256),
+        0x08 => Some(512),
+        0x52 => Some(72),
+        0x53 => Some(80),
+        0x54 => Some(96),
+        _ => None,
     }
 }
 
-fn get_number_ram_banks(value: u8) -> usize {
+fn get_number_ram_banks(value: u8) -> Option<usize> {
     match value {
-        0x00 => 0,
-        0x01 => 1,
-        0x02 => 1,
-        0x03 => 4,
-        0x04 => 16,
-        0x05 => 8,
-        _ => panic!("Invalid MBC1 RAM bank size: {:02x}", value),
+        0x00 => Some(0),
+        0x01 => Some(1),
+        0x02 => Some(1),
+        0x03 => Some(4),
+        0x04 => Some(16),
+        0x05 => Some(8),
+        _ => None,
     }
 }
\ No newline at end of file
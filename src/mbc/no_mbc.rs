@@ -5,7 +5,7 @@ pub struct NoMBC {
 }
 
 impl NoMBC {
-    pub fn new(rom: &Vec<u8>) -> Self {
+    pub fn new(rom: &[u8]) -> Self {
         NoMBC { rom: rom.to_vec() }
     }
 }
@@ -15,7 +15,23 @@ impl MBC for NoMBC {
         self.rom[address as usize]
     }
 
-    fn write_rom(&mut self, address: u16, value: u8) {}
+    fn write_rom(&mut self, _address: u16, _value: u8) {}
+
+    fn rom_bank(&self, address: u16) -> usize {
+        (address >= 0x4000) as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        0
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
 
     fn read_ram(&self, _address: u16) -> u8 {
         0
@@ -30,4 +46,14 @@ impl MBC for NoMBC {
     fn info(&self) -> String {
         "No MBC".to_string()
     }
+
+    fn ram(&self) -> &[u8] {
+        &[]
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    fn load_ram(&mut self, _data: &[u8]) {}
 }
\ No newline at end of file
@@ -1,11 +1,11 @@
-use crate::{gpu::GPU, keypad::Keypad, mbc::MBC, timer::Timer};
+use crate::{cheats::Cheats, debugger::{WatchHit, Watchpoint}, gameboy::Freeze, gpu::GPU, keypad::Keypad, mbc::MBC, scheduler::{Event, Scheduler}, timer::Timer};
 
-const ROM_SIZE: usize = 0x8000;
 const WRAM_SIZE: usize = 0x2000;
 const HRAM_SIZE: usize = 0x7F;
 
 pub struct Memory {
     pub mbc: Box<dyn MBC+'static>,
+    boot_rom: Option<Vec<u8>>,  // Mapped over the cartridge until 0xFF50 is written
     pub gpu: GPU,
     pub keypad: Keypad,
 
@@ -14,28 +14,66 @@ pub struct Memory {
 
     timer: Timer,
 
+    scheduler: Scheduler,
+    pending: u32,       // Cycles not yet given to the components
+
     wram: [u8; WRAM_SIZE],
     wram_bank: u8,
     hram: [u8; HRAM_SIZE],
+
+    dma_source: u16,
+    dma_index: u16,     // Next OAM byte copied by the DMA, 0xA0 when no transfer is running
+
+    /// Block CPU access to VRAM/OAM while the PPU uses them, as the hardware does.
+    /// Can be turned off for debugging.
+    pub ppu_access_blocking: bool,
+    /// LY always reads 0x90, as in the emulator that made the gameboy-doctor logs
+    pub ly_stub: bool,
+
+    /// Checked on every CPU access while not empty, the last match is kept in `watch_hit`
+    pub watchpoints: Vec<Watchpoint>,
+    pub watch_hit: Option<WatchHit>,
+
+    /// Game Genie codes are applied to the ROM reads, GameShark codes by `apply_cheats` when VBlank starts
+    pub cheats: Cheats,
+    /// Addresses of `Gameboy::freeze`, written back with the GameShark codes
+    pub freezes: Vec<Freeze>,
 }
 
 impl Memory {
     pub fn new(mbc: Box<dyn MBC+'static>) -> Memory {
         let mut m = Memory {
             mbc,
+            boot_rom: None,
             gpu: GPU::new(),
             keypad: Keypad::new(),
 
             timer: Timer::new(),
 
+            scheduler: Scheduler::new(),
+            pending: 0,
+
             wram: [0; WRAM_SIZE],
             wram_bank: 0,
             hram: [0; HRAM_SIZE],
 
+            dma_source: 0,
+            dma_index: 0xA0,
+
             interrupt_flags: 0,
             interrupt_enable: 0,
+
+            ppu_access_blocking: true,
+            ly_stub: false,
+
+            watchpoints: Vec::new(),
+            watch_hit: None,
+
+            cheats: Cheats::new(),
+            freezes: Vec::new(),
         };
         m.init_memory();
+        m.schedule_events();
         m
     }
 
@@ -43,13 +81,76 @@ impl Memory {
         self.write(0xFF40, 0x91);
     }
 
-    pub fn read(&self, address: u16) -> u8 {
+    /// Map a boot ROM (0x100 bytes for DMG, 0x900 for CGB), the LCD starts off as on power on
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
+        self.write(0xFF40, 0x00);
+    }
+
+    /// The CGB boot ROM also covers 0x200-0x8FF, the cartridge header stays visible in between
+    fn read_boot_rom(&self, address: u16) -> Option<u8> {
+        let boot_rom = self.boot_rom.as_ref()?;
         match address {
-            0x0000..=0x7FFF => self.mbc.read_rom(address),
-            0x8000..=0x9FFF => self.gpu.read_vram(address - 0x8000), // VRAM
+            0x0000..=0x00FF => Some(boot_rom[address as usize]),
+            0x0200..=0x08FF if boot_rom.len() > 0x100 => Some(boot_rom[address as usize]),
+            _ => None,
+        }
+    }
+
+    /// Registers and memories owned by components that are synchronised lazily
+    #[inline(always)]
+    fn needs_sync(address: u16) -> bool {
+        matches!(address, 0x8000..=0x9FFF | 0xFE00..=0xFF7F)
+    }
+
+    pub fn read(&mut self, address: u16) -> u8 {
+        let value = self.read_mapped(address);
+        if !self.watchpoints.is_empty() {
+            self.watch(address, value, false);
+        }
+        value
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if !self.watchpoints.is_empty() {
+            self.watch(address, value, true);
+        }
+        self.write_mapped(address, value);
+    }
+
+    fn watch(&mut self, address: u16, value: u8, write: bool) {
+        if self.watchpoints.iter().any(|w| w.matches(address, write)) {
+            self.watch_hit = Some(WatchHit { address, value, write });
+        }
+    }
+
+    /// The CPU view of `peek`, with the PPU locks and the enable register of the external RAM
+    fn read_mapped(&mut self, address: u16) -> u8 {
+        if self.pending > 0 && Self::needs_sync(address) {
+            self.sync();
+        }
+
+        match address {
+            0x8000..=0x9FFF if !self.vram_accessible() => 0xFF,      // VRAM locked (mode 3)
             0xA000..=0xBFFF => self.mbc.read_ram(address),           // External RAM
+            0xFE00..=0xFE9F if !self.oam_accessible() => 0xFF,       // OAM locked (mode 2 & 3)
+            _ => self.peek(address),
+        }
+    }
+
+    /// Read without side effects, for debuggers and memory viewers: no PPU lock, no watchpoint,
+    /// the external RAM is read even when disabled. Registers of the components can lag behind
+    /// by the cycles of the current instruction as they aren't synchronised.
+    pub fn peek(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x08FF if self.boot_rom.is_some() => {
+                self.read_boot_rom(address).unwrap_or_else(|| self.mbc.read_rom(address))
+            }
+            0x0000..=0x7FFF => self.cheats.patch_rom(address, self.mbc.read_rom(address)),
+            0x8000..=0x9FFF => self.gpu.read_vram(address - 0x8000), // VRAM
+            0xA000..=0xBFFF => self.peek_ram(self.mbc.ram_bank(), address).unwrap_or(0xFF), // External RAM
             0xC000..=0xDFFF => self.wram[address as usize - 0xC000], // Work RAM (WRAM) -- TD Handle WRAM bank switching
-            0xE000..=0xFDFF => self.read(address - 0x2000),          // Echo RAM
+            0xE000..=0xFDFF => self.peek(address - 0x2000),          // Echo RAM
             0xFE00..=0xFE9F => self.gpu.read_oam(address - 0xFE00),  // OAM
             0xfea0..=0xfeff => 0,                                   // Unusable
             0xFF00 => self.keypad.read(),                            // Keypad
@@ -57,27 +158,81 @@ impl Memory {
             0xff04..=0xff07 => self.timer.read(address),             // Timer I/O
             0xff0f => self.interrupt_flags,                          // Interrupt Flags
             0xff10..=0xff3f => { 0 } // Sound I/O
+            0xff44 if self.ly_stub => 0x90,            // LY stubbed for traces
             0xff40..=0xFF4B => self.gpu.read(address), //LCD Control, Status, Position, Scrolling, and Palettes
             0xff4f => self.gpu.vram_bank,              // VRAM Bank
             0xff50 => 0,                               // Boot ROM disable
-            0xff51..=0xFF55 => self.gpu.read(address), // VRAM DMA
-            0xff68..=0xff6b => self.gpu.read(address), // Background/Object Palette Data
             0xff70 => self.wram_bank,                  // WRAM Bank
             0xff80..=0xfffe => self.hram[address as usize & HRAM_SIZE], // High RAM
             0xffff => self.interrupt_enable,           // Interrupt Enable
-            _ => { panic!("Unimplemented memory read at address: {:#06x}", address); }
+            _ => 0xFF,                                 // Unmapped, or not emulated (CGB VRAM DMA and palettes)
         }
     }
 
-    pub fn write(&mut self, address: u16, value: u8) {
+    /// Byte of any ROM bank, `address` is taken in the 0x0000-0x3FFF or 0x4000-0x7FFF window
+    pub fn peek_rom(&self, bank: usize, address: u16) -> Option<u8> {
+        self.mbc.rom().get(bank * 0x4000 + (address as usize & 0x3FFF)).copied()
+    }
+
+    /// Byte of any external RAM bank, `address` is taken in the 0xA000-0xBFFF window
+    pub fn peek_ram(&self, bank: usize, address: u16) -> Option<u8> {
+        self.mbc.ram().get(bank * 0x2000 + (address as usize & 0x1FFF)).copied()
+    }
+
+    /// Write without side effects: the ROM and the external RAM are patched in the mapped bank
+    /// instead of reaching the MBC, I/O registers are set without triggering anything
+    /// (DIV isn't reset, no DMA is started, the boot ROM stays mapped)
+    pub fn poke(&mut self, address: u16, value: u8) {
+        let needs_sync = Self::needs_sync(address);
+        if needs_sync {
+            self.sync();
+        }
+
+        match address {
+            0x0000..=0x7FFF => {
+                let offset = self.mbc.rom_bank(address) * 0x4000 + (address as usize & 0x3FFF);
+                if let Some(byte) = self.mbc.rom_mut().get_mut(offset) { *byte = value; }
+            }
+            0x8000..=0x9FFF => self.gpu.write_vram(address - 0x8000, value),
+            0xA000..=0xBFFF => {
+                let offset = self.mbc.ram_bank() * 0x2000 + (address as usize & 0x1FFF);
+                if let Some(byte) = self.mbc.ram_mut().get_mut(offset) { *byte = value; }
+            }
+            0xC000..=0xDFFF => self.wram[address as usize - 0xC000] = value,
+            0xE000..=0xFDFF => self.poke(address - 0x2000, value),
+            0xFE00..=0xFE9F => self.gpu.write_oam(address - 0xFE00, value),
+            0xFF00 => self.keypad.write(value),
+            0xff04..=0xff07 => self.timer.poke(address, value),
+            0xff0f => self.interrupt_flags = value,
+            0xff40..=0xFF4B => self.gpu.write(address, value),
+            0xff4f => self.gpu.vram_bank = value,
+            0xff70 => self.wram_bank = value,
+            0xff80..=0xfffe => self.hram[address as usize & HRAM_SIZE] = value,
+            0xffff => self.interrupt_enable = value,
+            _ => {}
+        }
+
+        if needs_sync {
+            self.schedule_events();
+        }
+    }
+
+    fn write_mapped(&mut self, address: u16, value: u8) {
+        let needs_sync = Self::needs_sync(address);
+        if needs_sync {
+            self.sync();
+        }
+
         match address {
             0x0000..=0x7FFF => {
                 self.mbc.write_rom(address, value);
             }, // Rom
+            0x8000..=0x9FFF if !self.vram_accessible() => (),                // VRAM locked (mode 3)
             0x8000..=0x9FFF => self.gpu.write_vram(address - 0x8000, value), // VRAM
             0xA000..=0xBFFF => self.mbc.write_ram(address, value),           // External RAM
             0xC000..=0xDFFF => self.wram[address as usize - 0xC000] = value, // Work RAM (WRAM) -- TD Handle WRAM bank switching
-            0xE000..=0xFDFF => self.write(address - 0x2000, value),          // Echo RAM
+            0xE000..=0xFDFF => self.write_mapped(address - 0x2000, value),   // Echo RAM
+            0xFE00..=0xFE9F if !self.oam_accessible() => (),                 // OAM locked (mode 2 & 3)
             0xFE00..=0xFE9F => self.gpu.write_oam(address - 0xFE00, value),  // OAM
             0xfea0..=0xfeff => (),                                           // Unusable
             0xFF00 => self.keypad.write(value),                              // Keypad
@@ -88,7 +243,7 @@ impl Memory {
             0xff46 => { self.dma_transfer(value); } // OAM DMA
             0xff40..=0xFF4B => self.gpu.write(address, value), //LCD Control, Status, Position, Scrolling, and Palettes
             0xff4f => self.gpu.vram_bank = value,              // VRAM Bank
-            0xff50 => (),                                      // Boot ROM disable
+            0xff50 if value != 0 => self.boot_rom = None,     // Boot ROM disable
             0xff51..=0xFF55 => self.gpu.write(address, value), // VRAM DMA
             0xff68..=0xff6b => self.gpu.write(address, value), // Background/Object Palette Data
             0xff70 => self.wram_bank = value,                  // WRAM Bank
@@ -98,6 +253,11 @@ impl Memory {
                 //panic!("Unimplemented memory write at address: {:#06x}", address);
             }
         }
+
+        // The write may have moved the next event of a component (TAC, LCDC, DMA...)
+        if needs_sync {
+            self.schedule_events();
+        }
     }
 
     pub fn write_word(&mut self, address: u16, value: u16) {
@@ -105,29 +265,210 @@ impl Memory {
         self.write(address + 1, (value >> 8) as u8);
     }
 
+    /// Start an OAM DMA transfer, one byte is copied every M-cycle
     pub fn dma_transfer(&mut self, address: u8) {
-        let start = address as u16 * 0x100;
-        for i in 0..0xA0 {
-            self.write(0xFE00 | i, self.read(start + i));
+        self.dma_source = address as u16 * 0x100;
+        self.dma_index = 0;
+    }
+
+    /// The DMA controller writes OAM directly, it isn't affected by the PPU locks
+    fn step_dma(&mut self, cycles: u32) {
+        for _ in 0..cycles / 4 {
+            if self.dma_index >= 0xA0 { return; }
+
+            let value = match self.dma_source + self.dma_index {
+                a @ 0x8000..=0x9FFF => self.gpu.read_vram(a - 0x8000),
+                a => self.read_mapped(a),
+            };
+            self.gpu.write_oam(self.dma_index, value);
+            self.dma_index += 1;
         }
     }
 
-    pub fn read_word(&self, address: u16) -> u16 {
+    #[inline(always)]
+    fn vram_accessible(&self) -> bool {
+        !self.ppu_access_blocking || self.gpu.vram_accessible()
+    }
+
+    #[inline(always)]
+    fn oam_accessible(&self) -> bool {
+        !self.ppu_access_blocking || self.gpu.oam_accessible()
+    }
+
+    /// Cycles elapsed since power on
+    pub fn cycles(&self) -> u64 {
+        self.scheduler.now()
+    }
+
+    pub fn read_word(&mut self, address: u16) -> u16 {
         (self.read(address) as u16) | ((self.read(address + 1) as u16) << 8)
     }
 
+    /// Advance the clock, the components are only stepped when one of their events is due
     pub fn step(&mut self, cycles: u8) {
         self.interrupt_flags |= self.keypad.interrupt;
         self.keypad.interrupt = 0;
 
-        self.gpu.step(cycles as u32);
+        self.pending += cycles as u32;
+        if self.scheduler.advance(cycles as u32) {
+            self.sync();
+        }
+    }
+
+    /// Bring every component up to date with the clock
+    pub fn sync(&mut self) {
+        let cycles = std::mem::take(&mut self.pending);
+        if cycles == 0 { return; }
+
+        self.step_dma(cycles);
+
+        self.gpu.step(cycles);
+        if self.gpu.interrupt & 0x01 != 0 {
+            self.apply_cheats();
+        }
         self.interrupt_flags |= self.gpu.interrupt;
         self.gpu.interrupt = 0;
 
         self.timer.step(cycles);
         self.interrupt_flags |= self.timer.interrupt;
         self.timer.interrupt = 0;
+
+        self.schedule_events();
+    }
+
+    /// The freezes and the GameShark codes write the RAM when VBlank starts
+    fn apply_cheats(&mut self) {
+        let freezes = self.freezes.iter().map(|freeze| (freeze.address, freeze.value));
+        let writes: Vec<(u16, u8)> = freezes.chain(self.cheats.ram_writes()).collect();
+        for (address, value) in writes {
+            self.poke(address, value);
+        }
+    }
+
+    fn schedule_events(&mut self) {
+        self.scheduler.schedule(Event::Ppu, self.gpu.cycles_until_vblank());
+
+        match self.timer.cycles_until_interrupt() {
+            Some(cycles) => self.scheduler.schedule(Event::Timer, cycles),
+            None => self.scheduler.cancel(Event::Timer),
+        }
+
+        if self.dma_index < 0xA0 {
+            self.scheduler.schedule(Event::Dma, (0xA0 - self.dma_index as u32) * 4);
+        } else {
+            self.scheduler.cancel(Event::Dma);
+        }
+    }
+
+    /// Cycles the CPU can skip while halted, nothing can wake it up before the next event
+    pub fn cycles_until_next_event(&self) -> u64 {
+        self.scheduler.cycles_until_next().unwrap_or(u64::MAX)
     }
 }
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc;
+
+    fn memory() -> Memory {
+        let rom = vec![0; 0x8000];
+        Memory::new(mbc::from_rom(&rom).unwrap())
+    }
+
+    #[test]
+    fn test_oam_locked_during_oam_scan() {
+        let mut memory = memory();
+        memory.write(0xFE00, 0x42);
+        assert_eq!(memory.read(0xFE00), 0xFF);
+        assert_eq!(memory.gpu.read_oam(0), 0x00);
+    }
+
+    #[test]
+    fn test_vram_locked_while_drawing() {
+        let mut memory = memory();
+        memory.write(0x8000, 0x42);
+        assert_eq!(memory.read(0x8000), 0x42);
+
+        memory.step(80);
+        memory.write(0x8000, 0x24);
+        assert_eq!(memory.read(0x8000), 0xFF);
+        assert_eq!(memory.read(0xFE00), 0xFF);
+
+        memory.step(172);
+        assert_eq!(memory.read(0x8000), 0x42);
+        assert_eq!(memory.read(0xFE00), 0x00);
+    }
+
+    #[test]
+    fn test_dma_one_byte_per_m_cycle() {
+        let mut memory = memory();
+        for i in 0..0xA0 {
+            memory.write(0xC000 + i, i as u8 + 1);
+        }
+        memory.write(0xFF46, 0xC0);
+        memory.step(8);
+        memory.sync();
+        assert_eq!(memory.gpu.read_oam(1), 0x02);
+        assert_eq!(memory.gpu.read_oam(2), 0x00);
+        for _ in 0..0xA0 {
+            memory.step(4);
+        }
+        memory.sync();
+        assert_eq!(memory.gpu.read_oam(0x9F), 0xA0);
+    }
+
+    #[test]
+    fn test_peek_poke() {
+        let mut memory = memory();
+        memory.poke(0x8000, 0x42);
+        memory.step(80);
+        assert_eq!(memory.read(0x8000), 0xFF);
+        assert_eq!(memory.peek(0x8000), 0x42);
+        assert_eq!(memory.peek(0xFF4C), 0xFF);
+
+        for _ in 0..64 {
+            memory.step(16);
+        }
+        memory.poke(0xFF04, 0x12);
+        assert_eq!(memory.read(0xFF04), 0x12);
+
+        memory.poke(0x0150, 0xAB);
+        assert_eq!(memory.peek(0x0150), 0xAB);
+        assert_eq!(memory.peek_rom(0, 0x0150), Some(0xAB));
+        assert_eq!(memory.peek_rom(2, 0x4000), None);
+        assert_eq!(memory.peek_ram(0, 0xA000), None);
+    }
+
+    #[test]
+    fn test_vblank_event() {
+        let mut memory = memory();
+        let mut cycles = 0;
+        while memory.interrupt_flags & 0x01 == 0 {
+            memory.step(4);
+            cycles += 4;
+        }
+        assert_eq!(cycles, 144 * 456);
+    }
+
+    #[test]
+    fn test_boot_rom_mapping() {
+        let mut memory = memory();
+        memory.load_boot_rom(vec![0x31; 0x100]);
+        assert_eq!(memory.read(0x0000), 0x31);
+        assert_eq!(memory.read(0x0100), 0x00);
+        memory.write(0xFF50, 0x01);
+        assert_eq!(memory.read(0x0000), 0x00);
+    }
+
+    #[test]
+    fn test_access_blocking_opt_out() {
+        let mut memory = memory();
+        memory.ppu_access_blocking = false;
+        memory.write(0xFE00, 0x42);
+        assert_eq!(memory.read(0xFE00), 0x42);
+    }
+}
@@ -0,0 +1,132 @@
+/// RGB colours of the 4 DMG shades, from the lightest (0) to the darkest (3)
+pub type Palette = [[u8; 3]; 4];
+
+/// Palettes of the background/window and of the two object palettes (OBP0 and OBP1),
+/// the way the CGB colourises DMG games
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palettes {
+    pub bg: Palette,
+    pub obj0: Palette,
+    pub obj1: Palette,
+}
+
+impl Palettes {
+    /// Same colours for the background and the objects
+    pub const fn uniform(palette: Palette) -> Palettes {
+        Palettes { bg: palette, obj0: palette, obj1: palette }
+    }
+}
+
+impl From<Palette> for Palettes {
+    fn from(palette: Palette) -> Self {
+        Palettes::uniform(palette)
+    }
+}
+
+const fn rgb(colours: [u32; 4]) -> Palette {
+    let mut palette = [[0; 3]; 4];
+    let mut i = 0;
+    while i < 4 {
+        palette[i] = [(colours[i] >> 16) as u8, (colours[i] >> 8) as u8, colours[i] as u8];
+        i += 1;
+    }
+    palette
+}
+
+/// Plain grey levels
+pub const GREY: Palette = [
+    [0xFF, 0xFF, 0xFF],
+    [0xAA, 0xAA, 0xAA],
+    [0x55, 0x55, 0x55],
+    [0x00, 0x00, 0x00],
+];
+
+/// Green tint of the original DMG screen
+pub const GREEN: Palette = rgb([0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]);
+
+/// Greenish grey of the Game Boy Pocket screen
+pub const POCKET: Palette = rgb([0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F]);
+
+// Palettes of the CGB boot ROM for DMG games, selected with a button combination on the logo
+const CGB_WHITE_BROWN: Palette = rgb([0xFFFFFF, 0xFFAD63, 0x843100, 0x000000]);
+const CGB_WHITE_RED: Palette = rgb([0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000]);
+const CGB_WHITE_GREEN: Palette = rgb([0xFFFFFF, 0x7BFF31, 0x008400, 0x000000]);
+const CGB_WHITE_BLUE: Palette = rgb([0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000]);
+
+/// Up
+pub const CGB_BROWN: Palettes = Palettes::uniform(CGB_WHITE_BROWN);
+/// Up + A
+pub const CGB_RED: Palettes = Palettes { bg: CGB_WHITE_RED, obj0: CGB_WHITE_GREEN, obj1: CGB_WHITE_BLUE };
+/// Up + B
+pub const CGB_DARK_BROWN: Palettes = Palettes {
+    bg: rgb([0xFFE6C5, 0xCE9C84, 0x846B29, 0x5A3108]),
+    obj0: CGB_WHITE_BROWN,
+    obj1: CGB_WHITE_BROWN,
+};
+/// Left
+pub const CGB_BLUE: Palettes = Palettes { bg: CGB_WHITE_BLUE, obj0: CGB_WHITE_RED, obj1: CGB_WHITE_GREEN };
+/// Left + A
+pub const CGB_DARK_BLUE: Palettes = Palettes {
+    bg: rgb([0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000]),
+    obj0: CGB_WHITE_RED,
+    obj1: CGB_WHITE_BROWN,
+};
+/// Left + B
+pub const CGB_GRAYSCALE: Palettes = Palettes::uniform(rgb([0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000]));
+/// Down
+pub const CGB_PALE_YELLOW: Palettes = Palettes::uniform(rgb([0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000]));
+/// Down + A
+pub const CGB_ORANGE: Palettes = Palettes::uniform(rgb([0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000]));
+/// Down + B
+pub const CGB_YELLOW: Palettes = Palettes {
+    bg: rgb([0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000]),
+    obj0: CGB_WHITE_BLUE,
+    obj1: CGB_WHITE_GREEN,
+};
+/// Right
+pub const CGB_GREEN: Palettes = Palettes::uniform(rgb([0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000]));
+/// Right + A, also the default for games the boot ROM doesn't know
+pub const CGB_DARK_GREEN: Palettes = Palettes {
+    bg: rgb([0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000]),
+    obj0: CGB_WHITE_RED,
+    obj1: CGB_WHITE_RED,
+};
+/// Right + B
+pub const CGB_INVERTED: Palettes = Palettes::uniform(rgb([0x000000, 0x008484, 0xFFDE00, 0xFFFFFF]));
+
+/// Every preset with its name, for frontends
+pub const PRESETS: [(&str, Palettes); 15] = [
+    ("grey", Palettes::uniform(GREY)),
+    ("green", Palettes::uniform(GREEN)),
+    ("pocket", Palettes::uniform(POCKET)),
+    ("cgb-brown", CGB_BROWN),
+    ("cgb-red", CGB_RED),
+    ("cgb-dark-brown", CGB_DARK_BROWN),
+    ("cgb-blue", CGB_BLUE),
+    ("cgb-dark-blue", CGB_DARK_BLUE),
+    ("cgb-grayscale", CGB_GRAYSCALE),
+    ("cgb-pale-yellow", CGB_PALE_YELLOW),
+    ("cgb-orange", CGB_ORANGE),
+    ("cgb-yellow", CGB_YELLOW),
+    ("cgb-green", CGB_GREEN),
+    ("cgb-dark-green", CGB_DARK_GREEN),
+    ("cgb-inverted", CGB_INVERTED),
+];
+
+/// Look a preset up by its name in `PRESETS`
+pub fn preset(name: &str) -> Option<Palettes> {
+    PRESETS.iter().find(|(preset, _)| preset.eq_ignore_ascii_case(name)).map(|&(_, palettes)| palettes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        assert_eq!(GREEN[0], [0x9B, 0xBC, 0x0F]);
+        assert_eq!(preset("Pocket"), Some(Palettes::uniform(POCKET)));
+        assert_eq!(preset("cgb-red").unwrap().obj1, CGB_WHITE_BLUE);
+        assert_eq!(preset("sepia"), None);
+    }
+}
@@ -0,0 +1,420 @@
+//! ROM patches in the IPS, UPS and BPS formats, as used by translations and romhacks.
+//! The format is detected from the magic number; UPS and BPS patches carry CRC32s of the
+//! original ROM, the patched ROM and the patch itself, they are all checked.
+
+use std::fmt;
+
+use crate::header::MAX_ROM_SIZE;
+use crate::image::crc32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Ips,
+    Ups,
+    Bps,
+}
+
+impl Format {
+    pub fn detect(patch: &[u8]) -> Option<Format> {
+        if patch.starts_with(b"PATCH") {
+            Some(Format::Ips)
+        } else if patch.starts_with(b"UPS1") {
+            Some(Format::Ups)
+        } else if patch.starts_with(b"BPS1") {
+            Some(Format::Bps)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    UnknownFormat,
+    /// The patch ends early, points outside of the ROM or gives a size too large for a ROM
+    Corrupt,
+    SourceSize { expected: usize, actual: usize },
+    BadSourceChecksum { expected: u32, computed: u32 },
+    BadTargetChecksum { expected: u32, computed: u32 },
+    BadPatchChecksum { expected: u32, computed: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::Corrupt => write!(f, "the patch is corrupt"),
+            PatchError::SourceSize { expected, actual } => {
+                write!(f, "the patch is for a ROM of {} bytes, got {}", expected, actual)
+            }
+            PatchError::BadSourceChecksum { expected, computed } => {
+                write!(f, "the patch is for a ROM with CRC32 {:08x}, got {:08x}", expected, computed)
+            }
+            PatchError::BadTargetChecksum { expected, computed } => {
+                write!(f, "patched ROM CRC32 is {:08x}, expected {:08x}", computed, expected)
+            }
+            PatchError::BadPatchChecksum { expected, computed } => {
+                write!(f, "patch CRC32 is {:08x}, computed {:08x}", expected, computed)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+/// Return the patched copy of `rom`
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match Format::detect(patch) {
+        Some(Format::Ips) => apply_ips(rom, patch),
+        Some(Format::Ups) => apply_ups(rom, patch),
+        Some(Format::Bps) => apply_bps(rom, patch),
+        None => Err(PatchError::UnknownFormat),
+    }
+}
+
+/// Sequential reads of the patch, running past the end is a `Corrupt` error
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], position: usize) -> Reader<'a> {
+        Reader { data, position }
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], PatchError> {
+        let end = self.position.checked_add(count).ok_or(PatchError::Corrupt)?;
+        let bytes = self.data.get(self.position..end).ok_or(PatchError::Corrupt)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, count: usize) -> Result<usize, PatchError> {
+        Ok(self.bytes(count)?.iter().fold(0, |value, &byte| value << 8 | byte as usize))
+    }
+
+    /// Variable length number of UPS and BPS: 7 bits per byte, the last one has bit 7 set
+    fn number(&mut self) -> Result<usize, PatchError> {
+        let (mut value, mut shift) = (0usize, 1usize);
+        loop {
+            let byte = self.byte()?;
+            value = value.checked_add((byte & 0x7F) as usize * shift).ok_or(PatchError::Corrupt)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(128).ok_or(PatchError::Corrupt)?;
+            value = value.checked_add(shift).ok_or(PatchError::Corrupt)?;
+        }
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut target = rom.to_vec();
+    let mut reader = Reader::new(patch, 5);
+    loop {
+        let offset = reader.be(3)?;
+        if offset == 0x454F46 {
+            // "EOF", optionally followed by the size to truncate the ROM to
+            if let Ok(size) = reader.be(3) {
+                target.truncate(size);
+            }
+            return Ok(target);
+        }
+        let data = match reader.be(2)? {
+            0 => {
+                let length = reader.be(2)?;
+                vec![reader.byte()?; length]
+            }
+            length => reader.bytes(length)?.to_vec(),
+        };
+        if target.len() < offset + data.len() {
+            target.resize(offset + data.len(), 0);
+        }
+        target[offset..offset + data.len()].copy_from_slice(&data);
+    }
+}
+
+/// CRC32s at the end of UPS and BPS patches: (source, target), the patch one is checked here
+fn footer(rom: &[u8], patch: &[u8]) -> Result<(u32, u32), PatchError> {
+    if patch.len() < 16 {
+        return Err(PatchError::Corrupt);
+    }
+    let crc = |offset: usize| u32::from_le_bytes(patch[offset..offset + 4].try_into().unwrap());
+    let end = patch.len() - 12;
+    let (computed, expected) = (crc32(&patch[..end + 8]), crc(end + 8));
+    if computed != expected {
+        return Err(PatchError::BadPatchChecksum { expected, computed });
+    }
+    let (source, computed) = (crc(end), crc32(rom));
+    if source != computed {
+        return Err(PatchError::BadSourceChecksum { expected: source, computed });
+    }
+    Ok((source, crc(end + 4)))
+}
+
+fn check_target(target: &[u8], expected: u32) -> Result<(), PatchError> {
+    match crc32(target) {
+        computed if computed == expected => Ok(()),
+        computed => Err(PatchError::BadTargetChecksum { expected, computed }),
+    }
+}
+
+fn check_source_size(rom: &[u8], expected: usize) -> Result<(), PatchError> {
+    match rom.len() {
+        actual if actual == expected => Ok(()),
+        actual => Err(PatchError::SourceSize { expected, actual }),
+    }
+}
+
+/// Size of the patched ROM, checked before it is allocated
+fn target_size(reader: &mut Reader) -> Result<usize, PatchError> {
+    match reader.number()? {
+        size if size > MAX_ROM_SIZE => Err(PatchError::Corrupt),
+        size => Ok(size),
+    }
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (_, target_crc) = footer(rom, patch)?;
+    let end = patch.len() - 12;
+    let mut reader = Reader::new(&patch[..end], 4);
+    let source_size = reader.number()?;
+    let target_size = target_size(&mut reader)?;
+    check_source_size(rom, source_size)?;
+
+    let mut target = rom.to_vec();
+    target.resize(target_size, 0);
+    let mut position = 0usize;
+    while reader.position < end {
+        position = position.checked_add(reader.number()?).ok_or(PatchError::Corrupt)?;
+        if position > target.len() {
+            return Err(PatchError::Corrupt);
+        }
+        // Bytes XORed with the ROM, up to and including a 0
+        loop {
+            let byte = reader.byte()?;
+            if byte != 0 {
+                *target.get_mut(position).ok_or(PatchError::Corrupt)? ^= byte;
+            }
+            position += 1;
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (_, target_crc) = footer(rom, patch)?;
+    let end = patch.len() - 12;
+    let mut reader = Reader::new(&patch[..end], 4);
+    let source_size = reader.number()?;
+    let target_size = target_size(&mut reader)?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+    check_source_size(rom, source_size)?;
+
+    let mut target = Vec::with_capacity(target_size);
+    let (mut source_offset, mut target_offset) = (0usize, 0usize);
+    let relative = |offset: usize, delta: usize| {
+        let distance = delta >> 1;
+        if delta & 1 != 0 { offset.checked_sub(distance) } else { offset.checked_add(distance) }.ok_or(PatchError::Corrupt)
+    };
+    while reader.position < end {
+        let action = reader.number()?;
+        let length = (action >> 2) + 1;
+        match action & 3 {
+            // Source read: the ROM byte at the same offset
+            0 => {
+                let start = target.len();
+                target.extend_from_slice(rom.get(start..start + length).ok_or(PatchError::Corrupt)?);
+            }
+            // Target read: bytes from the patch
+            1 => target.extend_from_slice(reader.bytes(length)?),
+            // Source copy: from a moving offset in the ROM
+            2 => {
+                source_offset = relative(source_offset, reader.number()?)?;
+                let end = source_offset.checked_add(length).ok_or(PatchError::Corrupt)?;
+                target.extend_from_slice(rom.get(source_offset..end).ok_or(PatchError::Corrupt)?);
+                source_offset = end;
+            }
+            // Target copy: from a moving offset in the output, can overlap what it writes
+            _ => {
+                target_offset = relative(target_offset, reader.number()?)?;
+                for _ in 0..length {
+                    if target.len() == target_size {
+                        return Err(PatchError::Corrupt);
+                    }
+                    let byte = *target.get(target_offset).ok_or(PatchError::Corrupt)?;
+                    target.push(byte);
+                    target_offset = target_offset.checked_add(1).ok_or(PatchError::Corrupt)?;
+                }
+            }
+        }
+        if target.len() > target_size {
+            return Err(PatchError::Corrupt);
+        }
+    }
+    if target.len() != target_size {
+        return Err(PatchError::Corrupt);
+    }
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(mut value: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte | 0x80);
+                return bytes;
+            }
+            bytes.push(byte);
+            value -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_numbers() {
+        for value in [0, 1, 127, 128, 300, 0x4000, 0x123456] {
+            assert_eq!(Reader::new(&number(value), 0).number().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_ips() {
+        let rom = [0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xAA, 0xBB]); // 2 bytes at 1
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 4, 0xCC]); // 4 times 0xCC at 6
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&rom, &patch).unwrap(), [0, 0xAA, 0xBB, 0, 0, 0, 0xCC, 0xCC, 0xCC, 0xCC]);
+
+        patch.extend_from_slice(&[0, 0, 4]);
+        assert_eq!(apply(&rom, &patch).unwrap(), [0, 0xAA, 0xBB, 0]);
+        assert_eq!(apply(&rom, &patch[..patch.len() - 7]), Err(PatchError::Corrupt));
+        assert_eq!(apply(&rom, b"NOPE"), Err(PatchError::UnknownFormat));
+    }
+
+    #[test]
+    fn test_ups() {
+        let rom = [1u8, 2, 3, 4];
+        let target = [1u8, 7, 3, 4, 5];
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(number(4));
+        patch.extend(number(5));
+        patch.extend(number(1));
+        patch.extend_from_slice(&[2 ^ 7, 0]);
+        patch.extend(number(1));
+        patch.extend_from_slice(&[5, 0]);
+        let patch = with_footer(patch, &rom, &target);
+        assert_eq!(apply(&rom, &patch).unwrap(), target);
+
+        assert!(matches!(apply(&[1, 2, 3, 5], &patch), Err(PatchError::BadSourceChecksum { .. })));
+        let mut corrupt = patch.clone();
+        corrupt[8] ^= 1;
+        assert!(matches!(apply(&rom, &corrupt), Err(PatchError::BadPatchChecksum { .. })));
+    }
+
+    #[test]
+    fn test_bps() {
+        let rom = b"ABCDEFGH";
+        let target = b"ABCxyxyxyEF";
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(8));
+        patch.extend(number(11));
+        patch.extend(number(2));
+        patch.extend_from_slice(b"{}");
+        patch.extend(number((3 - 1) << 2)); // Source read "ABC"
+        patch.extend(number((2 - 1) << 2 | 1)); // Target read "xy"
+        patch.extend_from_slice(b"xy");
+        patch.extend(number((4 - 1) << 2 | 3)); // Target copy "xyxy" from 3
+        patch.extend(number(3 << 1));
+        patch.extend(number((2 - 1) << 2 | 2)); // Source copy "EF" from 4
+        patch.extend(number(4 << 1));
+        let patch = with_footer(patch, rom, target);
+        assert_eq!(apply(rom, &patch).unwrap(), target);
+
+        assert_eq!(apply(b"ABCDEFG", &patch), Err(PatchError::BadSourceChecksum { expected: crc32(rom), computed: crc32(b"ABCDEFG") }));
+        let wrong = with_footer(patch[..patch.len() - 12].to_vec(), rom, b"ABC");
+        assert!(matches!(apply(rom, &wrong), Err(PatchError::BadTargetChecksum { .. })));
+    }
+
+    #[test]
+    fn test_corrupt_sizes() {
+        let mut overflow = vec![0x7F; 16];
+        overflow.push(0xFF);
+        assert_eq!(Reader::new(&overflow, 0).number(), Err(PatchError::Corrupt));
+
+        let rom = [0u8; 4];
+        for magic in [b"UPS1", b"BPS1"] {
+            let mut patch = magic.to_vec();
+            patch.extend(number(4));
+            patch.extend(number(usize::MAX >> 8));
+            patch.extend(number(0));
+            let patch = with_footer(patch, &rom, &rom);
+            assert_eq!(apply(&rom, &patch), Err(PatchError::Corrupt));
+        }
+
+        // Target copy far past the target size
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(4));
+        patch.extend(number(4));
+        patch.extend(number(0));
+        patch.extend(number(1 << 2 | 1));
+        patch.extend_from_slice(b"ab");
+        patch.extend(number((1 << 40) << 2 | 3));
+        patch.extend(number(0));
+        let patch = with_footer(patch, &rom, &rom);
+        assert_eq!(apply(&rom, &patch), Err(PatchError::Corrupt));
+
+        // Metadata running past the end of the address space
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(4));
+        patch.extend(number(4));
+        patch.extend(number(usize::MAX - 8));
+        let patch = with_footer(patch, &rom, &rom);
+        assert_eq!(apply(&rom, &patch), Err(PatchError::Corrupt));
+
+        // Source copy from an offset near the end of the address space
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(4));
+        patch.extend(number(4));
+        patch.extend(number(0));
+        patch.extend(number(3 << 2 | 2));
+        patch.extend(number((usize::MAX >> 1) << 1));
+        let patch = with_footer(patch, &rom, &rom);
+        assert_eq!(apply(&rom, &patch), Err(PatchError::Corrupt));
+
+        // UPS relative offset near the end of the address space
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(number(4));
+        patch.extend(number(4));
+        patch.extend(number(1));
+        patch.extend_from_slice(&[1, 0]);
+        patch.extend(number(usize::MAX - 2));
+        patch.extend_from_slice(&[1, 0]);
+        let patch = with_footer(patch, &rom, &rom);
+        assert_eq!(apply(&rom, &patch), Err(PatchError::Corrupt));
+    }
+}
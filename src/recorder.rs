@@ -0,0 +1,506 @@
+//! Recording of the frames into an animated GIF or an uncompressed AVI
+//!
+//! The AVI only has a video stream for now, audio will be added with the APU.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::image::Image;
+
+/// The PPU draws a frame every 70224 cycles at 4194304 Hz
+const FRAME_CYCLES: u32 = 70224;
+const CYCLES_PER_SECOND: u32 = 4_194_304;
+
+/// GIF delays are in centiseconds and most viewers don't honour anything under 2
+const GIF_MIN_DELAY: u64 = 2;
+
+pub struct Recorder {
+    encoder: Encoder,
+}
+
+enum Encoder {
+    Gif(GifEncoder<BufWriter<File>>),
+    Avi(AviEncoder<BufWriter<File>>),
+}
+
+impl Recorder {
+    /// Create the file, the format is chosen from the extension: `.gif` or `.avi`
+    pub fn create<P: AsRef<Path>>(path: P, width: usize, height: usize) -> io::Result<Recorder> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("").to_ascii_lowercase();
+        let encoder = match extension.as_str() {
+            "gif" => Encoder::Gif(GifEncoder::new(BufWriter::new(File::create(path)?), width, height)?),
+            "avi" => Encoder::Avi(AviEncoder::new(BufWriter::new(File::create(path)?), width, height)?),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "recordings must be .gif or .avi files")),
+        };
+        Ok(Recorder { encoder })
+    }
+
+    pub fn add_frame(&mut self, image: &Image) -> io::Result<()> {
+        match &mut self.encoder {
+            Encoder::Gif(gif) => gif.add_frame(image),
+            Encoder::Avi(avi) => avi.add_frame(image),
+        }
+    }
+
+    /// Write what is still buffered and complete the headers
+    pub fn finish(self) -> io::Result<()> {
+        match self.encoder {
+            Encoder::Gif(gif) => gif.finish().map(drop),
+            Encoder::Avi(avi) => avi.finish().map(drop),
+        }
+    }
+}
+
+/// Time of a frame in centiseconds, rounded
+fn frame_time_cs(frame: u64) -> u64 {
+    (frame * FRAME_CYCLES as u64 * 100 + CYCLES_PER_SECOND as u64 / 2) / CYCLES_PER_SECOND as u64
+}
+
+/// Animated GIF, looping forever.
+/// A frame is only written once it changes, so static screens cost nothing, and frames
+/// shown for less than `GIF_MIN_DELAY` are replaced by the next one.
+pub struct GifEncoder<W: Write> {
+    writer: W,
+    width: usize,
+    height: usize,
+    frames: u64,
+    pending: Option<(Image, u64)>,
+}
+
+impl<W: Write> GifEncoder<W> {
+    pub fn new(mut writer: W, width: usize, height: usize) -> io::Result<GifEncoder<W>> {
+        writer.write_all(b"GIF89a")?;
+        writer.write_all(&(width as u16).to_le_bytes())?;
+        writer.write_all(&(height as u16).to_le_bytes())?;
+        writer.write_all(&[0x00, 0x00, 0x00])?; // No global colour table
+        writer.write_all(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00")?; // Loop forever
+        Ok(GifEncoder { writer, width, height, frames: 0, pending: None })
+    }
+
+    pub fn add_frame(&mut self, image: &Image) -> io::Result<()> {
+        let time = frame_time_cs(self.frames);
+        self.frames += 1;
+
+        match &mut self.pending {
+            Some((pending, _)) if pending.data == image.data => {}
+            Some((pending, start)) if time - *start < GIF_MIN_DELAY => pending.clone_from(image),
+            Some(_) => {
+                let (pending, start) = self.pending.replace((image.clone(), time)).unwrap();
+                self.write_frame(&pending, time - start)?;
+            }
+            None => self.pending = Some((image.clone(), time)),
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some((pending, start)) = self.pending.take() {
+            let delay = (frame_time_cs(self.frames) - start).max(GIF_MIN_DELAY);
+            self.write_frame(&pending, delay)?;
+        }
+        self.writer.write_all(&[0x3B])?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_frame(&mut self, image: &Image, delay: u64) -> io::Result<()> {
+        let (colours, indices) = index_colours(image);
+
+        // Graphic control extension with the delay
+        self.writer.write_all(&[0x21, 0xF9, 0x04, 0x00])?;
+        self.writer.write_all(&(delay.min(u16::MAX as u64) as u16).to_le_bytes())?;
+        self.writer.write_all(&[0x00, 0x00])?;
+
+        // Image descriptor with a local colour table of 2^bits colours
+        let bits = (usize::BITS - (colours.len() - 1).leading_zeros()).max(1) as u8;
+        self.writer.write_all(&[0x2C, 0, 0, 0, 0])?;
+        self.writer.write_all(&(self.width as u16).to_le_bytes())?;
+        self.writer.write_all(&(self.height as u16).to_le_bytes())?;
+        self.writer.write_all(&[0x80 | (bits - 1)])?;
+        for i in 0..1 << bits {
+            self.writer.write_all(&colours.get(i).copied().unwrap_or([0; 3]))?;
+        }
+
+        let min_code_size = bits.max(2);
+        self.writer.write_all(&[min_code_size])?;
+        for block in lzw_encode(&indices, min_code_size).chunks(255) {
+            self.writer.write_all(&[block.len() as u8])?;
+            self.writer.write_all(block)?;
+        }
+        self.writer.write_all(&[0x00])
+    }
+}
+
+/// Colour table and the index of every pixel in it.
+/// With more than 256 colours (not possible with DMG palettes), they are reduced to RGB332.
+fn index_colours(image: &Image) -> (Vec<[u8; 3]>, Vec<u8>) {
+    let mut colours = Vec::new();
+    let mut lookup = HashMap::new();
+    let mut indices = Vec::with_capacity(image.width * image.height);
+    for pixel in image.data.chunks(3) {
+        let colour = [pixel[0], pixel[1], pixel[2]];
+        let index = *lookup.entry(colour).or_insert_with(|| {
+            colours.push(colour);
+            colours.len() - 1
+        });
+        if index > 0xFF {
+            return rgb332(image);
+        }
+        indices.push(index as u8);
+    }
+    (colours, indices)
+}
+
+fn rgb332(image: &Image) -> (Vec<[u8; 3]>, Vec<u8>) {
+    let colours = (0..=0xFFu8).map(|i| [(i >> 5) * 0x24, (i >> 2 & 0x07) * 0x24, (i & 0x03) * 0x55]).collect();
+    let indices = image.data.chunks(3).map(|p| (p[0] & 0xE0) | (p[1] & 0xE0) >> 3 | p[2] >> 6).collect();
+    (colours, indices)
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u8,
+}
+
+impl BitWriter {
+    /// GIF codes are packed from the least significant bit
+    fn write(&mut self, code: u16, size: u8) {
+        self.buffer |= (code as u32) << self.bits;
+        self.bits += size;
+        while self.bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Codes written by the LZW encoder. The decoder adds its table entries one code late,
+/// the code size has to follow its table rather than the encoder's.
+struct CodeWriter {
+    bits: BitWriter,
+    min_code_size: u8,
+    code_size: u8,
+    decoder_next: u16,
+    first: bool,
+}
+
+impl CodeWriter {
+    fn clear(&mut self) {
+        self.bits.write(1 << self.min_code_size, self.code_size);
+        self.code_size = self.min_code_size + 1;
+        self.decoder_next = (1 << self.min_code_size) + 2;
+        self.first = true;
+    }
+
+    fn emit(&mut self, code: u16) {
+        self.bits.write(code, self.code_size);
+        if !self.first {
+            self.decoder_next += 1;
+        }
+        self.first = false;
+        if self.decoder_next == 1 << self.code_size && self.code_size < 12 {
+            self.code_size += 1;
+        }
+    }
+}
+
+/// Variable-length LZW of the GIF format
+fn lzw_encode(indices: &[u8], min_code_size: u8) -> Vec<u8> {
+    let end = (1u16 << min_code_size) + 1;
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = end + 1;
+    let mut writer = CodeWriter {
+        bits: BitWriter::default(),
+        min_code_size,
+        code_size: min_code_size + 1,
+        decoder_next: end + 1,
+        first: true,
+    };
+
+    writer.clear();
+    if let Some((&head, tail)) = indices.split_first() {
+        let mut prefix = head as u16;
+        for &index in tail {
+            if let Some(&code) = table.get(&(prefix, index)) {
+                prefix = code;
+                continue;
+            }
+
+            writer.emit(prefix);
+            if next < 4096 {
+                table.insert((prefix, index), next);
+                next += 1;
+            } else {
+                writer.clear();
+                table.clear();
+                next = end + 1;
+            }
+            prefix = index as u16;
+        }
+        writer.emit(prefix);
+    }
+    writer.bits.write(end, writer.code_size);
+    writer.bits.finish()
+}
+
+/// Uncompressed AVI with a single 24-bit DIB video stream.
+/// The sizes and frame counts in the headers are patched by `finish`.
+pub struct AviEncoder<W: Write + Seek> {
+    writer: W,
+    width: usize,
+    height: usize,
+    frames: u32,
+    /// Offset and size of each frame for the idx1 index
+    index: Vec<(u32, u32)>,
+}
+
+// Offsets of the fields patched once the frame count is known
+const AVI_RIFF_SIZE: u64 = 4;
+const AVI_TOTAL_FRAMES: u64 = 48;
+const AVI_STREAM_LENGTH: u64 = 140;
+const AVI_MOVI_SIZE: u64 = 216;
+const AVI_MOVI_START: u64 = 220;
+
+impl<W: Write + Seek> AviEncoder<W> {
+    pub fn new(mut writer: W, width: usize, height: usize) -> io::Result<AviEncoder<W>> {
+        let frame_size = (width * 3).next_multiple_of(4) * height;
+        let micros_per_frame = FRAME_CYCLES as u64 * 1_000_000 / CYCLES_PER_SECOND as u64;
+
+        let mut header = Vec::with_capacity(AVI_MOVI_START as usize + 4);
+        let dword = |header: &mut Vec<u8>, value: u32| header.extend_from_slice(&value.to_le_bytes());
+
+        header.extend_from_slice(b"RIFF");
+        dword(&mut header, 0);
+        header.extend_from_slice(b"AVI LIST");
+        dword(&mut header, 192);
+        header.extend_from_slice(b"hdrlavih");
+        dword(&mut header, 56);
+        dword(&mut header, micros_per_frame as u32);
+        dword(&mut header, (frame_size as u64 * 1_000_000 / micros_per_frame) as u32);
+        dword(&mut header, 0);
+        dword(&mut header, 0x10); // AVIF_HASINDEX
+        dword(&mut header, 0); // Total frames
+        dword(&mut header, 0);
+        dword(&mut header, 1); // Streams
+        dword(&mut header, frame_size as u32);
+        dword(&mut header, width as u32);
+        dword(&mut header, height as u32);
+        header.extend_from_slice(&[0; 16]);
+
+        header.extend_from_slice(b"LIST");
+        dword(&mut header, 116);
+        header.extend_from_slice(b"strlstrh");
+        dword(&mut header, 56);
+        header.extend_from_slice(b"vidsDIB ");
+        dword(&mut header, 0); // Flags
+        dword(&mut header, 0); // Priority and language
+        dword(&mut header, 0);
+        dword(&mut header, FRAME_CYCLES); // Scale and rate: rate / scale frames per second
+        dword(&mut header, CYCLES_PER_SECOND);
+        dword(&mut header, 0);
+        dword(&mut header, 0); // Length in frames
+        dword(&mut header, frame_size as u32);
+        dword(&mut header, u32::MAX); // Default quality
+        dword(&mut header, 0);
+        header.extend_from_slice(&[0, 0, 0, 0]);
+        header.extend_from_slice(&(width as u16).to_le_bytes());
+        header.extend_from_slice(&(height as u16).to_le_bytes());
+
+        // BITMAPINFOHEADER, a positive height means bottom-up lines
+        header.extend_from_slice(b"strf");
+        dword(&mut header, 40);
+        dword(&mut header, 40);
+        dword(&mut header, width as u32);
+        dword(&mut header, height as u32);
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&24u16.to_le_bytes());
+        dword(&mut header, 0); // BI_RGB
+        dword(&mut header, frame_size as u32);
+        header.extend_from_slice(&[0; 16]);
+
+        header.extend_from_slice(b"LIST");
+        dword(&mut header, 4);
+        header.extend_from_slice(b"movi");
+        debug_assert_eq!(header.len() as u64, AVI_MOVI_START + 4);
+
+        writer.write_all(&header)?;
+        Ok(AviEncoder { writer, width, height, frames: 0, index: Vec::new() })
+    }
+
+    pub fn add_frame(&mut self, image: &Image) -> io::Result<()> {
+        let line_size = (self.width * 3).next_multiple_of(4);
+        let mut frame = vec![0; line_size * self.height];
+        for (y, line) in frame.chunks_mut(line_size).enumerate() {
+            let source = &image.data[(self.height - 1 - y) * self.width * 3..][..self.width * 3];
+            for (pixel, rgb) in line.chunks_mut(3).zip(source.chunks(3)) {
+                pixel.copy_from_slice(&[rgb[2], rgb[1], rgb[0]]);
+            }
+        }
+
+        let offset = self.index.last().map_or(4, |&(offset, size)| offset + 8 + size.next_multiple_of(2));
+        self.writer.write_all(b"00db")?;
+        self.writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&frame)?;
+        if frame.len() % 2 == 1 {
+            self.writer.write_all(&[0])?;
+        }
+        self.index.push((offset, frame.len() as u32));
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let movi_size = self.index.last().map_or(4, |&(offset, size)| offset + 8 + size.next_multiple_of(2));
+
+        self.writer.write_all(b"idx1")?;
+        self.writer.write_all(&(self.index.len() as u32 * 16).to_le_bytes())?;
+        for &(offset, size) in &self.index {
+            self.writer.write_all(b"00db")?;
+            self.writer.write_all(&0x10u32.to_le_bytes())?; // AVIIF_KEYFRAME
+            self.writer.write_all(&offset.to_le_bytes())?;
+            self.writer.write_all(&size.to_le_bytes())?;
+        }
+        let riff_size = self.writer.stream_position()? as u32 - 8;
+
+        for (position, value) in [
+            (AVI_RIFF_SIZE, riff_size),
+            (AVI_TOTAL_FRAMES, self.frames),
+            (AVI_STREAM_LENGTH, self.frames),
+            (AVI_MOVI_SIZE, movi_size),
+        ] {
+            self.writer.seek(SeekFrom::Start(position))?;
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Reference decoder following the GIF specification
+    fn lzw_decode(data: &[u8], min_code_size: u8) -> Vec<u8> {
+        let clear = 1u16 << min_code_size;
+        let end = clear + 1;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let reset = |table: &mut Vec<Vec<u8>>| {
+            table.clear();
+            table.extend((0..=end).map(|i| vec![i as u8]));
+        };
+        reset(&mut table);
+
+        let mut output = Vec::new();
+        let mut code_size = min_code_size + 1;
+        let mut previous: Option<Vec<u8>> = None;
+        let (mut buffer, mut bits, mut bytes) = (0u32, 0u8, data.iter());
+        loop {
+            while bits < code_size {
+                buffer |= (*bytes.next().unwrap() as u32) << bits;
+                bits += 8;
+            }
+            let code = (buffer & ((1 << code_size) - 1)) as u16;
+            buffer >>= code_size;
+            bits -= code_size;
+
+            if code == clear {
+                reset(&mut table);
+                code_size = min_code_size + 1;
+                previous = None;
+                continue;
+            }
+            if code == end {
+                return output;
+            }
+
+            let entry = match (table.get(code as usize), &previous) {
+                (Some(entry), _) => entry.clone(),
+                (None, Some(previous)) => [previous.clone(), vec![previous[0]]].concat(),
+                (None, None) => panic!("invalid code"),
+            };
+            if let Some(previous) = previous {
+                if table.len() < 4096 {
+                    table.push([previous, vec![entry[0]]].concat());
+                }
+            }
+            if table.len() == 1 << code_size && code_size < 12 {
+                code_size += 1;
+            }
+            output.extend_from_slice(&entry);
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn test_lzw_roundtrip() {
+        let patterns: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![1],
+            vec![0; 10_000],
+            (0..20_000u32).map(|i| (i * i / 7 % 4) as u8).collect(),
+            (0..50_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect(),
+        ];
+        for (i, indices) in patterns.iter().enumerate() {
+            let min_code_size = if i == 4 { 8 } else { 2 };
+            assert_eq!(&lzw_decode(&lzw_encode(indices, min_code_size), min_code_size), indices);
+        }
+    }
+
+    #[test]
+    fn test_gif() {
+        let black = Image::new(2, 2, vec![0; 12]);
+        let white = Image::new(2, 2, vec![0xFF; 12]);
+
+        let mut gif = GifEncoder::new(Cursor::new(Vec::new()), 2, 2).unwrap();
+        for image in [&black, &black, &black, &white, &white] {
+            gif.add_frame(image).unwrap();
+        }
+        let gif = gif.finish().unwrap().into_inner();
+
+        assert_eq!(&gif[..6], b"GIF89a");
+        assert_eq!(gif.last(), Some(&0x3B));
+        // The 3 black frames are merged: 5 centiseconds
+        let control = gif.windows(4).position(|w| w == [0x21, 0xF9, 0x04, 0x00]).unwrap();
+        assert_eq!(&gif[control + 4..control + 6], &[5, 0]);
+        assert_eq!(gif.windows(4).filter(|w| *w == [0x21, 0xF9, 0x04, 0x00]).count(), 2);
+    }
+
+    #[test]
+    fn test_avi() {
+        let image = Image::new(2, 2, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        let mut avi = AviEncoder::new(Cursor::new(Vec::new()), 2, 2).unwrap();
+        avi.add_frame(&image).unwrap();
+        avi.add_frame(&image).unwrap();
+        let avi = avi.finish().unwrap().into_inner();
+
+        let dword = |offset: u64| u32::from_le_bytes(avi[offset as usize..offset as usize + 4].try_into().unwrap());
+        assert_eq!(&avi[..4], b"RIFF");
+        assert_eq!(dword(AVI_RIFF_SIZE) as usize, avi.len() - 8);
+        assert_eq!(dword(AVI_TOTAL_FRAMES), 2);
+        assert_eq!(dword(AVI_STREAM_LENGTH), 2);
+        assert_eq!(&avi[AVI_MOVI_START as usize..][..4], b"movi");
+
+        // Bottom-up BGR lines padded to 4 bytes
+        let frame = AVI_MOVI_START as usize + 12;
+        assert_eq!(&avi[frame - 8..frame - 4], b"00db");
+        assert_eq!(&avi[frame..frame + 16], &[9, 8, 7, 12, 11, 10, 0, 0, 3, 2, 1, 6, 5, 4, 0, 0]);
+        assert_eq!(dword(AVI_MOVI_SIZE) as usize, 4 + 2 * (8 + 16));
+        assert_eq!(&avi[AVI_MOVI_START as usize + dword(AVI_MOVI_SIZE) as usize..][..4], b"idx1");
+    }
+}
@@ -40,6 +40,22 @@ impl Registers {
         }
     }
 
+    /// Registers at power on, before the boot ROM runs
+    pub fn power_on() -> Registers {
+        Registers {
+            a: 0,
+            f: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            sp: 0,
+            pc: 0,
+        }
+    }
+
     pub fn bc(&self) -> u16 {
         (self.b as u16) << 8 | self.c as u16
     }
@@ -242,11 +258,11 @@ mod tests {
     fn test_registers_get_flag() {
         let mut registers = Registers::new();
         registers.f = 0xb0;
-        assert_eq!(registers.get_flag(Flag::Zero), true);
-        assert_eq!(registers.get_flag(Flag::Sub), false);
-        assert_eq!(registers.get_flag(Flag::HalfCarry), true);
-        assert_eq!(registers.get_flag(Flag::Carry), true);
+        assert!(registers.get_flag(Flag::Zero));
+        assert!(!registers.get_flag(Flag::Sub));
+        assert!(registers.get_flag(Flag::HalfCarry));
+        assert!(registers.get_flag(Flag::Carry));
     }
     
 
-}
\ No newline at end of file
+}
\ No newline at end of file
@@ -0,0 +1,91 @@
+/// Components that can ask the scheduler to be synchronised at a given cycle
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Ppu,    // Next VBlank interrupt
+    Timer,  // Next TIMA overflow reload
+    Dma,    // End of the OAM DMA transfer
+}
+
+const EVENT_COUNT: usize = 3;
+
+/// Cycle-timestamped list of the next event of each component
+/// Components are only stepped when one of their events is due (or when their registers are accessed),
+/// so the CPU can run without polling every component after each instruction.
+pub struct Scheduler {
+    now: u64,
+    events: [Option<u64>; EVENT_COUNT],
+    next: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            now: 0,
+            events: [None; EVENT_COUNT],
+            next: u64::MAX,
+        }
+    }
+
+    /// Number of cycles elapsed since the start of the emulation
+    #[inline(always)]
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Register `event` to happen `cycles` cycles from now, replacing the previous one
+    pub fn schedule(&mut self, event: Event, cycles: u32) {
+        self.events[event as usize] = Some(self.now + cycles as u64);
+        self.update_next();
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.events[event as usize] = None;
+        self.update_next();
+    }
+
+    /// Cycles left before the next event, `None` if nothing is scheduled
+    pub fn cycles_until_next(&self) -> Option<u64> {
+        if self.next == u64::MAX {
+            None
+        } else {
+            Some(self.next.saturating_sub(self.now))
+        }
+    }
+
+    /// Advance the clock, return true if an event is due
+    #[inline(always)]
+    pub fn advance(&mut self, cycles: u32) -> bool {
+        self.now += cycles as u64;
+        self.now >= self.next
+    }
+
+    fn update_next(&mut self) {
+        self.next = self.events.iter().flatten().copied().min().unwrap_or(u64::MAX);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scheduler_due() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::Timer, 100);
+        scheduler.schedule(Event::Ppu, 40);
+        assert_eq!(scheduler.cycles_until_next(), Some(40));
+        assert!(!scheduler.advance(36));
+        assert!(scheduler.advance(4));
+        assert_eq!(scheduler.now(), 40);
+    }
+
+    #[test]
+    fn test_scheduler_cancel() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::Dma, 8);
+        scheduler.cancel(Event::Dma);
+        assert_eq!(scheduler.cycles_until_next(), None);
+        assert!(!scheduler.advance(16));
+    }
+}
@@ -0,0 +1,203 @@
+//! RAM search to find the address of a value (lives, money...) for a cheat: snapshot WRAM, HRAM
+//! and the mapped cartridge RAM, then narrow down the candidates as the value changes in the game
+//!
+//! ```
+//! use rusty_boy::gameboy::GameboyBuilder;
+//! use rusty_boy::search::{Filter, RamSearch, Size};
+//!
+//! let mut gameboy = GameboyBuilder::new(vec![0; 0x8000]).build().unwrap();
+//! let mut search = RamSearch::new(&gameboy, Size::Byte);
+//! gameboy.poke(0xC123, 3);
+//! search.filter(&gameboy, Filter::Value(3));
+//! gameboy.poke(0xC123, 2);
+//! search.filter(&gameboy, Filter::Decreased);
+//! assert_eq!(search.candidates()[0].address, 0xC123);
+//! search.candidates()[0].freeze(&mut gameboy, 9);
+//! ```
+
+use std::ops::RangeInclusive;
+
+use crate::gameboy::Gameboy;
+
+const WRAM: RangeInclusive<u16> = 0xC000..=0xDFFF;
+const HRAM: RangeInclusive<u16> = 0xFF80..=0xFFFE;
+const SRAM: RangeInclusive<u16> = 0xA000..=0xBFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Size {
+    Byte,
+    /// 16-bit little-endian
+    Word,
+}
+
+/// Comparison of the current values with the ones of the previous search
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    Value(u16),
+}
+
+impl Filter {
+    fn matches(self, previous: u16, value: u16) -> bool {
+        match self {
+            Filter::Equal => value == previous,
+            Filter::Changed => value != previous,
+            Filter::Increased => value > previous,
+            Filter::Decreased => value < previous,
+            Filter::Value(expected) => value == expected,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Candidate {
+    pub address: u16,
+    pub size: Size,
+    /// Value at the last search
+    pub value: u16,
+    pub previous: u16,
+}
+
+impl Candidate {
+    fn bytes(&self, value: u16) -> Vec<(u16, u8)> {
+        match self.size {
+            Size::Byte => vec![(self.address, value as u8)],
+            Size::Word => {
+                let [low, high] = value.to_le_bytes();
+                vec![(self.address, low), (self.address + 1, high)]
+            }
+        }
+    }
+
+    /// Keep `value` at the address, see `Gameboy::freeze`
+    pub fn freeze(&self, gameboy: &mut Gameboy, value: u16) {
+        for (address, byte) in self.bytes(value) {
+            gameboy.freeze(address, byte);
+        }
+    }
+
+    pub fn unfreeze(&self, gameboy: &mut Gameboy) {
+        for (address, _) in self.bytes(0) {
+            gameboy.unfreeze(address);
+        }
+    }
+
+    /// GameShark codes writing `value`, one per byte, to be added to `Gameboy::cheats_mut`
+    pub fn gameshark_codes(&self, value: u16) -> Vec<String> {
+        self.bytes(value)
+            .into_iter()
+            .map(|(address, byte)| format!("01{:02X}{:02X}{:02X}", byte, address & 0xFF, address >> 8))
+            .collect()
+    }
+}
+
+pub struct RamSearch {
+    size: Size,
+    candidates: Vec<Candidate>,
+}
+
+impl RamSearch {
+    /// Every address of WRAM, HRAM and the mapped cartridge RAM is a candidate
+    pub fn new(gameboy: &Gameboy, size: Size) -> RamSearch {
+        let mut regions = vec![WRAM, HRAM];
+        if gameboy.ram_banks() > 0 {
+            regions.insert(0, SRAM);
+        }
+        let last = match size {
+            Size::Byte => 0,
+            Size::Word => 1,
+        };
+        let candidates = regions
+            .into_iter()
+            .flat_map(|region| *region.start()..=*region.end() - last)
+            .map(|address| {
+                let value = read(gameboy, address, size);
+                Candidate { address, size, value, previous: value }
+            })
+            .collect();
+        RamSearch { size, candidates }
+    }
+
+    /// Start again from every address
+    pub fn reset(&mut self, gameboy: &Gameboy) {
+        *self = RamSearch::new(gameboy, self.size);
+    }
+
+    /// Keep the candidates matching `filter`, their values are updated for the next search.
+    /// Returns the number of candidates left.
+    pub fn filter(&mut self, gameboy: &Gameboy, filter: Filter) -> usize {
+        self.candidates.retain_mut(|candidate| {
+            let value = read(gameboy, candidate.address, candidate.size);
+            if !filter.matches(candidate.value, value) {
+                return false;
+            }
+            candidate.previous = candidate.value;
+            candidate.value = value;
+            true
+        });
+        self.candidates.len()
+    }
+
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    pub fn candidates(&self) -> &[Candidate] {
+        &self.candidates
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+}
+
+fn read(gameboy: &Gameboy, address: u16, size: Size) -> u16 {
+    match size {
+        Size::Byte => gameboy.peek(address) as u16,
+        Size::Word => u16::from_le_bytes([gameboy.peek(address), gameboy.peek(address + 1)]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cheats::Code;
+    use crate::gameboy::test_builder;
+
+    #[test]
+    fn test_search() {
+        let mut gameboy = test_builder(vec![0; 0x8000]).build().unwrap();
+        let mut search = RamSearch::new(&gameboy, Size::Word);
+        assert_eq!(search.len(), 0x2000 - 1 + 0x7F - 1);
+
+        gameboy.poke(0xC100, 0x34);
+        gameboy.poke(0xC101, 0x12);
+        gameboy.poke(0xFF90, 0x34);
+        assert_eq!(search.filter(&gameboy, Filter::Changed), 5);
+        search.filter(&gameboy, Filter::Value(0x1234));
+        assert_eq!(search.len(), 1);
+
+        gameboy.poke(0xC101, 0x13);
+        assert_eq!(search.filter(&gameboy, Filter::Increased), 1);
+        assert_eq!(search.filter(&gameboy, Filter::Equal), 1);
+        assert_eq!(search.filter(&gameboy, Filter::Decreased), 0);
+
+        let candidate = Candidate { address: 0xC100, size: Size::Word, value: 0x1334, previous: 0x1234 };
+        candidate.freeze(&mut gameboy, 0x0999);
+        assert_eq!((gameboy.peek(0xC100), gameboy.peek(0xC101)), (0x99, 0x09));
+        assert_eq!(gameboy.freezes().len(), 2);
+        candidate.unfreeze(&mut gameboy);
+        assert!(gameboy.freezes().is_empty());
+
+        let codes = candidate.gameshark_codes(0x0999);
+        assert_eq!(codes, ["019900C1", "010901C1"]);
+        assert_eq!(Code::parse(&codes[1]).unwrap(), Code::GameShark { bank: 0x01, address: 0xC101, value: 0x09 });
+    }
+}
@@ -0,0 +1,235 @@
+//! Terminal frontend: draws the screen with half-block characters and 24-bit colours
+//! and reads the keyboard in raw mode, so a game can be played over SSH.
+//!
+//! Terminals only report key presses, a key is considered released when no press
+//! (or auto-repeat) has been seen for `HOLD_TIME`.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::keypad::{Key, KeyEvent};
+
+const HOLD_TIME: Duration = Duration::from_millis(150);
+
+/// Input read from the terminal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Input {
+    Key(Key),
+    Quit,
+}
+
+pub struct Terminal {
+    saved_mode: String,
+    input: Receiver<u8>,
+    /// Start of an escape sequence split across reads
+    pending: Vec<u8>,
+    held: Vec<(Key, Instant)>,
+    quit: bool,
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("stdin is not a terminal"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl Terminal {
+    /// Switch the terminal to raw mode on the alternate screen, it is restored on drop
+    pub fn new() -> io::Result<Terminal> {
+        let saved_mode = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+
+        // Reading stdin blocks, so it is done on its own thread
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                match byte {
+                    Ok(byte) if sender.send(byte).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+
+        // Alternate screen, hidden cursor, cleared screen
+        print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+        io::stdout().flush()?;
+
+        Ok(Terminal { saved_mode, input, pending: Vec::new(), held: Vec::new(), quit: false })
+    }
+
+    /// Redraw the RGB24 frame in place, two pixels per character
+    pub fn draw(&mut self, rgb: &[u8], width: usize, height: usize) -> io::Result<()> {
+        let mut out = io::stdout().lock();
+        out.write_all(encode_frame(rgb, width, height).as_bytes())?;
+        out.flush()
+    }
+
+    /// Key events since the last call, releases included
+    pub fn poll(&mut self) -> Vec<KeyEvent> {
+        self.pending.extend(self.input.try_iter());
+        let now = Instant::now();
+        let mut events = Vec::new();
+
+        for input in parse_input(&mut self.pending) {
+            match input {
+                Input::Quit => self.quit = true,
+                Input::Key(key) => match self.held.iter_mut().find(|(held, _)| *held == key) {
+                    Some((_, time)) => *time = now,
+                    None => {
+                        self.held.push((key, now));
+                        events.push(KeyEvent::Press(key));
+                    }
+                },
+            }
+        }
+
+        self.held.retain(|&(key, time)| {
+            let held = now.duration_since(time) < HOLD_TIME;
+            if !held {
+                events.push(KeyEvent::Release(key));
+            }
+            held
+        });
+        events
+    }
+
+    /// True once Q or Ctrl-C has been pressed
+    pub fn quit_requested(&self) -> bool {
+        self.quit
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        let _ = stty(&[&self.saved_mode]);
+    }
+}
+
+/// Escape sequences drawing the frame from the top-left corner.
+/// Each character is an upper half block, the foreground is the top pixel and the background the bottom one.
+/// Colours are only sent when they change.
+pub fn encode_frame(rgb: &[u8], width: usize, height: usize) -> String {
+    let pixel = |x: usize, y: usize| {
+        let i = (y * width + x) * 3;
+        [rgb[i], rgb[i + 1], rgb[i + 2]]
+    };
+
+    let mut out = String::with_capacity(width * height * 12);
+    out.push_str("\x1b[H");
+    for y in (0..height).step_by(2) {
+        let mut colours = None;
+        for x in 0..width {
+            let top = pixel(x, y);
+            let bottom = if y + 1 < height { pixel(x, y + 1) } else { [0, 0, 0] };
+            if colours != Some((top, bottom)) {
+                let _ = write!(
+                    out,
+                    "\x1b[38;2;{};{};{};48;2;{};{};{}m",
+                    top[0], top[1], top[2], bottom[0], bottom[1], bottom[2]
+                );
+                colours = Some((top, bottom));
+            }
+            out.push('▀');
+        }
+        out.push_str("\x1b[0m\r\n");
+    }
+    out
+}
+
+/// Map the bytes read in raw mode to inputs, the bytes are consumed except an escape sequence
+/// cut at the end, which is completed by the next read.
+/// Arrows or WASD: D-pad, X or K: A, Z or J: B, Enter: Start, Space or Backspace: Select.
+/// Escape alone does nothing, it can't be told apart from the start of a sequence.
+pub fn parse_input(bytes: &mut Vec<u8>) -> Vec<Input> {
+    let mut inputs = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let input = match bytes[i] {
+            0x1B if matches!(bytes[i + 1..], [] | [b'[']) => break,
+            0x1B if bytes[i + 1] == b'[' => {
+                i += 2;
+                match bytes[i] {
+                    b'A' => Some(Input::Key(Key::Up)),
+                    b'B' => Some(Input::Key(Key::Down)),
+                    b'C' => Some(Input::Key(Key::Right)),
+                    b'D' => Some(Input::Key(Key::Left)),
+                    _ => None,
+                }
+            }
+            0x1B => None,
+            0x03 | b'q' | b'Q' => Some(Input::Quit),
+            b'w' | b'W' => Some(Input::Key(Key::Up)),
+            b's' | b'S' => Some(Input::Key(Key::Down)),
+            b'a' | b'A' => Some(Input::Key(Key::Left)),
+            b'd' | b'D' => Some(Input::Key(Key::Right)),
+            b'x' | b'X' | b'k' | b'K' => Some(Input::Key(Key::A)),
+            b'z' | b'Z' | b'j' | b'J' => Some(Input::Key(Key::B)),
+            b'\r' | b'\n' => Some(Input::Key(Key::Start)),
+            b' ' | 0x7F | 0x08 => Some(Input::Key(Key::Select)),
+            _ => None,
+        };
+        inputs.extend(input);
+        i += 1;
+    }
+    bytes.drain(..i);
+    inputs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_frame() {
+        // 2x2: white/black on the first column, two reds on the second
+        let rgb = [255, 255, 255, 255, 0, 0, 0, 0, 0, 255, 0, 0];
+        let frame = encode_frame(&rgb, 2, 2);
+        assert_eq!(
+            frame,
+            "\x1b[H\x1b[38;2;255;255;255;48;2;0;0;0m▀\x1b[38;2;255;0;0;48;2;255;0;0m▀\x1b[0m\r\n"
+        );
+
+        // Same colours on a line are only sent once
+        let frame = encode_frame(&[0; 4 * 2 * 3], 4, 2);
+        assert_eq!(frame.matches("\x1b[38").count(), 1);
+    }
+
+    #[test]
+    fn test_parse_input() {
+        let mut bytes = b"\x1b[Ax\x1b[Dzq\r\x1bx".to_vec();
+        assert_eq!(
+            parse_input(&mut bytes),
+            vec![
+                Input::Key(Key::Up),
+                Input::Key(Key::A),
+                Input::Key(Key::Left),
+                Input::Key(Key::B),
+                Input::Quit,
+                Input::Key(Key::Start),
+                Input::Key(Key::A),
+            ]
+        );
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_parse_split_escape() {
+        let mut bytes = b"x\x1b".to_vec();
+        assert_eq!(parse_input(&mut bytes), vec![Input::Key(Key::A)]);
+        assert_eq!(bytes, b"\x1b");
+        bytes.push(b'[');
+        assert_eq!(parse_input(&mut bytes), vec![]);
+        assert_eq!(bytes, b"\x1b[");
+        bytes.push(b'A');
+        assert_eq!(parse_input(&mut bytes), vec![Input::Key(Key::Up)]);
+        assert!(bytes.is_empty());
+    }
+}
@@ -1,84 +1,271 @@
+/// Value of the internal counter when the boot ROM hands over to the cartridge (DIV = 0xAB)
+const BOOT_COUNTER: u16 = 0xABCC;
+
 pub struct Timer {
-    div: u8,               // Divider register
+    counter: u16,           // Internal 16-bit system counter, DIV is its upper byte
     tima: u8,               // Counter
     tma: u8,                // Modulo
-    // tac: u8,                // Control
-    active: bool,           // TAC (2)
-    timer_clock: u8,
+    tac: u8,                // Control
+    overflow: bool,         // TIMA overflowed during the last M-cycle, reload is pending
+    reloading: bool,        // TIMA is being reloaded from TMA during this M-cycle
     pub interrupt: u8,
-    internal_clock: u8,
 }
 
 impl Timer {
     pub fn new() -> Timer {
         Timer {
-            div: 0,
+            counter: BOOT_COUNTER,
             tima: 0,
             tma: 0,
-            
-            active: false,
-            timer_clock: 64,
-            interrupt: 0,
+            tac: 0,
 
-            internal_clock: 0,
+            overflow: false,
+            reloading: false,
+            interrupt: 0,
         }
     }
 
     pub fn read(&self, address: u16) -> u8 {
         match address {
-            0xff04 => self.div,
+            0xff04 => (self.counter >> 8) as u8,
             0xff05 => self.tima,
             0xff06 => self.tma,
-            0xff07 => {
-                0xF8 |
-                (self.active as u8) << 2 |
-                match self.timer_clock {
-                    64 => 0b00,
-                    1 => 0b01,
-                    4 => 0b10,
-                    16 => 0b11,
-                    _ => panic!("Invalid timer clock: {}", self.timer_clock),
-                }
-            }
+            0xff07 => 0xF8 | self.tac,
             _ => panic!("Invalid timer read address: {:04x}", address),
         }
     }
 
-    pub fn write(&mut self, address: u16, value: u8) {
+    /// Set a register without the side effects of a write: DIV isn't reset, TIMA isn't incremented
+    pub fn poke(&mut self, address: u16, value: u8) {
         match address {
-            0xff04 => self.div = 0,
+            0xff04 => self.counter = (value as u16) << 8 | (self.counter & 0xFF),
             0xff05 => self.tima = value,
             0xff06 => self.tma = value,
+            0xff07 => self.tac = value & 0x07,
+            _ => panic!("Invalid timer poke address: {:04x}", address),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0xff04 => {
+                // Resetting the counter can produce a falling edge on the selected bit
+                let old = self.signal();
+                self.counter = 0;
+                self.detect_falling_edge(old);
+            }
+            0xff05 => {
+                // A write during the reload cycle is ignored, a write during the delay cancels the reload
+                if !self.reloading {
+                    self.tima = value;
+                    self.overflow = false;
+                }
+            }
+            0xff06 => {
+                self.tma = value;
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
             0xff07 => {
-                self.active = value & 0b100 != 0;
-                self.timer_clock = match value & 0b11 {
-                    0b00 => 64,
-                    0b01 => 1,
-                    0b10 => 4,
-                    0b11 => 16,
-                    _ => panic!("Invalid timer clock: {:02x}", value),
-                };
+                // On DMG, changing TAC can also produce a falling edge and increment TIMA
+                let old = self.signal();
+                self.tac = value & 0x07;
+                self.detect_falling_edge(old);
             }
             _ => panic!("Invalid timer write address: {:04x}", address),
         }
     }
 
-    pub fn step(&mut self, cycles: u8) {
-        
-        self.internal_clock += cycles;
+    /// Bit of the internal counter watched by TIMA, selected by TAC
+    fn selected_bit(&self) -> u16 {
+        match self.tac & 0b11 {
+            0b00 => 1 << 9, // 4096 Hz
+            0b01 => 1 << 3, // 262144 Hz
+            0b10 => 1 << 5, // 65536 Hz
+            _ => 1 << 7,    // 16384 Hz
+        }
+    }
+
+    /// Output of the AND gate between the timer enable bit and the selected counter bit
+    fn signal(&self) -> bool {
+        self.tac & 0b100 != 0 && self.counter & self.selected_bit() != 0
+    }
 
-        while self.internal_clock >= self.timer_clock {
-            self.div = self.div.wrapping_add(1);
-            self.internal_clock -= self.timer_clock;
+    fn detect_falling_edge(&mut self, old: bool) {
+        if old && !self.signal() {
+            self.increment_tima();
         }
+    }
 
-        if !self.active { return; }
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            // TIMA stays at 0x00 for one M-cycle before being reloaded
+            self.overflow = true;
+        }
+    }
 
-        let old_tima = self.tima;
-        let new_tima = self.tima.wrapping_add(cycles);
-        if new_tima < old_tima {
+    /// Advance the timer by one M-cycle (4 T-cycles)
+    pub fn tick(&mut self) {
+        self.reloading = false;
+        if self.overflow {
+            self.overflow = false;
+            self.reloading = true;
             self.tima = self.tma;
-            self.interrupt = 0x04;
+            self.interrupt |= 0x04;
         }
+
+        let old = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        self.detect_falling_edge(old);
     }
-}
\ No newline at end of file
+
+    pub fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+
+        // The overflow delay and the reload cycle are emulated M-cycle by M-cycle
+        while cycles >= 4 && (self.overflow || self.reloading) {
+            self.tick();
+            cycles -= 4;
+        }
+
+        // Fast path: count the falling edges of the selected bit when TIMA can't overflow
+        let edges = if self.tac & 0b100 == 0 {
+            0
+        } else {
+            let period = self.selected_bit() as u32 * 2;
+            let counter = self.counter as u32;
+            (counter + cycles) / period - counter / period
+        };
+        if self.tima as u32 + edges < 0x100 {
+            self.tima += edges as u8;
+            self.counter = self.counter.wrapping_add(cycles as u16);
+            return;
+        }
+
+        for _ in 0..cycles / 4 {
+            self.tick();
+        }
+    }
+
+    /// Cycles left before TIMA is reloaded and the interrupt requested, `None` if the timer is stopped
+    pub fn cycles_until_interrupt(&self) -> Option<u32> {
+        if self.overflow {
+            return Some(4);
+        }
+        if self.tac & 0b100 == 0 {
+            return None;
+        }
+
+        let period = self.selected_bit() as u32 * 2;
+        let next_edge = period - (self.counter as u32 % period);
+        let edges = 0x100 - self.tima as u32;
+        Some(next_edge + (edges - 1) * period + 4)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.write(0xff04, 0);
+        timer
+    }
+
+    #[test]
+    fn test_div_frequency() {
+        let mut timer = timer();
+        for _ in 0..63 { timer.tick(); }
+        assert_eq!(timer.read(0xff04), 0x00);
+        timer.tick();
+        assert_eq!(timer.read(0xff04), 0x01);
+    }
+
+    #[test]
+    fn test_tima_frequency() {
+        let mut timer = timer();
+        timer.write(0xff07, 0b101); // 16 T-cycles
+        timer.step(64);
+        assert_eq!(timer.read(0xff05), 4);
+    }
+
+    #[test]
+    fn test_tima_overflow_delay() {
+        let mut timer = timer();
+        timer.write(0xff06, 0x42);
+        timer.write(0xff05, 0xFF);
+        timer.write(0xff07, 0b101);
+        timer.step(16);
+        assert_eq!(timer.read(0xff05), 0x00);
+        assert_eq!(timer.interrupt, 0);
+        timer.tick();
+        assert_eq!(timer.read(0xff05), 0x42);
+        assert_eq!(timer.interrupt, 0x04);
+    }
+
+    #[test]
+    fn test_tima_write_cancels_reload() {
+        let mut timer = timer();
+        timer.write(0xff06, 0x42);
+        timer.write(0xff05, 0xFF);
+        timer.write(0xff07, 0b101);
+        timer.step(16);
+        timer.write(0xff05, 0x10);
+        timer.tick();
+        assert_eq!(timer.read(0xff05), 0x10);
+        assert_eq!(timer.interrupt, 0);
+    }
+
+    #[test]
+    fn test_fast_path_matches_ticks() {
+        let mut fast = timer();
+        let mut slow = timer();
+        for timer in [&mut fast, &mut slow] {
+            timer.write(0xff06, 0xF0);
+            timer.write(0xff05, 0x80);
+            timer.write(0xff07, 0b110);
+        }
+        fast.step(10_000);
+        for _ in 0..2_500 { slow.tick(); }
+        assert_eq!(fast.read(0xff04), slow.read(0xff04));
+        assert_eq!(fast.read(0xff05), slow.read(0xff05));
+        assert_eq!(fast.interrupt, slow.interrupt);
+    }
+
+    #[test]
+    fn test_cycles_until_interrupt() {
+        let mut timer = timer();
+        assert_eq!(timer.cycles_until_interrupt(), None);
+        timer.write(0xff05, 0xFE);
+        timer.write(0xff07, 0b101);
+        let cycles = timer.cycles_until_interrupt().unwrap();
+        assert_eq!(cycles, 36);
+        timer.step(cycles - 4);
+        assert_eq!(timer.interrupt, 0);
+        timer.step(4);
+        assert_eq!(timer.interrupt, 0x04);
+    }
+
+    #[test]
+    fn test_div_write_glitch() {
+        let mut timer = timer();
+        timer.write(0xff07, 0b101);
+        timer.step(8); // Bit 3 is set
+        timer.write(0xff04, 0);
+        assert_eq!(timer.read(0xff05), 1);
+    }
+
+    #[test]
+    fn test_tac_disable_glitch() {
+        let mut timer = timer();
+        timer.write(0xff07, 0b101);
+        timer.step(8);
+        timer.write(0xff07, 0b001);
+        assert_eq!(timer.read(0xff05), 1);
+    }
+}
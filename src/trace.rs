@@ -0,0 +1,125 @@
+//! Execution traces in the format of gameboy-doctor (https://github.com/robert/gameboy-doctor),
+//! one line per instruction with the CPU state before it is executed:
+//!
+//! `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+//!
+//! The reference logs were made with LY always reading 0x90, see `Gameboy::set_ly_stub`.
+//!
+//! `Trace::disassembly` logs the bank, the address and the instruction instead:
+//!
+//! `00:0100  NOP`
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use crate::disasm;
+use crate::registers::Registers;
+
+enum Output {
+    Writer(Box<dyn Write>),
+    Callback(Box<dyn FnMut(&str)>),
+}
+
+pub struct Trace {
+    output: Output,
+    range: Option<RangeInclusive<u16>>,
+    disassembly: bool,
+    error: Option<io::Error>,
+}
+
+impl Trace {
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Trace> {
+        Ok(Trace::to_writer(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn to_writer<W: Write + 'static>(writer: W) -> Trace {
+        Trace { output: Output::Writer(Box::new(writer)), range: None, disassembly: false, error: None }
+    }
+
+    /// The callback gets each line without the line break
+    pub fn to_callback<F: FnMut(&str) + 'static>(callback: F) -> Trace {
+        Trace { output: Output::Callback(Box::new(callback)), range: None, disassembly: false, error: None }
+    }
+
+    /// Only log the instructions with a PC in `range`
+    pub fn pc_range(mut self, range: RangeInclusive<u16>) -> Trace {
+        self.range = Some(range);
+        self
+    }
+
+    /// Log the disassembly of the instructions instead of the CPU state
+    pub fn disassembly(mut self) -> Trace {
+        self.disassembly = true;
+        self
+    }
+
+    pub(crate) fn wants(&self, pc: u16) -> bool {
+        self.range.as_ref().is_none_or(|range| range.contains(&pc))
+    }
+
+    /// A write error stops the logging, it is returned by `finish`.
+    /// `bank` is the ROM bank mapped at PC.
+    pub(crate) fn log(&mut self, registers: &Registers, pcmem: [u8; 4], bank: usize) {
+        let line = if self.disassembly {
+            let instruction = disasm::decode(&pcmem, registers.pc, bank);
+            format!("{:02x}:{:04x}  {}", instruction.bank, instruction.address, instruction)
+        } else {
+            format_line(registers, pcmem)
+        };
+        match &mut self.output {
+            Output::Callback(callback) => callback(&line),
+            Output::Writer(_) if self.error.is_some() => {}
+            Output::Writer(writer) => {
+                if let Err(e) = writeln!(writer, "{}", line) {
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+
+    /// Flush the output, returns the first write error
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        match &mut self.output {
+            Output::Writer(writer) => writer.flush(),
+            Output::Callback(_) => Ok(()),
+        }
+    }
+}
+
+fn format_line(r: &Registers, pcmem: [u8; 4]) -> String {
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+        r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l, r.sp, r.pc, pcmem[0], pcmem[1], pcmem[2], pcmem[3]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let registers = Registers::new();
+        assert_eq!(
+            format_line(&registers, [0x00, 0xC3, 0x13, 0x02]),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02"
+        );
+
+        let trace = Trace::to_callback(|_| {}).pc_range(0x0100..=0x3FFF);
+        assert!(trace.wants(0x0100));
+        assert!(!trace.wants(0xC000));
+
+        let lines = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let log = lines.clone();
+        let mut trace = Trace::to_callback(move |line| log.borrow_mut().push(line.to_string())).disassembly();
+        let mut registers = Registers::new();
+        registers.pc = 0x4000;
+        trace.log(&registers, [0xC3, 0x50, 0x01, 0x00], 2);
+        assert_eq!(*lines.borrow(), ["02:4000  JP $0150"]);
+    }
+}